@@ -0,0 +1,48 @@
+//! Compares the fixed-point Stucki ditherer with the generic one from the `dither` crate it replaced.
+//! Run with `cargo bench`.
+use dither::prelude::*;
+use sparkboard::halftone;
+use std::time::{Duration, Instant};
+
+const RUNS: u32 = 5;
+
+fn gradient(width: usize, height: usize) -> Vec<u8> {
+	(0..height)
+		.flat_map(|y| (0..width).map(move |x| ((x * 255 / width + y * 255 / height) / 2) as u8))
+		.collect()
+}
+
+fn time<F: FnMut()>(mut f: F) -> Duration {
+	let start = Instant::now();
+	for _ in 0..RUNS {
+		f();
+	}
+	start.elapsed() / RUNS
+}
+
+fn main() {
+	for (width, height) in [(800, 480), (640, 384)].iter() {
+		let gray = gradient(*width, *height);
+
+		let fixed = time(|| {
+			halftone::dither_stucki(&gray, *width, *height);
+		});
+
+		let generic = time(|| {
+			let source: Vec<RGB<f64>> = gray.iter().map(|g| RGB(*g, *g, *g).convert_with(f64::from)).collect();
+			let quantize = dither::create_quantize_n_bits_func(1).unwrap();
+			dither::ditherer::STUCKI
+				.dither(Img::new(source, *width as u32).unwrap(), RGB::map_across(quantize))
+				.convert_with(|rgb| rgb.convert_with(clamp_f64_to_u8));
+		});
+
+		println!(
+			"{}x{}: fixed-point {:?}, dither crate {:?} (x{:.1})",
+			width,
+			height,
+			fixed,
+			generic,
+			generic.as_secs_f64() / fixed.as_secs_f64()
+		);
+	}
+}
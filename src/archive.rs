@@ -0,0 +1,287 @@
+/*! Archive of the frames sent to the panels, to be able to show what was displayed when.
+
+Every frame is saved in the archive directory as a PNG (as `Bitmap::to_png` renders it), next to a JSON file with
+the time it was drawn, the display and panel, and where the image came from (see `Entry`). The oldest entries are
+removed when there are more, older or larger ones than the configuration allows. */
+use crate::graphics::Bitmap;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/** The archive as written in the configuration */
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArchiveConfig {
+	/** Directory to keep the frames in */
+	pub path: PathBuf,
+	/** Number of frames to keep at most */
+	pub max_count: Option<usize>,
+	/** Remove frames older than this (seconds) */
+	pub max_age: Option<u64>,
+	/** Total size of the archive in bytes at most */
+	pub max_size: Option<u64>,
+}
+
+/** What a panel was refreshed with */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Content<'a> {
+	/** An image fetched from a source (URL) */
+	Image(&'a str),
+	/** A message, such as an error or the closed screen */
+	Text(&'a str),
+	Blank,
+}
+
+/** A frame in the archive */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+	/** Name of the files of the entry, without extension */
+	#[serde(skip)]
+	pub id: String,
+	pub time: DateTime<Local>,
+	pub display: String,
+	pub panel: String,
+	/** 'image', 'text' or 'blank' */
+	pub content: String,
+	/** URL of the image */
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub source: Option<String>,
+	/** The message shown */
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub text: Option<String>,
+	/** Whether the frame has a color plane */
+	pub color: bool,
+}
+
+impl Entry {
+	/** The frame, as it was sent to the panel */
+	pub fn load(&self, dir: &Path) -> Result<Bitmap, Box<dyn Error>> {
+		let png = fs::read(dir.join(format!("{}.png", self.id)))?;
+		Bitmap::from_png(&png, self.color)
+	}
+}
+
+/** An archive being written to, with what is in it so that pruning does not need to read the directory again */
+struct Archive {
+	config: ArchiveConfig,
+	/** The entries, oldest first, with the size of their files */
+	stored: VecDeque<(Entry, u64)>,
+	/** Size of all entries */
+	size: u64,
+}
+
+static ARCHIVE: Mutex<Option<Archive>> = Mutex::new(None);
+
+/** Archive frames as configured from now on (or stop archiving, with None) */
+pub fn open(config: Option<&ArchiveConfig>) -> Result<(), Box<dyn Error>> {
+	let archive = match config {
+		Some(config) => {
+			fs::create_dir_all(&config.path)
+				.map_err(|e| format!("could not create archive directory {:?}: {}", config.path, e))?;
+			Some(Archive::load(config.clone())?)
+		}
+		None => None,
+	};
+	*ARCHIVE.lock().unwrap() = archive;
+	Ok(())
+}
+
+/** Archive a frame that was sent to a panel of a display, if archiving */
+pub fn save(display: &str, panel: &str, bitmap: &Bitmap, content: Content) {
+	let mut archive = ARCHIVE.lock().unwrap();
+	if let Some(archive) = archive.as_mut() {
+		if let Err(e) = archive.save(display, panel, bitmap, content, Local::now()) {
+			log::error!("[{}] could not archive frame of {}: {}", display, panel, e);
+		}
+	}
+}
+
+impl Archive {
+	/** Open an archive, reading what is in it already (and removing what is beyond the limits) */
+	fn load(config: ArchiveConfig) -> Result<Archive, Box<dyn Error>> {
+		let stored: VecDeque<(Entry, u64)> = entries(&config.path)?
+			.into_iter()
+			.map(|entry| {
+				let size = Archive::files(&config.path, &entry)
+					.iter()
+					.filter_map(|f| fs::metadata(f).ok())
+					.map(|m| m.len())
+					.sum();
+				(entry, size)
+			})
+			.collect();
+		let size = stored.iter().map(|(_, size)| size).sum();
+		let mut archive = Archive { config, stored, size };
+		archive.prune(Local::now())?;
+		Ok(archive)
+	}
+
+	fn files(dir: &Path, entry: &Entry) -> [PathBuf; 2] {
+		[
+			dir.join(format!("{}.png", entry.id)),
+			dir.join(format!("{}.json", entry.id)),
+		]
+	}
+
+	fn save(
+		&mut self,
+		display: &str,
+		panel: &str,
+		bitmap: &Bitmap,
+		content: Content,
+		time: DateTime<Local>,
+	) -> Result<(), Box<dyn Error>> {
+		let (kind, source, text) = match content {
+			Content::Image(url) => ("image", Some(url.to_string()), None),
+			Content::Text(text) => ("text", None, Some(text.to_string())),
+			Content::Blank => ("blank", None, None),
+		};
+		let safe_panel: String = panel
+			.chars()
+			.map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+			.collect();
+		// In UTC, so that names sort in the order the frames were drawn
+		let id = format!(
+			"{}-{}",
+			time.with_timezone(&Utc).format("%Y%m%dT%H%M%S%.3fZ"),
+			safe_panel
+		);
+		let entry = Entry {
+			id,
+			time,
+			display: display.to_string(),
+			panel: panel.to_string(),
+			content: kind.to_string(),
+			source,
+			text,
+			color: bitmap.color.is_some(),
+		};
+		// The PNG goes first, so that every entry that is listed can be loaded
+		let [png_path, json_path] = Archive::files(&self.config.path, &entry);
+		let png = bitmap.to_png()?;
+		fs::write(png_path, &png)?;
+		let json = serde_json::to_string_pretty(&entry)?;
+		fs::write(json_path, &json)?;
+
+		let size = (png.len() + json.len()) as u64;
+		self.stored.push_back((entry, size));
+		self.size += size;
+		self.prune(time)
+	}
+
+	/** Remove the oldest entries until the archive is within its limits */
+	fn prune(&mut self, now: DateTime<Local>) -> Result<(), Box<dyn Error>> {
+		let max_age = self.config.max_age.map(|age| {
+			chrono::Duration::from_std(Duration::from_secs(age)).unwrap_or_else(|_| chrono::Duration::max_value())
+		});
+		while let Some((entry, entry_size)) = self.stored.front() {
+			let too_many = self
+				.config
+				.max_count
+				.map(|max| self.stored.len() > max)
+				.unwrap_or(false);
+			let too_old = max_age
+				.map(|max| now.signed_duration_since(entry.time) > max)
+				.unwrap_or(false);
+			let too_large = self.config.max_size.map(|max| self.size > max).unwrap_or(false);
+			if !too_many && !too_old && !too_large {
+				break;
+			}
+			for file in &Archive::files(&self.config.path, entry) {
+				match fs::remove_file(file) {
+					Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+					_ => {}
+				}
+			}
+			self.size -= entry_size;
+			self.stored.pop_front();
+		}
+		Ok(())
+	}
+}
+
+/** The entries in an archive directory, oldest first */
+pub fn entries(dir: &Path) -> Result<Vec<Entry>, Box<dyn Error>> {
+	let mut entries = vec![];
+	for file in fs::read_dir(dir).map_err(|e| format!("could not read archive {:?}: {}", dir, e))? {
+		let path = file?.path();
+		if path.extension().and_then(|e| e.to_str()) != Some("json") {
+			continue;
+		}
+		let id = match path.file_stem().and_then(|s| s.to_str()) {
+			Some(id) => id.to_string(),
+			None => continue,
+		};
+		match fs::read_to_string(&path)
+			.map_err(|e| e.to_string())
+			.and_then(|json| serde_json::from_str::<Entry>(&json).map_err(|e| e.to_string()))
+		{
+			Ok(entry) => entries.push(Entry { id, ..entry }),
+			Err(e) => log::warn!("skipping archive entry {:?}: {}", path, e),
+		}
+	}
+	entries.sort_by(|a, b| a.id.cmp(&b.id));
+	Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn keeps_frames_within_limits() {
+		let dir = std::env::temp_dir().join(format!("sparkboard-archive-{}", std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		let config = ArchiveConfig {
+			path: dir.clone(),
+			max_count: Some(4),
+			max_age: Some(3600),
+			max_size: None,
+		};
+		let bitmap = Bitmap {
+			width: 8,
+			height: 1,
+			black: vec![0x0F],
+			color: None,
+		};
+		let start = Local.ymd(2021, 1, 4).and_hms(9, 0, 0);
+		let mut archive = Archive::load(config.clone()).unwrap();
+		for minutes in &[0, 50, 70, 80, 90, 95] {
+			let time = start + chrono::Duration::minutes(*minutes);
+			archive
+				.save("hall", "hall #1", &bitmap, Content::Image("http://a/"), time)
+				.unwrap();
+		}
+
+		// The first is too old by the fifth, and only four may be kept
+		let entries = entries(&dir).unwrap();
+		let times: Vec<_> = entries.iter().map(|e| e.time).collect();
+		assert_eq!(
+			times,
+			vec![70, 80, 90, 95]
+				.into_iter()
+				.map(|m| start + chrono::Duration::minutes(m))
+				.collect::<Vec<_>>()
+		);
+		assert_eq!(entries[0].source.as_deref(), Some("http://a/"));
+		assert_eq!(entries[0].load(&dir).unwrap(), bitmap);
+		assert_eq!(archive.stored.len(), 4);
+
+		// What was there already counts when opening the archive again
+		let archive = Archive::load(ArchiveConfig {
+			max_count: Some(2),
+			max_age: None,
+			..config
+		})
+		.unwrap();
+		assert_eq!(archive.stored.len(), 2);
+		assert_eq!(super::entries(&dir).unwrap().len(), 2);
+		fs::remove_dir_all(dir).unwrap();
+	}
+}
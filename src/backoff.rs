@@ -0,0 +1,85 @@
+use rand::Rng;
+use std::time::Duration;
+
+/** Exponential backoff for retrying failed fetches */
+pub struct Backoff {
+	initial: Duration,
+	max: Duration,
+	/** Fraction (0..1) by which each delay is randomly varied, so a fleet of devices does not retry in lockstep */
+	jitter: f64,
+	current: Duration,
+}
+
+impl Backoff {
+	pub fn new(initial: Duration, max: Duration, jitter: f64) -> Backoff {
+		Backoff {
+			initial,
+			max: max.max(initial),
+			jitter: if jitter.is_finite() {
+				jitter.clamp(0.0, 1.0)
+			} else {
+				0.0
+			},
+			current: initial,
+		}
+	}
+
+	/** Returns the delay to wait before the next attempt, and doubles the delay for the attempt after that */
+	pub fn next_delay(&mut self) -> Duration {
+		let delay = self.current;
+		self.current = self.current.checked_mul(2).unwrap_or(self.max).min(self.max);
+
+		if self.jitter > 0.0 {
+			let factor = rand::thread_rng().gen_range((1.0 - self.jitter)..=(1.0 + self.jitter));
+			Duration::try_from_secs_f64(delay.as_secs_f64() * factor).unwrap_or(self.max)
+		} else {
+			delay
+		}
+	}
+
+	/** Start over at the initial delay (call after a successful attempt) */
+	pub fn reset(&mut self) {
+		self.current = self.initial;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn doubles_up_to_the_maximum() {
+		let mut backoff = Backoff::new(Duration::from_secs(5), Duration::from_secs(30), 0.0);
+		let delays: Vec<u64> = (0..5).map(|_| backoff.next_delay().as_secs()).collect();
+		assert_eq!(delays, vec![5, 10, 20, 30, 30]);
+		backoff.reset();
+		assert_eq!(backoff.next_delay(), Duration::from_secs(5));
+
+		// Doubling the largest delay there is would overflow
+		let mut backoff = Backoff::new(Duration::MAX / 3, Duration::MAX, 0.0);
+		backoff.next_delay();
+		backoff.next_delay();
+		assert_eq!(backoff.next_delay(), Duration::MAX);
+	}
+
+	#[test]
+	fn varies_delays_within_the_jitter() {
+		let mut backoff = Backoff::new(Duration::from_secs(10), Duration::from_secs(10), 0.5);
+		for _ in 0..100 {
+			let delay = backoff.next_delay();
+			assert!(
+				delay >= Duration::from_secs(5) && delay <= Duration::from_secs(15),
+				"{:?}",
+				delay
+			);
+		}
+
+		// Jitter beyond 1 would make delays negative
+		for jitter in &[3.0, f64::NAN] {
+			let mut backoff = Backoff::new(Duration::from_secs(10), Duration::from_secs(10), *jitter);
+			for _ in 0..100 {
+				assert!(backoff.next_delay() <= Duration::from_secs(20));
+			}
+		}
+	}
+}
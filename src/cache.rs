@@ -0,0 +1,219 @@
+/*! The images the panels showed last, kept on disk so they can be shown again right after a restart, before the network
+is up.
+
+For every panel, the cache directory has the last image fetched for it (as a PNG) and a JSON file with where it came
+from and whether the panel still shows it (see `Entry`). Every time a panel is woken up to be drawn on, it is taken
+to no longer show the image until the draw is known to have sent the same image, so that a panel that lost power
+halfway through a refresh is drawn again. Files are replaced atomically, so a power cut cannot leave half of one. */
+use crate::graphics::Bitmap;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/** What the cache knows about the image of a panel */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+	/** When the image was drawn */
+	pub time: DateTime<Local>,
+	pub display: String,
+	pub panel: String,
+	/** URL the image was fetched from */
+	pub source: String,
+	/** Whether the image has a color plane */
+	pub color: bool,
+	/** Whether the panel still shows the image: nothing else was drawn on it since */
+	pub shows: bool,
+}
+
+struct Cache {
+	dir: PathBuf,
+	/** By panel */
+	panels: BTreeMap<String, (Entry, Bitmap)>,
+}
+
+impl Cache {
+	fn path(&self, panel: &str, extension: &str) -> PathBuf {
+		let name: String = panel
+			.chars()
+			.map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+			.collect();
+		self.dir.join(format!("{}.{}", name, extension))
+	}
+
+	fn write_entry(&self, entry: &Entry) -> Result<(), Box<dyn Error>> {
+		let json = serde_json::to_string_pretty(entry)?;
+		write_atomically(&self.path(&entry.panel, "json"), json.as_bytes())
+	}
+
+	/** Write a new image with its entry. The old entry goes first, so that it is never read with the new image. */
+	fn write(&self, entry: &Entry, bitmap: &Bitmap) -> Result<(), Box<dyn Error>> {
+		match fs::remove_file(self.path(&entry.panel, "json")) {
+			Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+			_ => {}
+		}
+		write_atomically(&self.path(&entry.panel, "png"), &bitmap.to_png()?)?;
+		self.write_entry(entry)
+	}
+}
+
+static CACHE: Mutex<Option<Cache>> = Mutex::new(None);
+
+/** Keep the images in `dir` from now on, reading what is there already (or stop caching, with None) */
+pub fn open(dir: Option<&Path>) -> Result<(), Box<dyn Error>> {
+	let cache = match dir {
+		Some(dir) => {
+			fs::create_dir_all(dir).map_err(|e| format!("could not create cache directory {:?}: {}", dir, e))?;
+			let mut panels = BTreeMap::new();
+			for file in fs::read_dir(dir)? {
+				let path = file?.path();
+				if path.extension().and_then(|e| e.to_str()) != Some("json") {
+					continue;
+				}
+				match load(&path) {
+					Ok((entry, bitmap)) => {
+						panels.insert(entry.panel.clone(), (entry, bitmap));
+					}
+					Err(e) => log::warn!("ignoring cached image {:?}: {}", path, e),
+				}
+			}
+			Some(Cache {
+				dir: dir.to_path_buf(),
+				panels,
+			})
+		}
+		None => None,
+	};
+	*CACHE.lock().unwrap() = cache;
+	Ok(())
+}
+
+fn load(json: &Path) -> Result<(Entry, Bitmap), Box<dyn Error>> {
+	let entry: Entry = serde_json::from_str(&fs::read_to_string(json)?)?;
+	let bitmap = Bitmap::from_png(&fs::read(json.with_extension("png"))?, entry.color)?;
+	Ok((entry, bitmap))
+}
+
+/** The image a panel showed last, if it is in the cache */
+pub fn cached(panel: &str) -> Option<(Entry, Bitmap)> {
+	let cache = CACHE.lock().unwrap();
+	cache.as_ref()?.panels.get(panel).cloned()
+}
+
+/** Whether a panel still shows the image in the cache */
+pub fn shows_image(panel: &str) -> bool {
+	let cache = CACHE.lock().unwrap();
+	let entry = cache.as_ref().and_then(|cache| cache.panels.get(panel));
+	entry.map(|(entry, _)| entry.shows).unwrap_or(false)
+}
+
+/** A panel was woken up to be drawn on, so it may no longer show its image */
+pub fn drawing(panel: &str) {
+	update(panel, |entry, _| entry.shows = false);
+}
+
+/** A panel was sent `bitmap`, which may be its image */
+pub fn drawn(panel: &str, bitmap: &Bitmap) {
+	update(panel, |entry, cached| entry.shows = cached == bitmap);
+}
+
+/** Change the entry of a panel (which is given its image), writing it when it changed */
+fn update(panel: &str, f: impl FnOnce(&mut Entry, &Bitmap)) {
+	let mut cache = CACHE.lock().unwrap();
+	let cache = match cache.as_mut() {
+		Some(cache) => cache,
+		None => return,
+	};
+	let (entry, bitmap) = match cache.panels.get(panel) {
+		Some((entry, bitmap)) => (entry.clone(), bitmap),
+		None => return,
+	};
+	let mut updated = entry.clone();
+	f(&mut updated, bitmap);
+	if updated != entry {
+		if let Err(e) = cache.write_entry(&updated) {
+			log::warn!("could not update cached image of {}: {}", panel, e);
+		}
+		cache.panels.get_mut(panel).expect("found above").0 = updated;
+	}
+}
+
+/** A panel of a display now shows an image fetched from `source` */
+pub fn save(display: &str, panel: &str, bitmap: &Bitmap, source: &str) {
+	let mut cache = CACHE.lock().unwrap();
+	let cache = match cache.as_mut() {
+		Some(cache) => cache,
+		None => return,
+	};
+	let entry = Entry {
+		time: Local::now(),
+		display: display.to_string(),
+		panel: panel.to_string(),
+		source: source.to_string(),
+		color: bitmap.color.is_some(),
+		shows: true,
+	};
+	match cache.write(&entry, bitmap) {
+		Ok(()) => {
+			cache.panels.insert(panel.to_string(), (entry, bitmap.clone()));
+		}
+		Err(e) => {
+			log::warn!("[{}] could not cache image of {}: {}", display, panel, e);
+			cache.panels.remove(panel);
+		}
+	}
+}
+
+/** Replace a file with new contents, so that it has either the old or the new contents should the power be cut */
+fn write_atomically(path: &Path, data: &[u8]) -> Result<(), Box<dyn Error>> {
+	let mut temporary = path.as_os_str().to_owned();
+	temporary.push(".new");
+	let mut file = File::create(&temporary)?;
+	file.write_all(data)?;
+	file.sync_all()?;
+	fs::rename(&temporary, path)?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn knows_whether_panels_still_show_their_image() {
+		let dir = std::env::temp_dir().join(format!("sparkboard-cache-{}", std::process::id()));
+		let image = Bitmap {
+			width: 8,
+			height: 1,
+			black: vec![0xF0],
+			color: None,
+		};
+		let text = Bitmap {
+			black: vec![0x01],
+			..image.clone()
+		};
+
+		open(Some(&dir)).unwrap();
+		save("hall", "hall #1", &image, "http://a/");
+		drawing("hall #1");
+		drawn("hall #1", &text);
+		open(Some(&dir)).unwrap();
+		let (entry, bitmap) = cached("hall #1").unwrap();
+		assert_eq!((entry.source.as_str(), entry.shows), ("http://a/", false));
+		assert_eq!(bitmap, image);
+
+		// Drawing the image again (as at startup) means the panel shows it again
+		drawing("hall #1");
+		drawn("hall #1", &image);
+		open(Some(&dir)).unwrap();
+		assert!(cached("hall #1").unwrap().0.shows);
+		assert_eq!(cached("lobby"), None);
+
+		open(None).unwrap();
+		fs::remove_dir_all(dir).unwrap();
+	}
+}
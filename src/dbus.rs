@@ -0,0 +1,587 @@
+/*! Just enough of a D-Bus client to ask system services (such as NetworkManager and iwd) how they are doing, without
+linking libdbus.
+
+A `Connection` talks to the system bus over its Unix socket, authenticating as the user running the process. Method
+calls only take string arguments; replies are read into `Value`s, whatever their signature. */
+use std::error::Error;
+use std::io::{Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+/** Socket of the system bus, unless DBUS_SYSTEM_BUS_ADDRESS says otherwise */
+const SYSTEM_BUS: &str = "/run/dbus/system_bus_socket";
+
+/** How long to wait for the bus to answer */
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/** Messages larger than this are refused (the protocol allows 128 MiB) */
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+const METHOD_CALL: u8 = 1;
+const METHOD_RETURN: u8 = 2;
+const ERROR: u8 = 3;
+
+/** A value read from a message */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+	Bool(bool),
+	/** Any signed integer */
+	Int(i64),
+	/** Any unsigned integer, including bytes and file descriptor indices */
+	Uint(u64),
+	Double(f64),
+	/** A string, object path or signature */
+	Str(String),
+	Array(Vec<Value>),
+	/** A struct, or an entry of a dictionary (key and value) */
+	Struct(Vec<Value>),
+	Variant(Box<Value>),
+}
+
+impl Value {
+	/** The value inside a variant (or the value itself, when it is not one) */
+	pub fn inner(&self) -> &Value {
+		match self {
+			Value::Variant(value) => value.inner(),
+			value => value,
+		}
+	}
+
+	pub fn as_str(&self) -> Option<&str> {
+		match self.inner() {
+			Value::Str(s) => Some(s),
+			_ => None,
+		}
+	}
+
+	pub fn as_u64(&self) -> Option<u64> {
+		match self.inner() {
+			Value::Uint(n) => Some(*n),
+			_ => None,
+		}
+	}
+
+	pub fn as_array(&self) -> Option<&[Value]> {
+		match self.inner() {
+			Value::Array(items) => Some(items),
+			_ => None,
+		}
+	}
+
+	/** The value under a (string or object path) key of a dictionary */
+	pub fn get(&self, key: &str) -> Option<&Value> {
+		self.as_array()?.iter().find_map(|entry| match entry {
+			Value::Struct(fields) if fields.len() == 2 && fields[0].as_str() == Some(key) => Some(&fields[1]),
+			_ => None,
+		})
+	}
+}
+
+/** A connection to the system bus */
+pub struct Connection {
+	stream: UnixStream,
+	serial: u32,
+}
+
+impl Connection {
+	/** Connect to the system bus, and register with it */
+	pub fn system() -> Result<Connection, Box<dyn Error>> {
+		let path = match std::env::var("DBUS_SYSTEM_BUS_ADDRESS") {
+			Ok(address) => address
+				.split(';')
+				.find_map(|a| a.strip_prefix("unix:path="))
+				.and_then(|a| a.split(',').next())
+				.map(|path| path.to_string())
+				.ok_or_else(|| format!("unsupported system bus address {:?}", address))?,
+			Err(_) => SYSTEM_BUS.to_string(),
+		};
+		let stream = UnixStream::connect(&path)
+			.map_err(|e| format!("could not connect to the system bus at {}: {}", path, e))?;
+		stream.set_read_timeout(Some(TIMEOUT))?;
+		stream.set_write_timeout(Some(TIMEOUT))?;
+		let mut connection = Connection { stream, serial: 0 };
+		connection.authenticate()?;
+		connection.call(
+			"org.freedesktop.DBus",
+			"/org/freedesktop/DBus",
+			"org.freedesktop.DBus",
+			"Hello",
+			&[],
+		)?;
+		Ok(connection)
+	}
+
+	/** Authenticate as the user running the process, which the bus checks against the credentials of the socket */
+	fn authenticate(&mut self) -> Result<(), Box<dyn Error>> {
+		let uid = std::fs::metadata("/proc/self")?.uid();
+		let hex: String = uid.to_string().bytes().map(|b| format!("{:02x}", b)).collect();
+		self.stream
+			.write_all(format!("\0AUTH EXTERNAL {}\r\n", hex).as_bytes())?;
+		let reply = self.read_line()?;
+		if !reply.starts_with("OK ") {
+			return Err(format!("system bus refused authentication: {}", reply.trim_end()).into());
+		}
+		self.stream.write_all(b"BEGIN\r\n")?;
+		Ok(())
+	}
+
+	fn read_line(&mut self) -> Result<String, Box<dyn Error>> {
+		let mut line = vec![];
+		let mut byte = [0];
+		while !line.ends_with(b"\r\n") {
+			if line.len() > 1024 {
+				return Err("system bus sent an overly long line".into());
+			}
+			self.stream.read_exact(&mut byte)?;
+			line.push(byte[0]);
+		}
+		Ok(String::from_utf8_lossy(&line).into_owned())
+	}
+
+	/** Call a method with string arguments, and return what it returned */
+	pub fn call(
+		&mut self,
+		destination: &str,
+		path: &str,
+		interface: &str,
+		member: &str,
+		args: &[&str],
+	) -> Result<Vec<Value>, Box<dyn Error>> {
+		self.serial += 1;
+		let serial = self.serial;
+		self.stream
+			.write_all(&method_call(serial, destination, path, interface, member, args))?;
+		loop {
+			let message = self.read_message()?;
+			// Anything else, such as the NameAcquired signal after Hello, is of no interest
+			if message.reply_serial != Some(u64::from(serial)) {
+				continue;
+			}
+			match message.kind {
+				METHOD_RETURN => return Ok(message.body),
+				ERROR => {
+					let text = message.body.first().and_then(Value::as_str).unwrap_or_default();
+					let name = message.error_name.unwrap_or_default();
+					return Err(format!("{}.{} failed: {}: {}", interface, member, name, text).into());
+				}
+				_ => {}
+			}
+		}
+	}
+
+	/** Read a property of an object */
+	pub fn property(
+		&mut self,
+		destination: &str,
+		path: &str,
+		interface: &str,
+		name: &str,
+	) -> Result<Value, Box<dyn Error>> {
+		let reply = self.call(
+			destination,
+			path,
+			"org.freedesktop.DBus.Properties",
+			"Get",
+			&[interface, name],
+		)?;
+		match reply.into_iter().next() {
+			Some(Value::Variant(value)) => Ok(*value),
+			_ => Err(format!("{} returned no value for {}.{}", destination, interface, name).into()),
+		}
+	}
+
+	fn read_message(&mut self) -> Result<Message, Box<dyn Error>> {
+		let mut fixed = [0; 16];
+		self.stream.read_exact(&mut fixed)?;
+		let big_endian = fixed[0] == b'B';
+		let number = |bytes: &[u8]| {
+			let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+			if big_endian {
+				u32::from_be_bytes(bytes)
+			} else {
+				u32::from_le_bytes(bytes)
+			}
+		};
+		let body_size = number(&fixed[4..8]) as usize;
+		let fields_size = number(&fixed[12..16]) as usize;
+		if body_size.saturating_add(fields_size) > MAX_MESSAGE_SIZE {
+			return Err("system bus sent an overly large message".into());
+		}
+		let mut data = fixed.to_vec();
+		data.resize(align(16 + fields_size, 8) + body_size, 0);
+		self.stream.read_exact(&mut data[16..])?;
+		parse_message(&data)
+	}
+}
+
+/** What matters of a message that was received */
+#[derive(Debug)]
+struct Message {
+	kind: u8,
+	reply_serial: Option<u64>,
+	error_name: Option<String>,
+	body: Vec<Value>,
+}
+
+/** A method call with string arguments, in little-endian byte order */
+fn method_call(serial: u32, destination: &str, path: &str, interface: &str, member: &str, args: &[&str]) -> Vec<u8> {
+	let mut body = vec![];
+	for arg in args {
+		write_string(&mut body, arg);
+	}
+	let signature = "s".repeat(args.len());
+
+	let mut message = vec![b'l', METHOD_CALL, 0, 1];
+	message.extend(&(body.len() as u32).to_le_bytes());
+	message.extend(&serial.to_le_bytes());
+	// Size of the header fields, filled in below
+	message.extend(&[0; 4]);
+	let mut fields = vec![
+		(1, b'o', path),
+		(2, b's', interface),
+		(3, b's', member),
+		(6, b's', destination),
+	];
+	if !args.is_empty() {
+		fields.push((8, b'g', &signature));
+	}
+	for (code, kind, value) in fields {
+		// Each field is a struct of its code and a variant: the signature of the value, then the value
+		pad(&mut message, 8);
+		message.extend(&[code, 1, kind, 0]);
+		if kind == b'g' {
+			message.push(value.len() as u8);
+			message.extend(value.as_bytes());
+			message.push(0);
+		} else {
+			write_string(&mut message, value);
+		}
+	}
+	let fields_size = message.len() - 16;
+	message[12..16].copy_from_slice(&(fields_size as u32).to_le_bytes());
+	pad(&mut message, 8);
+	message.extend(body);
+	message
+}
+
+fn write_string(out: &mut Vec<u8>, text: &str) {
+	pad(out, 4);
+	out.extend(&(text.len() as u32).to_le_bytes());
+	out.extend(text.as_bytes());
+	out.push(0);
+}
+
+fn pad(out: &mut Vec<u8>, alignment: usize) {
+	out.resize(align(out.len(), alignment), 0);
+}
+
+fn align(position: usize, alignment: usize) -> usize {
+	position.div_ceil(alignment) * alignment
+}
+
+fn parse_message(data: &[u8]) -> Result<Message, Box<dyn Error>> {
+	let mut reader = Reader {
+		data,
+		position: 12,
+		big_endian: data.first() == Some(&b'B'),
+		variants: 0,
+	};
+	let fields = reader.value(b"a(yv)")?;
+	let mut message = Message {
+		kind: data[1],
+		reply_serial: None,
+		error_name: None,
+		body: vec![],
+	};
+	let mut signature = String::new();
+	for field in fields.as_array().unwrap_or_default() {
+		if let Value::Struct(field) = field {
+			match (field[0].as_u64(), &field[1]) {
+				(Some(4), name) => message.error_name = name.as_str().map(|n| n.to_string()),
+				(Some(5), serial) => message.reply_serial = serial.as_u64(),
+				(Some(8), body) => signature = body.as_str().unwrap_or_default().to_string(),
+				_ => {}
+			}
+		}
+	}
+	reader.position = align(reader.position, 8);
+	message.body = reader.values(signature.as_bytes())?;
+	Ok(message)
+}
+
+/** Reads values from a message, which are aligned to their size from the start of it */
+struct Reader<'a> {
+	data: &'a [u8],
+	position: usize,
+	big_endian: bool,
+	/** Variants the value being read is in, which the protocol limits */
+	variants: usize,
+}
+
+impl<'a> Reader<'a> {
+	fn take(&mut self, size: usize) -> Result<&'a [u8], Box<dyn Error>> {
+		let bytes = self
+			.data
+			.get(self.position..self.position + size)
+			.ok_or("message is truncated")?;
+		self.position += size;
+		Ok(bytes)
+	}
+
+	fn number(&mut self, size: usize) -> Result<u64, Box<dyn Error>> {
+		self.position = align(self.position, size);
+		let bytes = self.take(size)?;
+		let fold = |n: u64, b: &u8| n << 8 | u64::from(*b);
+		Ok(if self.big_endian {
+			bytes.iter().fold(0, fold)
+		} else {
+			bytes.iter().rev().fold(0, fold)
+		})
+	}
+
+	/** A string, with a length of `size` bytes in front and a nul byte after it */
+	fn string(&mut self, size: usize) -> Result<String, Box<dyn Error>> {
+		let length = self.number(size)? as usize;
+		let text = std::str::from_utf8(self.take(length)?)?.to_string();
+		self.take(1)?;
+		Ok(text)
+	}
+
+	/** The values of a signature of any number of complete types */
+	fn values(&mut self, mut signature: &[u8]) -> Result<Vec<Value>, Box<dyn Error>> {
+		let mut values = vec![];
+		while !signature.is_empty() {
+			let length = complete_type(signature)?;
+			values.push(self.value(&signature[..length])?);
+			signature = &signature[length..];
+		}
+		Ok(values)
+	}
+
+	/** The value of a single complete type */
+	fn value(&mut self, signature: &[u8]) -> Result<Value, Box<dyn Error>> {
+		Ok(match signature.first().copied().unwrap_or_default() {
+			b'y' => Value::Uint(self.number(1)?),
+			b'b' => Value::Bool(self.number(4)? != 0),
+			b'n' => Value::Int(i64::from(self.number(2)? as u16 as i16)),
+			b'q' => Value::Uint(self.number(2)?),
+			b'i' => Value::Int(i64::from(self.number(4)? as u32 as i32)),
+			b'u' | b'h' => Value::Uint(self.number(4)?),
+			b'x' => Value::Int(self.number(8)? as i64),
+			b't' => Value::Uint(self.number(8)?),
+			b'd' => Value::Double(f64::from_bits(self.number(8)?)),
+			b's' | b'o' => Value::Str(self.string(4)?),
+			b'g' => Value::Str(self.string(1)?),
+			b'v' => {
+				let signature = self.string(1)?;
+				if complete_type(signature.as_bytes())? != signature.len() {
+					return Err(format!("invalid variant signature {:?}", signature).into());
+				}
+				if self.variants == 64 {
+					return Err("variants are nested too deeply".into());
+				}
+				self.variants += 1;
+				let value = self.value(signature.as_bytes());
+				self.variants -= 1;
+				Value::Variant(Box::new(value?))
+			}
+			b'a' => {
+				let size = self.number(4)? as usize;
+				let element = &signature[1..];
+				self.position = align(self.position, alignment(element[0]));
+				let end = self.position + size;
+				if end > self.data.len() {
+					return Err("message is truncated".into());
+				}
+				let mut items = vec![];
+				while self.position < end {
+					let start = self.position;
+					items.push(self.value(element)?);
+					// Only an empty struct takes no space, and the protocol does not allow one
+					if self.position == start {
+						return Err("array element takes no space".into());
+					}
+				}
+				Value::Array(items)
+			}
+			b'(' | b'{' => {
+				self.position = align(self.position, 8);
+				Value::Struct(self.values(&signature[1..signature.len() - 1])?)
+			}
+			other => return Err(format!("unsupported type {:?} in message", other as char).into()),
+		})
+	}
+}
+
+/** Length of the complete type at the start of a signature */
+fn complete_type(signature: &[u8]) -> Result<usize, Box<dyn Error>> {
+	match signature.first() {
+		Some(b'a') => Ok(1 + complete_type(&signature[1..])?),
+		Some(b'(') | Some(b'{') => {
+			let mut depth = 0;
+			for (index, c) in signature.iter().enumerate() {
+				match c {
+					b'(' | b'{' => depth += 1,
+					b')' | b'}' => depth -= 1,
+					_ => {}
+				}
+				if depth == 0 {
+					return Ok(index + 1);
+				}
+			}
+			Err(format!("unbalanced signature {:?}", String::from_utf8_lossy(signature)).into())
+		}
+		Some(_) => Ok(1),
+		None => Err("incomplete signature".into()),
+	}
+}
+
+/** Alignment of the values of a type */
+fn alignment(kind: u8) -> usize {
+	match kind {
+		b'n' | b'q' => 2,
+		b'b' | b'i' | b'u' | b'h' | b's' | b'o' | b'a' => 4,
+		b'x' | b't' | b'd' | b'(' | b'{' => 8,
+		_ => 1,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn reads_the_messages_it_writes() {
+		let call = method_call(
+			7,
+			"org.freedesktop.NetworkManager",
+			"/org/freedesktop/NetworkManager/Devices/3",
+			"org.freedesktop.DBus.Properties",
+			"Get",
+			&["org.freedesktop.NetworkManager.Device", "State"],
+		);
+		let message = parse_message(&call).unwrap();
+		assert_eq!(message.kind, METHOD_CALL);
+		assert_eq!(
+			message.body,
+			vec![
+				Value::Str("org.freedesktop.NetworkManager.Device".to_string()),
+				Value::Str("State".to_string())
+			]
+		);
+
+		// A reply to it: a variant holding a dictionary, as GetAll would return
+		let mut reply = vec![b'l', METHOD_RETURN, 0, 1, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0];
+		reply.extend(&[5, 1, b'u', 0, 7, 0, 0, 0]);
+		reply.extend(&[8, 1, b'g', 0, 1, b'v', 0]);
+		reply[12] = (reply.len() - 16) as u8;
+		pad(&mut reply, 8);
+		let body_start = reply.len();
+		reply.extend(&[5, b'a', b'{', b's', b'v', b'}', 0]);
+		pad(&mut reply, 4);
+		let size_at = reply.len();
+		reply.extend(&[0; 4]);
+		pad(&mut reply, 8);
+		let entries_start = reply.len();
+		write_string(&mut reply, "State");
+		reply.extend(&[1, b'u', 0]);
+		pad(&mut reply, 4);
+		reply.extend(&100u32.to_le_bytes());
+		let size = (reply.len() - entries_start) as u32;
+		reply[size_at..size_at + 4].copy_from_slice(&size.to_le_bytes());
+		let body_size = (reply.len() - body_start) as u32;
+		reply[4..8].copy_from_slice(&body_size.to_le_bytes());
+
+		let message = parse_message(&reply).unwrap();
+		assert_eq!(message.reply_serial, Some(7));
+		assert_eq!(message.body[0].get("State").and_then(Value::as_u64), Some(100));
+		assert_eq!(message.body[0].get("Managed"), None);
+	}
+
+	#[test]
+	fn reads_network_manager_replies() {
+		// GetDeviceByIpIface, as relayed by the bus: an object path
+		let device: &[u8] = &[
+			0x6c, 0x02, 0x01, 0x01, 0x2e, 0x00, 0x00, 0x00, 0x1f, 0x07, 0x00, 0x00, 0x2d, 0x00, 0x00, 0x00, 0x05, 0x01,
+			0x75, 0x00, 0x03, 0x00, 0x00, 0x00, 0x06, 0x01, 0x73, 0x00, 0x05, 0x00, 0x00, 0x00, 0x3a, 0x31, 0x2e, 0x35,
+			0x37, 0x00, 0x00, 0x00, 0x08, 0x01, 0x67, 0x00, 0x01, 0x6f, 0x00, 0x00, 0x07, 0x01, 0x73, 0x00, 0x04, 0x00,
+			0x00, 0x00, 0x3a, 0x31, 0x2e, 0x38, 0x00, 0x00, 0x00, 0x00, 0x29, 0x00, 0x00, 0x00, 0x2f, 0x6f, 0x72, 0x67,
+			0x2f, 0x66, 0x72, 0x65, 0x65, 0x64, 0x65, 0x73, 0x6b, 0x74, 0x6f, 0x70, 0x2f, 0x4e, 0x65, 0x74, 0x77, 0x6f,
+			0x72, 0x6b, 0x4d, 0x61, 0x6e, 0x61, 0x67, 0x65, 0x72, 0x2f, 0x44, 0x65, 0x76, 0x69, 0x63, 0x65, 0x73, 0x2f,
+			0x33, 0x00,
+		];
+		let message = parse_message(device).unwrap();
+		assert_eq!(message.kind, METHOD_RETURN);
+		assert_eq!(message.reply_serial, Some(3));
+		assert_eq!(
+			message.body[0].as_str(),
+			Some("/org/freedesktop/NetworkManager/Devices/3")
+		);
+
+		// Properties.Get of the device State: a variant holding 100, activated
+		let state: &[u8] = &[
+			0x6c, 0x02, 0x01, 0x01, 0x08, 0x00, 0x00, 0x00, 0x20, 0x07, 0x00, 0x00, 0x2d, 0x00, 0x00, 0x00, 0x05, 0x01,
+			0x75, 0x00, 0x04, 0x00, 0x00, 0x00, 0x06, 0x01, 0x73, 0x00, 0x05, 0x00, 0x00, 0x00, 0x3a, 0x31, 0x2e, 0x35,
+			0x37, 0x00, 0x00, 0x00, 0x08, 0x01, 0x67, 0x00, 0x01, 0x76, 0x00, 0x00, 0x07, 0x01, 0x73, 0x00, 0x04, 0x00,
+			0x00, 0x00, 0x3a, 0x31, 0x2e, 0x38, 0x00, 0x00, 0x00, 0x00, 0x01, 0x75, 0x00, 0x00, 0x64, 0x00, 0x00, 0x00,
+		];
+		let message = parse_message(state).unwrap();
+		assert_eq!(message.reply_serial, Some(4));
+		assert_eq!(message.body[0].as_u64(), Some(100));
+	}
+
+	#[test]
+	fn reads_iwd_managed_objects() {
+		// GetManagedObjects with a single station
+		let objects: &[u8] = &[
+			0x6c, 0x02, 0x01, 0x01, 0xe4, 0x00, 0x00, 0x00, 0x9c, 0x01, 0x00, 0x00, 0x3e, 0x00, 0x00, 0x00, 0x05, 0x01,
+			0x75, 0x00, 0x03, 0x00, 0x00, 0x00, 0x06, 0x01, 0x73, 0x00, 0x05, 0x00, 0x00, 0x00, 0x3a, 0x31, 0x2e, 0x35,
+			0x37, 0x00, 0x00, 0x00, 0x08, 0x01, 0x67, 0x00, 0x0d, 0x61, 0x7b, 0x6f, 0x61, 0x7b, 0x73, 0x61, 0x7b, 0x73,
+			0x76, 0x7d, 0x7d, 0x7d, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x01, 0x73, 0x00, 0x05, 0x00, 0x00, 0x00,
+			0x3a, 0x31, 0x2e, 0x31, 0x32, 0x00, 0x00, 0x00, 0xdc, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x14, 0x00,
+			0x00, 0x00, 0x2f, 0x6e, 0x65, 0x74, 0x2f, 0x63, 0x6f, 0x6e, 0x6e, 0x6d, 0x61, 0x6e, 0x2f, 0x69, 0x77, 0x64,
+			0x2f, 0x30, 0x2f, 0x34, 0x00, 0x00, 0x00, 0x00, 0xbc, 0x00, 0x00, 0x00, 0x16, 0x00, 0x00, 0x00, 0x6e, 0x65,
+			0x74, 0x2e, 0x63, 0x6f, 0x6e, 0x6e, 0x6d, 0x61, 0x6e, 0x2e, 0x69, 0x77, 0x64, 0x2e, 0x44, 0x65, 0x76, 0x69,
+			0x63, 0x65, 0x00, 0x00, 0x48, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x4e, 0x61, 0x6d, 0x65, 0x00, 0x01,
+			0x73, 0x00, 0x05, 0x00, 0x00, 0x00, 0x77, 0x6c, 0x61, 0x6e, 0x30, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00,
+			0x50, 0x6f, 0x77, 0x65, 0x72, 0x65, 0x64, 0x00, 0x01, 0x62, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+			0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x4d, 0x6f, 0x64, 0x65, 0x00, 0x01, 0x73, 0x00, 0x07, 0x00, 0x00, 0x00,
+			0x73, 0x74, 0x61, 0x74, 0x69, 0x6f, 0x6e, 0x00, 0x17, 0x00, 0x00, 0x00, 0x6e, 0x65, 0x74, 0x2e, 0x63, 0x6f,
+			0x6e, 0x6e, 0x6d, 0x61, 0x6e, 0x2e, 0x69, 0x77, 0x64, 0x2e, 0x53, 0x74, 0x61, 0x74, 0x69, 0x6f, 0x6e, 0x00,
+			0x34, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x53, 0x74, 0x61, 0x74, 0x65, 0x00, 0x01, 0x73, 0x00, 0x00,
+			0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x63, 0x6f, 0x6e, 0x6e, 0x65, 0x63, 0x74, 0x65, 0x64, 0x00, 0x00, 0x00,
+			0x08, 0x00, 0x00, 0x00, 0x53, 0x63, 0x61, 0x6e, 0x6e, 0x69, 0x6e, 0x67, 0x00, 0x01, 0x62, 0x00, 0x00, 0x00,
+			0x00, 0x00,
+		];
+		let message = parse_message(objects).unwrap();
+		let object = &message.body[0].as_array().unwrap()[0];
+		let interfaces = match object {
+			Value::Struct(fields) => {
+				assert_eq!(fields[0].as_str(), Some("/net/connman/iwd/0/4"));
+				&fields[1]
+			}
+			_ => panic!("not an object: {:?}", object),
+		};
+		let device = interfaces.get("net.connman.iwd.Device").unwrap();
+		assert_eq!(device.get("Name").and_then(Value::as_str), Some("wlan0"));
+		assert_eq!(
+			device.get("Powered"),
+			Some(&Value::Variant(Box::new(Value::Bool(true))))
+		);
+		let station = interfaces.get("net.connman.iwd.Station").unwrap();
+		assert_eq!(station.get("State").and_then(Value::as_str), Some("connected"));
+	}
+
+	#[test]
+	fn rejects_empty_array_elements() {
+		// An array of empty structs, which would never advance through its 8 bytes
+		let mut data = vec![b'l', METHOD_RETURN, 0, 1, 16, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0];
+		data.extend(&[8, 1, b'g', 0, 3, b'a', b'(', b')', 0]);
+		data[12] = (data.len() - 16) as u8;
+		pad(&mut data, 8);
+		data.extend(&[8, 0, 0, 0, 0, 0, 0, 0]);
+		data.extend(&[0; 8]);
+		assert!(parse_message(&data).is_err());
+	}
+}
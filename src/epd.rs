@@ -1,36 +1,118 @@
-use rppal::gpio::Level;
-use rppal::gpio::{InputPin, OutputPin};
-use spidev::Spidev;
+use crate::metrics;
 use std::error::Error;
 use std::io::prelude::*;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+/** GPIO line driven by us (CS, DC and RST) */
+pub trait OutputLine: Send {
+	fn set_high(&mut self) -> Result<(), Box<dyn Error>>;
+	fn set_low(&mut self) -> Result<(), Box<dyn Error>>;
+}
+
+/** GPIO line driven by the display (BUSY) */
+pub trait InputLine: Send {
+	fn is_high(&mut self) -> Result<bool, Box<dyn Error>>;
+}
+
+/** GPIO lines a display is connected to. On a Raspberry Pi these are BCM pin numbers; for the GPIO character device
+they are line offsets on the chip. */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pins {
+	pub busy: u32,
+	/** None when the SPI controller drives chip select itself (CE0 for spidev0.0, CE1 for spidev0.1) */
+	pub cs: Option<u32>,
+	pub dc: u32,
+	pub rst: u32,
+}
+
+impl Default for Pins {
+	/** Pins used by the Waveshare e-Paper HAT on a Raspberry Pi */
+	fn default() -> Pins {
+		Pins {
+			busy: 24,    // physical 18
+			cs: Some(8), // CE0
+			dc: 25,
+			rst: 17,
+		}
+	}
+}
+
+/** SPI device to send commands and data over; anything that accepts bytes will do (such as `spidev::Spidev`). Each
+write is sent as a single transfer. */
+pub type SpiBus = Box<dyn Write + Send>;
+
+/** Largest transfer to send when the SPI device does not say otherwise (the default spidev buffer size) */
+pub const DEFAULT_MAX_TRANSFER_SIZE: usize = 4096;
+
+/** Connection to a Waveshare e-paper display: the SPI bus and the BUSY, CS, DC and RST pins. Panel drivers use this to
+send commands and data; it does not know anything about a specific panel. */
 pub struct EPD {
-	pub(crate) spi: Spidev,
-	pub(crate) busy: InputPin,
-	pub(crate) cs: OutputPin,
-	pub(crate) dc: OutputPin,
-	pub(crate) rst: OutputPin,
+	pub(crate) spi: SpiBus,
+	pub(crate) busy: Box<dyn InputLine>,
+	/** None when the SPI controller drives chip select itself */
+	pub(crate) cs: Option<Box<dyn OutputLine>>,
+	pub(crate) dc: Box<dyn OutputLine>,
+	pub(crate) rst: Box<dyn OutputLine>,
+	pub(crate) max_transfer_size: usize,
 }
 
-pub trait EPDDisplay {
+/** A panel that can show packed images (see `graphics::Bitmap` for the layout of the buffers) */
+pub trait EPDDisplay: Send {
+	/** Wake up the panel and prepare it for drawing; call before drawing after `sleep` */
 	fn init(&mut self) -> Result<(), Box<dyn Error>>;
+	/** Put the panel in deep sleep. The image remains visible. */
 	fn sleep(&mut self) -> Result<(), Box<dyn Error>>;
+	/** Draw a packed black and white image of exactly `width() * height() / 8` bytes */
 	fn draw(&mut self, buffer: &[u8]) -> Result<(), Box<dyn Error>>;
+	/** Draw black and colored pixels. Displays without a third color only draw the black pixels. */
+	fn draw_bichromatic(&mut self, black_buffer: &[u8], _color_buffer: &[u8]) -> Result<(), Box<dyn Error>> {
+		self.draw(black_buffer)
+	}
+	/** Width of the panel in pixels */
 	fn width(&self) -> usize;
+	/** Height of the panel in pixels */
 	fn height(&self) -> usize;
 }
 
 impl EPD {
+	/** Pass None for `cs` when the SPI controller drives chip select itself */
+	pub fn new(
+		spi: SpiBus,
+		busy: Box<dyn InputLine>,
+		cs: Option<Box<dyn OutputLine>>,
+		dc: Box<dyn OutputLine>,
+		rst: Box<dyn OutputLine>,
+	) -> EPD {
+		EPD {
+			spi,
+			busy,
+			cs,
+			dc,
+			rst,
+			max_transfer_size: DEFAULT_MAX_TRANSFER_SIZE,
+		}
+	}
+
+	/** Set the largest number of bytes the SPI device accepts in a single transfer (spidev's bufsiz) */
+	pub fn set_max_transfer_size(&mut self, size: usize) {
+		self.max_transfer_size = size.max(1);
+	}
+
 	pub fn spi_transfer(&mut self, data: &[u8]) -> Result<(), Box<dyn Error>> {
-		self.cs.set_low();
-		for chunk in data.chunks(128) {
+		let started = Instant::now();
+		if let Some(cs) = &mut self.cs {
+			cs.set_low()?;
+		}
+		for chunk in data.chunks(self.max_transfer_size) {
 			if self.spi.write(chunk)? != chunk.len() {
 				panic!("too little bytes written to SPI");
 			}
 		}
-		self.cs.set_high();
+		if let Some(cs) = &mut self.cs {
+			cs.set_high()?;
+		}
+		metrics::spi_transfer(data.len(), started.elapsed());
 		Ok(())
 	}
 
@@ -51,51 +133,198 @@ impl EPD {
 	}
 
 	pub fn send_command(&mut self, cmd: u8) -> Result<(), Box<dyn Error>> {
-		self.dc.set_low();
-		log::debug!("send cmd {:02x?} busy={:?}", cmd, self.busy.read());
+		self.dc.set_low()?;
+		log::debug!("send cmd {:02x?} busy={:?}", cmd, self.busy.is_high()?);
 		self.spi_transfer(&[cmd])?;
 		Ok(())
 	}
 
 	pub fn send_data(&mut self, data: &[u8]) -> Result<(), Box<dyn Error>> {
-		self.dc.set_high();
+		self.dc.set_high()?;
 		self.spi_transfer(data)?;
 		Ok(())
 	}
 
 	// https://github.com/waveshare/e-Paper/blob/master/RaspberryPi_JetsonNano/c/lib/e-Paper/EPD_7in5_V2.c#L38
 	// https://github.com/waveshare/e-Paper/blob/master/RaspberryPi_JetsonNano/c/lib/e-Paper/EPD_7in5bc.c#L92
-	pub fn reset(&mut self) {
-		self.cs.set_high();
+	pub fn reset(&mut self) -> Result<(), Box<dyn Error>> {
+		if let Some(cs) = &mut self.cs {
+			cs.set_high()?;
+		}
 		log::debug!("reset");
-		self.rst.set_high();
+		self.rst.set_high()?;
 		sleep(Duration::from_millis(200));
-		self.rst.set_low();
+		self.rst.set_low()?;
 		sleep(Duration::from_millis(2));
-		self.rst.set_high();
+		self.rst.set_high()?;
 		sleep(Duration::from_millis(200));
-		log::debug!("reset done; busy={:?}", self.busy.read());
+		log::debug!("reset done; busy={:?}", self.busy.is_high()?);
+		Ok(())
 	}
 
 	// https://github.com/waveshare/e-Paper/blob/master/RaspberryPi_JetsonNano/c/lib/e-Paper/EPD_7in5_V2.c#L78
 	pub fn wait_until_idle(&mut self) -> Result<(), Box<dyn Error>> {
 		//assert!(self.busy.is_high(), "expect busy pin to be high before waiting until idle");
+		let started = Instant::now();
 		let mut n = 0;
 		log::debug!(
 			"wait_until_idle busy={:?} {:?} {:?}",
-			self.busy.read(),
-			self.busy.read(),
-			self.busy.read()
+			self.busy.is_high()?,
+			self.busy.is_high()?,
+			self.busy.is_high()?
 		);
 		loop {
 			n += 1;
 			self.send_command(0x71)?;
 			sleep(Duration::from_millis(20));
-			if self.busy.read() == Level::High {
+			if self.busy.is_high()? {
 				sleep(Duration::from_millis(100));
 				log::debug!("busy release n={:?}", n);
+				metrics::busy_wait(started.elapsed());
 				return Ok(());
 			}
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::{Arc, Mutex};
+
+	/** Everything that happened on the fake chip and bus, in order */
+	#[derive(Debug, Clone, PartialEq)]
+	enum Event {
+		Line(&'static str, bool),
+		Spi(Vec<u8>),
+	}
+
+	type Log = Arc<Mutex<Vec<Event>>>;
+
+	struct FakeLine {
+		name: &'static str,
+		log: Log,
+	}
+
+	impl OutputLine for FakeLine {
+		fn set_high(&mut self) -> Result<(), Box<dyn Error>> {
+			self.log.lock().unwrap().push(Event::Line(self.name, true));
+			Ok(())
+		}
+
+		fn set_low(&mut self) -> Result<(), Box<dyn Error>> {
+			self.log.lock().unwrap().push(Event::Line(self.name, false));
+			Ok(())
+		}
+	}
+
+	/** BUSY line that reports 'busy' (low) a number of times before going high */
+	struct FakeBusy {
+		busy_reads: u32,
+	}
+
+	impl InputLine for FakeBusy {
+		fn is_high(&mut self) -> Result<bool, Box<dyn Error>> {
+			if self.busy_reads > 0 {
+				self.busy_reads -= 1;
+				Ok(false)
+			} else {
+				Ok(true)
+			}
+		}
+	}
+
+	struct FakeSpi {
+		log: Log,
+	}
+
+	impl Write for FakeSpi {
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+			self.log.lock().unwrap().push(Event::Spi(buf.to_vec()));
+			Ok(buf.len())
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+
+	fn fake_epd(busy_reads: u32, gpio_cs: bool) -> (EPD, Log) {
+		let log: Log = Arc::new(Mutex::new(vec![]));
+		let line = |name| Box::new(FakeLine { name, log: log.clone() });
+		let cs: Option<Box<dyn OutputLine>> = if gpio_cs { Some(line("cs")) } else { None };
+		let epd = EPD::new(
+			Box::new(FakeSpi { log: log.clone() }),
+			Box::new(FakeBusy { busy_reads }),
+			cs,
+			line("dc"),
+			line("rst"),
+		);
+		(epd, log)
+	}
+
+	#[test]
+	fn send_selects_command_and_data_mode() {
+		let (mut epd, log) = fake_epd(0, true);
+		epd.send(0x12, &[0x34, 0x56]).unwrap();
+		assert_eq!(
+			*log.lock().unwrap(),
+			vec![
+				Event::Line("dc", false),
+				Event::Line("cs", false),
+				Event::Spi(vec![0x12]),
+				Event::Line("cs", true),
+				Event::Line("dc", true),
+				Event::Line("cs", false),
+				Event::Spi(vec![0x34, 0x56]),
+				Event::Line("cs", true),
+			]
+		);
+	}
+
+	#[test]
+	fn kernel_chip_select_sends_whole_transfers() {
+		let (mut epd, log) = fake_epd(0, false);
+		epd.set_max_transfer_size(4);
+		epd.send(0x13, &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]).unwrap();
+		assert_eq!(
+			*log.lock().unwrap(),
+			vec![
+				Event::Line("dc", false),
+				Event::Spi(vec![0x13]),
+				Event::Line("dc", true),
+				Event::Spi(vec![1, 2, 3, 4]),
+				Event::Spi(vec![5, 6, 7, 8]),
+				Event::Spi(vec![9, 10]),
+			]
+		);
+	}
+
+	#[test]
+	fn reset_pulses_rst_low() {
+		let (mut epd, log) = fake_epd(0, true);
+		epd.reset().unwrap();
+		assert_eq!(
+			*log.lock().unwrap(),
+			vec![
+				Event::Line("cs", true),
+				Event::Line("rst", true),
+				Event::Line("rst", false),
+				Event::Line("rst", true),
+			]
+		);
+	}
+
+	#[test]
+	fn wait_until_idle_polls_until_busy_is_released() {
+		let (mut epd, log) = fake_epd(3, true);
+		epd.wait_until_idle().unwrap();
+		let polls = log
+			.lock()
+			.unwrap()
+			.iter()
+			.filter(|e| **e == Event::Spi(vec![0x71]))
+			.count();
+		assert!(polls > 1);
+	}
+}
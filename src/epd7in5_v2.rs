@@ -1,34 +1,27 @@
 use crate::epd::{EPDDisplay, EPD};
-use rppal::gpio::{InputPin, OutputPin};
-use spidev::Spidev;
 use std::error::Error;
 use std::thread::sleep;
 use std::time::Duration;
+/** Waveshare 7.5" V2 panel (800x480, black and white) */
 pub struct EPD7in5v2 {
 	epd: EPD,
 }
 
-const EPD7IN5V2_WIDTH: usize = 800;
-const EPD7IN5V2_HEIGHT: usize = 480;
+pub const EPD7IN5V2_WIDTH: usize = 800;
+pub const EPD7IN5V2_HEIGHT: usize = 480;
 
 impl EPD7in5v2 {
-	pub fn new(spi: Spidev, busy: InputPin, cs: OutputPin, dc: OutputPin, rst: OutputPin) -> EPD7in5v2 {
-		EPD7in5v2 {
-			epd: EPD { spi, busy, cs, dc, rst },
-		}
+	pub fn new(epd: EPD) -> EPD7in5v2 {
+		EPD7in5v2 { epd }
 	}
 
 	pub fn clear(&mut self, black: bool) -> Result<(), Box<dyn Error>> {
-		let width = if EPD7IN5V2_WIDTH % 8 == 0 {
-			EPD7IN5V2_WIDTH / 8
-		} else {
-			EPD7IN5V2_WIDTH / 8 + 1
-		};
+		let width = EPD7IN5V2_WIDTH.div_ceil(8);
 		let height = EPD7IN5V2_HEIGHT;
 		log::debug!("clear w={:?} h={:?}", width, height);
 
-		let zeroes = vec![0x00 as u8; height * width];
-		let ffs = vec![0xFF as u8; height * width];
+		let zeroes = vec![0x00u8; height * width];
+		let ffs = vec![0xFFu8; height * width];
 
 		if black {
 			//self.send(0x10, &zeroes)?;
@@ -64,7 +57,7 @@ impl EPDDisplay for EPD7in5v2 {
 
 	fn draw(&mut self, buffer: &[u8]) -> Result<(), Box<dyn Error>> {
 		if buffer.len() != (EPD7IN5V2_HEIGHT * EPD7IN5V2_WIDTH / 8) {
-			panic!("invalid buffer size");
+			return Err("invalid buffer size".into());
 		}
 
 		self.epd.send(0x13, buffer)?;
@@ -81,7 +74,7 @@ impl EPDDisplay for EPD7in5v2 {
 	}
 
 	fn init(&mut self) -> Result<(), Box<dyn Error>> {
-		self.epd.reset();
+		self.epd.reset()?;
 		self.epd.send(0x01, &[0x07, 0x07, 0x3f, 0x3f])?; // Power setting VGH=20V, VGL=-20V, VDH=15V, VDL=-15V
 		self.epd.send_command(0x04)?; // POWER ON
 		sleep(Duration::from_millis(100));
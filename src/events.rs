@@ -0,0 +1,191 @@
+/*! Fetches, refreshes and failures, with stable fields (see `Event`) so that logs can be searched by machines.
+
+Events are logged like any other line, and the fields of the event being logged can be added to JSON log lines (see
+`json_line`). They are also appended to an event log on disk (see `open_log`), which survives reboots and is rotated
+when it grows too large, so that what a display did overnight can be found out afterwards. */
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/** Size at which the event log is rotated; the previous one is kept with '.1' appended to its name */
+pub const MAX_LOG_SIZE: u64 = 1024 * 1024;
+
+/** Something that happened to a display */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+	pub time: DateTime<Local>,
+	/** What happened: 'fetched', 'fetch_failed', 'refreshed', 'refresh_failed', 'started' or 'stopped' */
+	pub event: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub display: Option<String>,
+	/** The panel drawn on (a display, or a tile of a wall) */
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub panel: Option<String>,
+	/** URL of the source fetched from */
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub source: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub duration_ms: Option<u64>,
+	/** Type of error, such as 'timeout' or 'http_status' (see `fetch::error_kind`) */
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub error_kind: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub error: Option<String>,
+}
+
+impl Event {
+	pub fn new(event: &str, display: Option<&str>) -> Event {
+		Event {
+			time: Local::now(),
+			event: event.to_string(),
+			display: display.map(|d| d.to_string()),
+			panel: None,
+			source: None,
+			duration_ms: None,
+			error_kind: None,
+			error: None,
+		}
+	}
+}
+
+/** Milliseconds, as events give durations */
+pub fn millis(duration: Duration) -> Option<u64> {
+	Some(duration.as_millis() as u64)
+}
+
+/** The event log, once opened */
+struct EventLog {
+	path: PathBuf,
+	file: File,
+	size: u64,
+}
+
+static EVENT_LOG: Mutex<Option<EventLog>> = Mutex::new(None);
+
+thread_local! {
+	/** The event being logged on this thread, for `json_line` */
+	static CURRENT: RefCell<Option<Event>> = const { RefCell::new(None) };
+}
+
+/** Append events to the file at `path` from now on (or stop writing them, with None) */
+pub fn open_log(path: Option<&Path>) -> Result<(), Box<dyn Error>> {
+	let log = match path {
+		Some(path) => {
+			let file = OpenOptions::new()
+				.create(true)
+				.append(true)
+				.open(path)
+				.map_err(|e| format!("could not open event log {:?}: {}", path, e))?;
+			let size = file.metadata()?.len();
+			Some(EventLog {
+				path: path.to_path_buf(),
+				file,
+				size,
+			})
+		}
+		None => None,
+	};
+	*EVENT_LOG.lock().unwrap() = log;
+	Ok(())
+}
+
+/** Log `message` at `level` for an event, and append the event to the event log */
+pub fn record(level: log::Level, event: Event, message: &str) {
+	CURRENT.with(|current| *current.borrow_mut() = Some(event));
+	log::log!(level, "{}", message);
+	let event = CURRENT.with(|current| current.borrow_mut().take()).expect("set above");
+
+	let mut log = EVENT_LOG.lock().unwrap();
+	if let Some(event_log) = log.as_mut() {
+		if let Err(e) = append(event_log, &event) {
+			// Logging this through `log` could end up here again
+			eprintln!("could not write to event log {:?}: {}", event_log.path, e);
+		}
+	}
+}
+
+/** Write an event to the log as a line of JSON, rotating the log first if it would grow too large */
+fn append(log: &mut EventLog, event: &Event) -> Result<(), Box<dyn Error>> {
+	let mut line = serde_json::to_string(event)?;
+	line.push('\n');
+	if log.size > 0 && log.size + line.len() as u64 > MAX_LOG_SIZE {
+		let mut rotated = log.path.clone().into_os_string();
+		rotated.push(".1");
+		fs::rename(&log.path, rotated)?;
+		log.file = OpenOptions::new().create(true).append(true).open(&log.path)?;
+		log.size = 0;
+	}
+	log.file.write_all(line.as_bytes())?;
+	// Events are rare, and the point is to still have them after the power was cut
+	log.file.sync_data()?;
+	log.size += line.len() as u64;
+	Ok(())
+}
+
+/** A log record as a line of JSON (without newline): time, level, target and message, and the fields of the event it
+is for (if any) */
+pub fn json_line(record: &log::Record) -> String {
+	let event = CURRENT.with(|current| current.borrow().clone());
+	let mut object = match event.map(serde_json::to_value) {
+		Some(Ok(serde_json::Value::Object(object))) => object,
+		_ => serde_json::Map::new(),
+	};
+	object.insert("time".to_string(), Local::now().to_rfc3339().into());
+	object.insert("level".to_string(), record.level().as_str().into());
+	object.insert("target".to_string(), record.target().into());
+	object.insert("message".to_string(), record.args().to_string().into());
+	serde_json::Value::Object(object).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn event_fields_are_added_to_json_lines() {
+		let args = format_args!("fetch failed");
+		let record = log::Record::builder().args(args).level(log::Level::Error).build();
+		let event = Event {
+			source: Some("http://a/".to_string()),
+			error_kind: Some("timeout".to_string()),
+			..Event::new("fetch_failed", Some("hall"))
+		};
+		CURRENT.with(|current| *current.borrow_mut() = Some(event));
+		let line: serde_json::Value = serde_json::from_str(&json_line(&record)).unwrap();
+		CURRENT.with(|current| current.borrow_mut().take());
+		assert_eq!(line["event"], "fetch_failed");
+		assert_eq!(line["display"], "hall");
+		assert_eq!(line["source"], "http://a/");
+		assert_eq!(line["error_kind"], "timeout");
+		assert_eq!(line["level"], "ERROR");
+		assert_eq!(line["message"], "fetch failed");
+		assert!(line.get("panel").is_none());
+
+		let plain: serde_json::Value = serde_json::from_str(&json_line(&record)).unwrap();
+		assert!(plain.get("event").is_none());
+	}
+
+	#[test]
+	fn event_log_is_rotated() {
+		let path = std::env::temp_dir().join(format!("sparkboard-events-{}.log", std::process::id()));
+		let rotated = PathBuf::from(format!("{}.1", path.display()));
+		let mut log = EventLog {
+			path: path.clone(),
+			file: File::create(&path).unwrap(),
+			size: MAX_LOG_SIZE - 10,
+		};
+		append(&mut log, &Event::new("refreshed", Some("hall"))).unwrap();
+		assert!(rotated.exists());
+		let text = fs::read_to_string(&path).unwrap();
+		let event: Event = serde_json::from_str(text.trim_end()).unwrap();
+		assert_eq!(event.event, "refreshed");
+		fs::remove_file(path).unwrap();
+		fs::remove_file(rotated).unwrap();
+	}
+}
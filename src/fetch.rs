@@ -1,79 +1,414 @@
-use bytes::Buf;
-use dither::prelude::*;
+use crate::graphics::Bitmap;
+use crate::image::{check_size, decode_png, ImageError, Panel};
+use crate::metrics;
+use crate::raw;
+use crate::refresh;
+use crate::tls::TlsOptions;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
 use std::error::Error;
+use std::fmt;
+use std::io::Read;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-/** Fetch a URL and return it as a black and white (packed) bitmap. Size of the buffer will be width * height / 8. */
-pub fn fetch(url: &str) -> std::result::Result<Vec<u8>, Box<dyn Error>> {
-	let client = reqwest::blocking::Client::builder()
-		.user_agent("Sparkboard/1.0")
-		.danger_accept_invalid_certs(true)
-		.build()?;
-
-	let resp = client.get(url).send()?;
-	let png = resp.bytes()?;
-	log::info!("got image: {:#?} bytes", png.len());
-
-	// Read PNG
-	let decoder = png::Decoder::new(png.reader());
-	let (info, mut reader) = decoder.read_info().unwrap();
-	log::info!("image size {:?}x{:?}", info.width, info.height);
-
-	// Allocate the output buffer.
-	let mut buf = vec![0; info.buffer_size()];
-	// Read the next frame. An APNG might contain multiple frames.
-	reader.next_frame(&mut buf).unwrap();
-
-	// Dither
-	let mut source: Vec<RGB<u8>> = vec![RGB(0, 0, 0); (info.width * info.height) as usize];
-	let w = info.width as usize;
-
-	for x in 0..(info.width as usize) {
-		for y in 0..(info.height as usize) {
-			let r = buf[(x + w * y) * 4];
-			let g = buf[(x + w * y) * 4 + 1];
-			let b = buf[(x + w * y) * 4 + 2];
-			//let avg = ((r as u64 + g as u64 + b as u64) / 3) as u8;
-			let avg = r.min(g).min(b);
-
-			source[x + w * y] = RGB(avg, avg, avg)
+/** A secret (password or token) that is read when needed, so it does not have to appear on the command line */
+#[derive(Debug, Clone)]
+pub enum Secret {
+	File(PathBuf),
+	Env(String),
+}
+
+impl Secret {
+	pub fn read(&self) -> Result<String, Box<dyn Error>> {
+		match self {
+			Secret::File(path) => Ok(std::fs::read_to_string(path)?.trim_end().to_string()),
+			Secret::Env(name) => std::env::var(name).map_err(|e| format!("{}: {}", name, e).into()),
+		}
+	}
+}
+
+impl FromStr for Secret {
+	type Err = Box<dyn Error>;
+
+	/** Parses 'file:PATH' or 'env:NAME' */
+	fn from_str(s: &str) -> Result<Secret, Self::Err> {
+		if let Some(path) = s.strip_prefix("file:") {
+			Ok(Secret::File(PathBuf::from(path)))
+		} else if let Some(name) = s.strip_prefix("env:") {
+			Ok(Secret::Env(name.to_string()))
+		} else {
+			Err(format!("secret should be 'file:PATH' or 'env:NAME', got {:?}", s).into())
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
+pub enum Auth {
+	Basic { username: String, password: Option<Secret> },
+	Bearer(Secret),
+}
+
+/** Location of an image to fetch, and how to connect to it */
+#[derive(Debug, Clone)]
+pub struct Source {
+	pub url: String,
+	pub tls: TlsOptions,
+	/** Additional request headers */
+	pub headers: Vec<(String, String)>,
+	pub auth: Option<Auth>,
+	pub connect_timeout: Option<Duration>,
+	/** Timeout for the whole request, including reading the response body */
+	pub timeout: Option<Duration>,
+	/** Fail when the response body is larger than this many bytes */
+	pub max_body_size: Option<u64>,
+	/** Proxy URL to send all requests through */
+	pub proxy: Option<String>,
+	/** Number of redirects to follow (zero to not follow redirects at all) */
+	pub max_redirects: usize,
+}
+
+impl Source {
+	pub fn new(url: &str) -> Source {
+		Source {
+			url: url.to_string(),
+			tls: TlsOptions::default(),
+			headers: vec![],
+			auth: None,
+			connect_timeout: Some(Duration::from_secs(10)),
+			timeout: Some(Duration::from_secs(60)),
+			max_body_size: Some(10 * 1024 * 1024),
+			proxy: None,
+			max_redirects: 10,
 		}
 	}
+}
+
+/** Parse a header written as 'Name: value' */
+pub fn parse_header(text: &str) -> Result<(String, String), Box<dyn Error>> {
+	match text.find(':') {
+		Some(index) => Ok((text[..index].trim().to_string(), text[index + 1..].trim().to_string())),
+		None => Err(format!("header should be 'Name: value', got {:?}", text).into()),
+	}
+}
 
-	let source_image = Img::new(source, info.width).unwrap();
-	let source_image_f = source_image.convert_with(|rgb| rgb.convert_with(f64::from));
+/** Error status returned by the server, with the delay it asked us to wait before trying again (if any) */
+#[derive(Debug)]
+pub struct HttpError {
+	pub status: reqwest::StatusCode,
+	pub retry_after: Option<Duration>,
+}
 
-	let quantize = dither::create_quantize_n_bits_func(1)?;
-	let dest = dither::ditherer::STUCKI
-		.dither(source_image_f, RGB::map_across(quantize))
-		.convert_with(|rgb| rgb.convert_with(clamp_f64_to_u8));
+impl fmt::Display for HttpError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "server returned {}", self.status)
+	}
+}
 
-	// Build BW buffer
-	let w = if info.width % 8 == 0 {
-		info.width
+impl Error for HttpError {}
+
+/** What kind of error a fetch failed with, for `events`: 'http_status', 'timeout', 'connect', 'request', 'io', 'decode',
+'size' (the image does not fit the panel) or 'other' */
+pub fn error_kind(error: &(dyn Error + 'static)) -> &'static str {
+	if error.is::<HttpError>() {
+		"http_status"
+	} else if let Some(e) = error.downcast_ref::<reqwest::Error>() {
+		if e.is_timeout() {
+			"timeout"
+		} else if e.is_connect() {
+			"connect"
+		} else {
+			"request"
+		}
+	} else if error.is::<std::io::Error>() {
+		"io"
+	} else if error.is::<png::DecodingError>() {
+		"decode"
+	} else if let Some(e) = error.downcast_ref::<ImageError>() {
+		match e {
+			ImageError::Decode(_) => "decode",
+			ImageError::Size { .. } => "size",
+		}
 	} else {
-		info.width + 1
-	};
-	let mut buffer = vec![0x00 as u8; (w * info.height / 8) as usize];
-
-	for y in 0..info.height {
-		for x in 0..(w / 8) {
-			// Pack eight consecutive pixels to a single byte
-			let mut byte: u8 = 0;
-			for k in 0..8 {
-				let source_x = x * 8 + k;
-				let source_pixel = dest[(source_x, y)];
-				let black = source_pixel.0 > 0 || source_pixel.1 > 0 || source_pixel.2 > 0;
-				// Shift current set of pixels left by one
-				byte <<= 1;
-				if !black {
-					// Set rightmost bit
-					byte |= 1;
+		"other"
+	}
+}
+
+/** A fetched image, and when the server would like it to be fetched again */
+pub struct Fetched {
+	pub bitmap: Bitmap,
+	pub refresh_after: Option<Duration>,
+	/** Size of the response body */
+	pub bytes: usize,
+	/** Time taken to download the image (not including decoding it) */
+	pub download_time: Duration,
+}
+
+/** A client for a source, sending `headers` along with those configured for the source */
+fn client(source: &Source, headers: HeaderMap) -> Result<reqwest::blocking::Client, Box<dyn Error>> {
+	let mut builder = reqwest::blocking::Client::builder()
+		.user_agent("Sparkboard/1.0")
+		.use_preconfigured_tls(source.tls.client_config()?)
+		.default_headers(request_headers(source, headers)?)
+		.timeout(source.timeout)
+		.redirect(if source.max_redirects == 0 {
+			reqwest::redirect::Policy::none()
+		} else {
+			reqwest::redirect::Policy::limited(source.max_redirects)
+		});
+	if let Some(connect_timeout) = source.connect_timeout {
+		builder = builder.connect_timeout(connect_timeout);
+	}
+	if let Some(proxy) = &source.proxy {
+		builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+	}
+	Ok(builder.build()?)
+}
+
+/** The headers to send: `headers`, those configured for the source and authentication */
+fn request_headers(source: &Source, mut headers: HeaderMap) -> Result<HeaderMap, Box<dyn Error>> {
+	// Headers configured for the source replace those given
+	let mut custom = HeaderMap::new();
+	for (name, value) in &source.headers {
+		custom.append(HeaderName::from_bytes(name.as_bytes())?, HeaderValue::from_str(value)?);
+	}
+	for name in custom.keys() {
+		headers.remove(name);
+	}
+	headers.extend(custom);
+
+	match &source.auth {
+		Some(Auth::Basic { username, password }) => {
+			let password = match password {
+				Some(p) => p.read()?,
+				None => String::new(),
+			};
+			let credentials = base64::encode(format!("{}:{}", username, password));
+			headers.insert(AUTHORIZATION, HeaderValue::from_str(&format!("Basic {}", credentials))?);
+		}
+		Some(Auth::Bearer(token)) => {
+			headers.insert(
+				AUTHORIZATION,
+				HeaderValue::from_str(&format!("Bearer {}", token.read()?))?,
+			);
+		}
+		None => {}
+	}
+	Ok(headers)
+}
+
+/** Send a HEAD request to a source, returning the status it answers with */
+pub fn head(source: &Source) -> Result<reqwest::StatusCode, Box<dyn Error>> {
+	Ok(client(source, HeaderMap::new())?.head(&source.url).send()?.status())
+}
+
+fn download(source: &Source, panel: &Panel) -> Result<(Vec<u8>, HeaderMap), Box<dyn Error>> {
+	let mut headers = HeaderMap::new();
+	headers.insert("X-Sparkboard-Width", HeaderValue::from(panel.width as u64));
+	headers.insert("X-Sparkboard-Height", HeaderValue::from(panel.height as u64));
+	headers.insert("X-Sparkboard-Type", HeaderValue::from_str(&panel.kind)?);
+	headers.insert(
+		reqwest::header::ACCEPT,
+		HeaderValue::from_str(&format!("{}, image/png;q=0.9", raw::CONTENT_TYPE))?,
+	);
+
+	let client = client(source, headers)?;
+	let resp = client.get(&source.url).send()?;
+	if !resp.status().is_success() {
+		return Err(Box::new(HttpError {
+			status: resp.status(),
+			retry_after: refresh::retry_after(resp.headers()),
+		}));
+	}
+
+	let headers = resp.headers().clone();
+	let mut body = vec![];
+	match source.max_body_size {
+		Some(max) => {
+			if let Some(length) = resp.content_length() {
+				if length > max {
+					return Err(
+						format!("response is {} bytes, which exceeds the limit of {} bytes", length, max).into(),
+					);
 				}
 			}
+			resp.take(max + 1).read_to_end(&mut body)?;
+			if body.len() as u64 > max {
+				return Err(format!("response exceeds the limit of {} bytes", max).into());
+			}
+		}
+		None => {
+			let mut resp = resp;
+			resp.read_to_end(&mut body)?;
+		}
+	}
+	Ok((body, headers))
+}
+
+/** Fetch a URL and return it as a black and white (packed) bitmap. Size of the buffer will be width * height / 8.
+PNG images are dithered; images in the raw format (see the `raw` module) are used as-is. */
+pub fn fetch(source: &Source, panel: &Panel) -> Result<Fetched, Box<dyn Error>> {
+	let started = Instant::now();
+	let (body, headers) = download(source, panel)?;
+	let download_time = started.elapsed();
+	log::info!("got image: {:#?} bytes", body.len());
+	let refresh_after = refresh::refresh_hint(&headers);
+	if let Some(refresh_after) = refresh_after {
+		log::info!("server asks to refresh after {:?}", refresh_after);
+	}
+
+	let is_raw = headers
+		.get(CONTENT_TYPE)
+		.and_then(|v| v.to_str().ok())
+		.map(|v| v.starts_with(raw::CONTENT_TYPE))
+		.unwrap_or(false);
+	let bitmap = if is_raw {
+		let started = Instant::now();
+		let bitmap = raw::decode(&body, panel)?;
+		metrics::decoded(started.elapsed());
+		bitmap
+	} else {
+		decode_png(&body)?
+	};
 
-			buffer[(y * (w / 8) + x) as usize] = byte;
+	check_size(&bitmap, panel)?;
+	Ok(Fetched {
+		bitmap,
+		refresh_after,
+		bytes: body.len(),
+		download_time,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::Write;
+	use std::net::TcpListener;
+	use std::thread;
+
+	fn panel() -> Panel {
+		Panel {
+			width: 8,
+			height: 2,
+			kind: "7in5v2".to_string(),
 		}
 	}
 
-	Ok(buffer)
+	/** Serve a single request with `response`, returning the URL to request and (once done) the request received */
+	fn serve_once(response: Vec<u8>) -> (String, thread::JoinHandle<String>) {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = format!("http://{}/image", listener.local_addr().unwrap());
+		let handle = thread::spawn(move || {
+			let (mut stream, _) = listener.accept().unwrap();
+			let mut request = vec![];
+			let mut byte = [0];
+			while !request.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap() == 1 {
+				request.push(byte[0]);
+			}
+			// The client may hang up once it has read as much as it wants
+			let _ = stream.write_all(&response);
+			String::from_utf8(request).unwrap().to_lowercase()
+		});
+		(url, handle)
+	}
+
+	fn response(headers: &str, body: &[u8]) -> Vec<u8> {
+		let mut response = format!("HTTP/1.1 200 OK\r\nConnection: close\r\n{}\r\n", headers).into_bytes();
+		response.extend_from_slice(body);
+		response
+	}
+
+	#[test]
+	fn parses_headers_and_secrets() {
+		assert_eq!(
+			parse_header(" X-Time : 12:00 ").unwrap(),
+			("X-Time".to_string(), "12:00".to_string())
+		);
+		assert!(parse_header("X-Time").is_err());
+
+		let path = std::env::temp_dir().join(format!("sparkboard-secret-{}", std::process::id()));
+		std::fs::write(&path, "hunter2\n").unwrap();
+		let secret = Secret::from_str(&format!("file:{}", path.display())).unwrap();
+		assert_eq!(secret.read().unwrap(), "hunter2");
+		std::fs::remove_file(&path).unwrap();
+		assert!(secret.read().is_err());
+
+		std::env::set_var("SPARKBOARD_TEST_SECRET", "s3cret");
+		assert_eq!(
+			Secret::from_str("env:SPARKBOARD_TEST_SECRET").unwrap().read().unwrap(),
+			"s3cret"
+		);
+		assert!(Secret::from_str("env:SPARKBOARD_TEST_MISSING").unwrap().read().is_err());
+		assert!(Secret::from_str("hunter2").is_err());
+	}
+
+	#[test]
+	fn sends_configured_headers_and_authentication() {
+		std::env::set_var("SPARKBOARD_TEST_PASSWORD", "pass");
+		let mut source = Source::new("http://example.com/");
+		source.headers = vec![
+			("Accept".to_string(), "image/png".to_string()),
+			("X-Room".to_string(), "2.14".to_string()),
+			("X-Room".to_string(), "2.15".to_string()),
+		];
+		source.auth = Some(Auth::Basic {
+			username: "user".to_string(),
+			password: Some(Secret::Env("SPARKBOARD_TEST_PASSWORD".to_string())),
+		});
+		let mut defaults = HeaderMap::new();
+		defaults.insert(reqwest::header::ACCEPT, HeaderValue::from_static("image/*"));
+		defaults.insert("X-Sparkboard-Width", HeaderValue::from(800u64));
+
+		let headers = request_headers(&source, defaults).unwrap();
+		// Configured headers replace the defaults of the same name, and may be repeated
+		assert_eq!(
+			headers.get_all(reqwest::header::ACCEPT).iter().collect::<Vec<_>>(),
+			["image/png"]
+		);
+		assert_eq!(headers.get_all("X-Room").iter().collect::<Vec<_>>(), ["2.14", "2.15"]);
+		assert_eq!(headers["X-Sparkboard-Width"], "800");
+		assert_eq!(
+			headers[AUTHORIZATION],
+			format!("Basic {}", base64::encode("user:pass")).as_str()
+		);
+
+		source.auth = Some(Auth::Bearer(Secret::Env("SPARKBOARD_TEST_PASSWORD".to_string())));
+		assert_eq!(
+			request_headers(&source, HeaderMap::new()).unwrap()[AUTHORIZATION],
+			"Bearer pass"
+		);
+		source.auth = Some(Auth::Bearer(Secret::Env("SPARKBOARD_TEST_MISSING".to_string())));
+		assert!(request_headers(&source, HeaderMap::new()).is_err());
+	}
+
+	#[test]
+	fn sends_the_panel_size_and_type() {
+		let (url, request) = serve_once(response("Content-Length: 3\r\n", b"abc"));
+		let (body, _) = download(&Source::new(&url), &panel()).unwrap();
+		assert_eq!(body, b"abc");
+		let request = request.join().unwrap();
+		assert!(request.contains("\r\nx-sparkboard-width: 8\r\n"), "{}", request);
+		assert!(request.contains("\r\nx-sparkboard-height: 2\r\n"), "{}", request);
+		assert!(request.contains("\r\nx-sparkboard-type: 7in5v2\r\n"), "{}", request);
+	}
+
+	#[test]
+	fn limits_the_body_size() {
+		let download_with_limit = |response: Vec<u8>| {
+			let (url, request) = serve_once(response);
+			let mut source = Source::new(&url);
+			source.max_body_size = Some(4);
+			let result = download(&source, &panel()).map(|(body, _)| body);
+			request.join().unwrap();
+			result
+		};
+		assert_eq!(download_with_limit(response("", b"abcd")).unwrap(), b"abcd");
+		// Without a length, the body is read until it is one byte too long
+		let error = download_with_limit(response("", b"abcde")).unwrap_err();
+		assert!(error.to_string().contains("exceeds the limit of 4 bytes"), "{}", error);
+		// With a length, it is not read at all
+		let error = download_with_limit(response("Content-Length: 5\r\n", b"abcde")).unwrap_err();
+		assert!(error.to_string().starts_with("response is 5 bytes"), "{}", error);
+	}
 }
@@ -0,0 +1,111 @@
+/*! GPIO through the Linux GPIO character device (/dev/gpiochipN), for boards other than the Raspberry Pi */
+use crate::epd::{InputLine, OutputLine, Pins, EPD};
+use crate::spi;
+use gpio_cdev::{Chip, LineHandle, LineRequestFlags};
+use std::error::Error;
+use std::path::Path;
+
+impl OutputLine for LineHandle {
+	fn set_high(&mut self) -> Result<(), Box<dyn Error>> {
+		Ok(self.set_value(1)?)
+	}
+
+	fn set_low(&mut self) -> Result<(), Box<dyn Error>> {
+		Ok(self.set_value(0)?)
+	}
+}
+
+impl InputLine for LineHandle {
+	fn is_high(&mut self) -> Result<bool, Box<dyn Error>> {
+		Ok(self.get_value()? != 0)
+	}
+}
+
+/** Open a display connected to the given SPI device, with its pins given as line offsets on a GPIO chip. The lines are
+requested with `consumer` as label, which shows up in tools like gpioinfo. */
+pub fn open(spi_path: &str, chip: &Path, consumer: &str, pins: &Pins) -> Result<EPD, Box<dyn Error>> {
+	let mut chip = open_chip(chip)?;
+	let cs = match pins.cs {
+		Some(cs) => Some(output(&mut chip, cs, consumer)?),
+		None => None,
+	};
+	let dc = output(&mut chip, pins.dc, consumer)?;
+	let rst = output(&mut chip, pins.rst, consumer)?;
+	let busy = input(&mut chip, pins.busy, consumer)?;
+
+	spi::connect(spi_path, busy, cs, dc, rst)
+}
+
+pub fn open_chip(path: &Path) -> Result<Chip, Box<dyn Error>> {
+	let chip = Chip::new(path).map_err(|e| format!("could not open GPIO chip {:?}: {}", path, e))?;
+	log::info!("Using GPIO chip {:?} ({})", chip.path(), chip.label());
+	Ok(chip)
+}
+
+/** Request a line as output, labelled with `consumer` */
+pub fn output(chip: &mut Chip, offset: u32, consumer: &str) -> Result<Box<dyn OutputLine>, Box<dyn Error>> {
+	Ok(Box::new(request(chip, offset, LineRequestFlags::OUTPUT, consumer)?))
+}
+
+/** Request a line as input, labelled with `consumer` */
+pub fn input(chip: &mut Chip, offset: u32, consumer: &str) -> Result<Box<dyn InputLine>, Box<dyn Error>> {
+	Ok(Box::new(request(chip, offset, LineRequestFlags::INPUT, consumer)?))
+}
+
+fn request(
+	chip: &mut Chip,
+	offset: u32,
+	flags: LineRequestFlags,
+	consumer: &str,
+) -> Result<LineHandle, Box<dyn Error>> {
+	Ok(chip
+		.get_line(offset)?
+		.request(flags, 0, consumer)
+		.map_err(|e| format!("could not request line {}: {}", offset, e))?)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use gpio_cdev::LineDirection;
+
+	#[test]
+	fn names_chips_it_cannot_open() {
+		let error = open_chip(Path::new("/dev/gpiochip-missing")).unwrap_err();
+		assert!(error
+			.to_string()
+			.starts_with("could not open GPIO chip \"/dev/gpiochip-missing\""));
+	}
+
+	// Needs a chip with at least four lines that nothing uses, such as one simulated by gpio-sim (as root):
+	//
+	// modprobe gpio-sim
+	// mkdir -p /sys/kernel/config/gpio-sim/sparkboard/bank0
+	// echo 4 > /sys/kernel/config/gpio-sim/sparkboard/bank0/num_lines
+	// echo 1 > /sys/kernel/config/gpio-sim/sparkboard/live
+	// SPARKBOARD_TEST_GPIOCHIP=/dev/$(cat /sys/kernel/config/gpio-sim/sparkboard/bank0/chip_name) \
+	//     cargo test --features linux-gpiod -- --ignored gpiod
+	#[test]
+	#[ignore]
+	fn requests_lines_with_their_consumer() {
+		let path = std::env::var("SPARKBOARD_TEST_GPIOCHIP").expect("SPARKBOARD_TEST_GPIOCHIP should name a chip");
+		let mut chip = open_chip(Path::new(&path)).unwrap();
+		let mut dc = output(&mut chip, 1, "sparkboard-test").unwrap();
+		let mut busy = input(&mut chip, 3, "sparkboard-test").unwrap();
+		dc.set_high().unwrap();
+		dc.set_low().unwrap();
+		// Simulated lines are pulled down
+		assert!(!busy.is_high().unwrap());
+
+		for (offset, direction) in &[(1, LineDirection::Out), (3, LineDirection::In)] {
+			let info = chip.get_line(*offset).unwrap().info().unwrap();
+			assert_eq!(info.consumer(), Some("sparkboard-test"));
+			assert_eq!(info.direction(), *direction);
+		}
+		assert!(!chip.get_line(2).unwrap().info().unwrap().is_used());
+
+		// A line in use (such as a pin configured twice) cannot be requested again
+		let error = output(&mut chip, 1, "sparkboard-test").err().unwrap();
+		assert!(error.to_string().starts_with("could not request line 1"), "{}", error);
+	}
+}
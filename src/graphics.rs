@@ -1,6 +1,81 @@
 use embedded_graphics::pixelcolor::BinaryColor;
 use embedded_graphics::prelude::*;
+use std::error::Error;
 
+/** A packed image as sent to the display: one bit per pixel (1 = black), rows top to bottom, leftmost pixel in the most
+significant bit. Displays that support a third color take a second plane in which 1 means 'colored'. */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bitmap {
+	pub width: u32,
+	pub height: u32,
+	pub black: Vec<u8>,
+	pub color: Option<Vec<u8>>,
+}
+
+impl Bitmap {
+	/** Encode as an RGB PNG the way the display would show it: black, white, and red where the color plane is set */
+	pub fn to_png(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+		let row_bytes = (self.width / 8) as usize;
+		let mut pixels = Vec::with_capacity((self.width * self.height) as usize * 3);
+		for y in 0..self.height as usize {
+			for x in 0..self.width as usize {
+				let index = y * row_bytes + x / 8;
+				let bit = 0x80 >> (x % 8);
+				let colored = self.color.as_ref().map(|c| c[index] & bit != 0).unwrap_or(false);
+				let rgb = if colored {
+					[0xFF, 0x00, 0x00]
+				} else if self.black[index] & bit != 0 {
+					[0x00, 0x00, 0x00]
+				} else {
+					[0xFF, 0xFF, 0xFF]
+				};
+				pixels.extend_from_slice(&rgb);
+			}
+		}
+
+		let mut png = vec![];
+		let mut encoder = png::Encoder::new(&mut png, self.width, self.height);
+		encoder.set_color(png::ColorType::RGB);
+		encoder.set_depth(png::BitDepth::Eight);
+		encoder.write_header()?.write_image_data(&pixels)?;
+		Ok(png)
+	}
+
+	/** Decode a PNG written by `to_png`. Red pixels go to the color plane, which is only there when `color` is set. */
+	pub fn from_png(data: &[u8], color: bool) -> Result<Bitmap, Box<dyn Error>> {
+		let decoder = png::Decoder::new(data);
+		let (info, mut reader) = decoder.read_info()?;
+		if info.color_type != png::ColorType::RGB || info.bit_depth != png::BitDepth::Eight || info.width % 8 != 0 {
+			return Err("not a PNG written by Sparkboard".into());
+		}
+		let mut pixels = vec![0; info.buffer_size()];
+		reader.next_frame(&mut pixels)?;
+
+		let size = (info.width * info.height / 8) as usize;
+		let mut bitmap = Bitmap {
+			width: info.width,
+			height: info.height,
+			black: vec![0; size],
+			color: if color { Some(vec![0; size]) } else { None },
+		};
+		for (pixel, rgb) in pixels.chunks(3).enumerate() {
+			let bit = 0x80 >> (pixel % 8);
+			match rgb {
+				[0x00, 0x00, 0x00] => bitmap.black[pixel / 8] |= bit,
+				[0xFF, 0x00, 0x00] => {
+					if let Some(color) = &mut bitmap.color {
+						color[pixel / 8] |= bit;
+					}
+				}
+				_ => {}
+			}
+		}
+		Ok(bitmap)
+	}
+}
+
+/** Packed black and white image that can be drawn on with embedded-graphics. `BinaryColor::On` draws white and
+`BinaryColor::Off` draws black. */
 pub struct Surface {
 	buffer: Vec<u8>,
 	width: u32,
@@ -16,20 +91,32 @@ impl Surface {
 		}
 	}
 
-	fn find_position(&self, x: i32, y: i32) -> (usize, u8) {
-		if x < 0 || y < 0 {
-			return (0, 0);
+	/** Wrap an existing packed bitmap (such as one returned by `fetch`) so it can be drawn on */
+	pub fn from_buffer(width: u32, height: u32, buffer: Vec<u8>) -> Surface {
+		if buffer.len() != (width * height) as usize / 8 {
+			panic!("invalid buffer size");
+		}
+		Surface { buffer, width, height }
+	}
+
+	fn find_position(&self, x: i32, y: i32) -> Option<(usize, u8)> {
+		if x < 0 || y < 0 || x as u32 >= self.width || y as u32 >= self.height {
+			return None;
 		}
 
-		(
+		Some((
 			((x as u32) / 8 + (self.width / 8) * (y as u32)) as usize,
 			0x80 >> ((x as u32) % 8),
-		)
+		))
 	}
 
 	pub fn buffer(&self) -> &[u8] {
 		&self.buffer
 	}
+
+	pub fn into_buffer(self) -> Vec<u8> {
+		self.buffer
+	}
 }
 
 impl DrawTarget<BinaryColor> for Surface {
@@ -37,12 +124,16 @@ impl DrawTarget<BinaryColor> for Surface {
 
 	fn draw_pixel(&mut self, pixel: Pixel<BinaryColor>) -> Result<(), Self::Error> {
 		let Pixel(point, color) = pixel;
-		let (index, bit) = self.find_position(point.x, point.y);
+		let (index, bit) = match self.find_position(point.x, point.y) {
+			Some(position) => position,
+			None => return Ok(()), // Clip pixels outside of the surface
+		};
 		match color {
+			// White
 			BinaryColor::On => {
 				self.buffer[index] &= !bit;
 			}
-			// White
+			// Black
 			BinaryColor::Off => {
 				self.buffer[index] |= bit;
 			}
@@ -54,3 +145,35 @@ impl DrawTarget<BinaryColor> for Surface {
 		Size::new(self.width, self.height)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn png_round_trip() {
+		let bitmap = Bitmap {
+			width: 16,
+			height: 2,
+			black: vec![0x80, 0x01, 0x00, 0xFF],
+			color: Some(vec![0x40, 0x00, 0x10, 0x00]),
+		};
+		assert_eq!(Bitmap::from_png(&bitmap.to_png().unwrap(), true).unwrap(), bitmap);
+		let black = Bitmap::from_png(&bitmap.to_png().unwrap(), false).unwrap();
+		assert_eq!(black.black, bitmap.black);
+		assert_eq!(black.color, None);
+	}
+
+	#[test]
+	fn png_shows_color_over_black() {
+		let bitmap = Bitmap {
+			width: 8,
+			height: 1,
+			black: vec![0xC0],
+			color: Some(vec![0x60]),
+		};
+		let shown = Bitmap::from_png(&bitmap.to_png().unwrap(), true).unwrap();
+		assert_eq!(shown.color, Some(vec![0x60]));
+		assert_eq!(shown.black, vec![0x80]);
+	}
+}
@@ -0,0 +1,224 @@
+/*! Fixed-point Stucki error diffusion that works on 8-bit grayscale pixels and writes packed 1-bit rows directly.
+
+This produces the same output as running the `dither` crate's STUCKI ditherer with a 1-bit quantizer on `RGB<f64>`
+pixels, including its quirks: error that falls off the right edge of a row spills into the start of the next row
+(the image is treated as one long row), and values far outside the 0..255 range carry over the distance to the
+nearest multiple of 255 rather than to the clamped output value. */
+
+/** Fractional bits of the fixed-point pixel values. The error of a pixel is divided by the diffusion divisor once
+(rounding to the nearest unit), after which all arithmetic is exact; with this many bits the result only differs from
+a floating point implementation for values within a few billionths of the threshold. */
+const FRACTION_BITS: u32 = 32;
+const ONE: i64 = 1 << FRACTION_BITS;
+const STEP: i64 = 255 * ONE;
+
+/** Stucki diffusion matrix: (dx, dy, weight); weights add up to 42 */
+const STUCKI: [(isize, isize, i64); 12] = [
+	(1, 0, 8),
+	(2, 0, 4),
+	(-2, 1, 2),
+	(-1, 1, 4),
+	(0, 1, 8),
+	(1, 1, 4),
+	(2, 1, 2),
+	(-2, 2, 1),
+	(-1, 2, 2),
+	(0, 2, 4),
+	(1, 2, 2),
+	(2, 2, 1),
+];
+const STUCKI_DIVISOR: i64 = 42;
+
+/** Convert decoded pixels with `samples` channels per pixel (gray, gray+alpha, RGB or RGBA) to grayscale. For color
+pixels the darkest channel is used, so colored text and lines come out black. */
+pub fn grayscale(pixels: &[u8], samples: usize) -> Vec<u8> {
+	match samples {
+		1 => pixels.to_vec(),
+		2 => pixels.chunks_exact(2).map(|p| p[0]).collect(),
+		_ => pixels.chunks_exact(samples).map(|p| p[0].min(p[1]).min(p[2])).collect(),
+	}
+}
+
+/** Dither a grayscale image to black and white and pack it, eight pixels per byte with the leftmost pixel in the most
+significant bit and a set bit meaning black. Rows are padded to a whole number of bytes. */
+pub fn dither_stucki(gray: &[u8], width: usize, height: usize) -> Vec<u8> {
+	assert_eq!(gray.len(), width * height, "invalid grayscale buffer size");
+	let row_bytes = width.div_ceil(8);
+	let mut packed = vec![0u8; row_bytes * height];
+	if width == 0 {
+		return packed;
+	}
+
+	// Error spills at most two rows and two pixels ahead, so a ring buffer of that size is enough
+	let ring = 2 * width + 3;
+	let mut errors = vec![0i64; ring];
+	let mut offsets = [(0usize, 0i64); STUCKI.len()];
+	let mut n_offsets = 0;
+	for (dx, dy, weight) in STUCKI.iter() {
+		let delta = dy * width as isize + dx;
+		// Spills to earlier pixels are impossible for images wider than two pixels; the reference drops them too
+		if delta > 0 {
+			offsets[n_offsets] = (delta as usize, *weight);
+			n_offsets += 1;
+		}
+	}
+	let offsets = &offsets[0..n_offsets];
+	let len = width * height;
+	let mut slot = 0;
+
+	for y in 0..height {
+		let row = &gray[y * width..(y + 1) * width];
+		let out = &mut packed[y * row_bytes..(y + 1) * row_bytes];
+		let mut byte = 0u8;
+		for (x, &pixel) in row.iter().enumerate() {
+			let i = y * width + x;
+			let value = pixel as i64 * ONE + errors[slot];
+			errors[slot] = 0;
+
+			let (white, spill) = quantize(value);
+			byte <<= 1;
+			if !white {
+				byte |= 1;
+			}
+			if x % 8 == 7 {
+				out[x / 8] = byte;
+				byte = 0;
+			}
+
+			if spill != 0 {
+				let share = div_round(spill, STUCKI_DIVISOR);
+				for &(delta, weight) in offsets {
+					if i + delta < len {
+						// slot + delta stays below twice the ring size, so one subtraction wraps it
+						let mut target = slot + delta;
+						if target >= ring {
+							target -= ring;
+						}
+						errors[target] += share * weight;
+					}
+				}
+			}
+
+			slot += 1;
+			if slot == ring {
+				slot = 0;
+			}
+		}
+		if !width.is_multiple_of(8) {
+			out[row_bytes - 1] = byte << (8 - width % 8);
+		}
+	}
+
+	packed
+}
+
+/** Round to the nearest multiple of 255 (ties go up). Returns whether the result is white, and the error to spill. */
+#[inline]
+fn quantize(value: i64) -> (bool, i64) {
+	let floor = value.div_euclid(STEP) * STEP;
+	let floor_rem = value - floor;
+	let ceil = if floor_rem == 0 { floor } else { floor + STEP };
+	let ceil_rem = ceil - value;
+	if floor_rem < ceil_rem {
+		(floor > 0, floor_rem)
+	} else {
+		(ceil > 0, -ceil_rem)
+	}
+}
+
+#[inline]
+fn div_round(value: i64, divisor: i64) -> i64 {
+	if value >= 0 {
+		(value + divisor / 2) / divisor
+	} else {
+		(value - divisor / 2) / divisor
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use dither::prelude::*;
+
+	/** The previous pipeline, using the generic ditherer from the `dither` crate */
+	fn reference(gray: &[u8], width: usize, height: usize) -> Vec<u8> {
+		let source: Vec<RGB<f64>> = gray.iter().map(|g| RGB(*g, *g, *g).convert_with(f64::from)).collect();
+		let quantize = dither::create_quantize_n_bits_func(1).unwrap();
+		let dest = dither::ditherer::STUCKI
+			.dither(Img::new(source, width as u32).unwrap(), RGB::map_across(quantize))
+			.convert_with(|rgb| rgb.convert_with(clamp_f64_to_u8));
+
+		let mut buffer = vec![0u8; width / 8 * height];
+		for y in 0..height {
+			for x in 0..(width / 8) {
+				let mut byte: u8 = 0;
+				for k in 0..8 {
+					let pixel = dest[((x * 8 + k) as u32, y as u32)];
+					byte <<= 1;
+					if !(pixel.0 > 0 || pixel.1 > 0 || pixel.2 > 0) {
+						byte |= 1;
+					}
+				}
+				buffer[y * (width / 8) + x] = byte;
+			}
+		}
+		buffer
+	}
+
+	fn assert_same_as_reference(gray: &[u8], width: usize, height: usize) {
+		let expected = reference(gray, width, height);
+		let actual = dither_stucki(gray, width, height);
+		let differing = expected.iter().zip(actual.iter()).filter(|(a, b)| a != b).count();
+		assert_eq!(differing, 0, "{} of {} bytes differ", differing, expected.len());
+	}
+
+	/** Deterministic noise, so the tests do not depend on a random number generator */
+	fn noise(width: usize, height: usize, seed: u32) -> Vec<u8> {
+		let mut state = seed;
+		(0..width * height)
+			.map(|_| {
+				state ^= state << 13;
+				state ^= state >> 17;
+				state ^= state << 5;
+				(state >> 24) as u8
+			})
+			.collect()
+	}
+
+	fn gradient(width: usize, height: usize) -> Vec<u8> {
+		(0..height)
+			.flat_map(|y| (0..width).map(move |x| ((x * 255 / width + y * 255 / height) / 2) as u8))
+			.collect()
+	}
+
+	#[test]
+	fn stucki_matches_reference_on_sample_image() {
+		let decoder = png::Decoder::new(&include_bytes!("../in.png")[..]);
+		let (info, mut reader) = decoder.read_info().unwrap();
+		let mut pixels = vec![0; info.buffer_size()];
+		reader.next_frame(&mut pixels).unwrap();
+		let gray = grayscale(&pixels, reader.output_color_type().0.samples());
+		assert_same_as_reference(&gray, info.width as usize, info.height as usize);
+	}
+
+	#[test]
+	fn stucki_matches_reference_on_panel_sizes() {
+		for (width, height) in [(800, 480), (640, 384)].iter() {
+			assert_same_as_reference(&gradient(*width, *height), *width, *height);
+			assert_same_as_reference(&noise(*width, *height, 0x5eed), *width, *height);
+		}
+	}
+
+	#[test]
+	fn stucki_matches_reference_on_flat_grays() {
+		for level in [0u8, 1, 64, 127, 128, 129, 200, 254, 255].iter() {
+			assert_same_as_reference(&vec![*level; 64 * 48], 64, 48);
+		}
+	}
+
+	#[test]
+	fn pads_rows_to_whole_bytes() {
+		let packed = dither_stucki(&[0u8; 10 * 2], 10, 2);
+		assert_eq!(packed, vec![0xFF, 0xC0, 0xFF, 0xC0]);
+	}
+}
@@ -0,0 +1,497 @@
+/*! Opening the displays described by a configuration from one process.
+
+Several panels can share GPIO lines (typically RST, sometimes DC or BUSY) and SPI devices. Each line is claimed only
+once and handed to every display that uses it, and displays that share a line or SPI device are put in the same lock
+group, so that one is never reset or selected while another is being refreshed. */
+use crate::config::{Config, DisplayConfig};
+use crate::epd::{InputLine, OutputLine, Pins, EPD};
+use crate::epd7in5_v2::{EPD7IN5V2_HEIGHT, EPD7IN5V2_WIDTH};
+use crate::epd7in5bc::{EPD7IN5BC_HEIGHT, EPD7IN5BC_WIDTH};
+use crate::{cache, status};
+use crate::{Bitmap, EPD7in5bc, EPD7in5v2, EPDDisplay};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
+
+/** How GPIO lines are accessed */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GpioBackend {
+	/** Raspberry Pi GPIO through rppal; pins are BCM numbers */
+	Rpi,
+	/** Linux GPIO character device; pins are line offsets on a chip */
+	Gpiod,
+}
+
+/** The SPI device and GPIO lines a panel is connected to */
+#[derive(Debug, Clone)]
+pub struct Connection {
+	pub spi: String,
+	pub gpio: GpioBackend,
+	/** GPIO chip the pins are on (gpiod only) */
+	pub gpio_chip: PathBuf,
+	/** Label to request the GPIO lines with (gpiod only) */
+	pub gpio_consumer: String,
+	pub pins: Pins,
+}
+
+/** Width and height of a type of panel, or None for an unknown type */
+pub fn panel_size(kind: &str) -> Option<(usize, usize)> {
+	match kind {
+		"7in5bc" => Some((EPD7IN5BC_WIDTH, EPD7IN5BC_HEIGHT)),
+		"7in5v2" => Some((EPD7IN5V2_WIDTH, EPD7IN5V2_HEIGHT)),
+		_ => None,
+	}
+}
+
+/** Identifies a GPIO line across displays */
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum LineKey {
+	Rpi(u32),
+	Gpiod(PathBuf, u32),
+}
+
+impl LineKey {
+	fn new(connection: &Connection, pin: u32) -> LineKey {
+		match connection.gpio {
+			GpioBackend::Rpi => LineKey::Rpi(pin),
+			GpioBackend::Gpiod => LineKey::Gpiod(connection.gpio_chip.clone(), pin),
+		}
+	}
+}
+
+/** Output line that may be used by several displays */
+struct SharedOutput(Arc<Mutex<Box<dyn OutputLine>>>);
+
+impl OutputLine for SharedOutput {
+	fn set_high(&mut self) -> Result<(), Box<dyn Error>> {
+		self.0.lock().unwrap_or_else(PoisonError::into_inner).set_high()
+	}
+
+	fn set_low(&mut self) -> Result<(), Box<dyn Error>> {
+		self.0.lock().unwrap_or_else(PoisonError::into_inner).set_low()
+	}
+}
+
+/** Input line that may be used by several displays */
+struct SharedInput(Arc<Mutex<Box<dyn InputLine>>>);
+
+impl InputLine for SharedInput {
+	fn is_high(&mut self) -> Result<bool, Box<dyn Error>> {
+		self.0.lock().unwrap_or_else(PoisonError::into_inner).is_high()
+	}
+}
+
+/** An opened display, and the lock to hold while using it. A panel that is still awake when this is dropped (because
+a draw failed halfway, for instance) is powered off and put in deep sleep, as leaving it powered may damage it. What is
+drawn on it is passed on to `status` and `cache`. */
+pub struct Attached {
+	/** Name of the display in the configuration */
+	pub name: String,
+	pub display: Box<dyn EPDDisplay>,
+	/** Shared by all displays that have a GPIO line or SPI device in common */
+	pub lock: Arc<Mutex<()>>,
+	/** Whether the panel was woken up (through `locked`) and not put back to sleep */
+	awake: bool,
+}
+
+impl Attached {
+	pub fn new(name: &str, display: Box<dyn EPDDisplay>, lock: Arc<Mutex<()>>) -> Attached {
+		Attached {
+			name: name.to_string(),
+			display,
+			lock,
+			awake: false,
+		}
+	}
+
+	/** Use the display while holding its lock, so that no display sharing pins with it is used at the same time */
+	pub fn locked<T>(&mut self, f: impl FnOnce(&mut dyn EPDDisplay) -> T) -> T {
+		let _guard = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+		f(&mut Tracked {
+			name: &self.name,
+			display: self.display.as_mut(),
+			awake: &mut self.awake,
+		})
+	}
+
+	/** Put the panel to sleep if it is still awake */
+	pub fn shut_down(&mut self) -> Result<(), Box<dyn Error>> {
+		if self.awake {
+			log::warn!("panel was left awake; powering it off");
+			self.locked(|display| display.sleep())?;
+		}
+		Ok(())
+	}
+}
+
+impl Drop for Attached {
+	fn drop(&mut self) {
+		if let Err(e) = self.shut_down() {
+			log::error!("could not put panel to sleep: {}", e);
+		}
+	}
+}
+
+/** Passes calls on to a display, keeping track of whether it is awake and what it was sent */
+struct Tracked<'a> {
+	name: &'a str,
+	display: &'a mut dyn EPDDisplay,
+	awake: &'a mut bool,
+}
+
+impl Tracked<'_> {
+	fn drawn(&self, black: &[u8], color: Option<&[u8]>) {
+		let bitmap = Bitmap {
+			width: self.display.width() as u32,
+			height: self.display.height() as u32,
+			black: black.to_vec(),
+			color: color.map(|c| c.to_vec()),
+		};
+		cache::drawn(self.name, &bitmap);
+		status::drawn(self.name, bitmap);
+	}
+}
+
+impl EPDDisplay for Tracked<'_> {
+	fn init(&mut self) -> Result<(), Box<dyn Error>> {
+		*self.awake = true;
+		cache::drawing(self.name);
+		self.display.init()
+	}
+
+	fn sleep(&mut self) -> Result<(), Box<dyn Error>> {
+		self.display.sleep()?;
+		*self.awake = false;
+		Ok(())
+	}
+
+	fn draw(&mut self, buffer: &[u8]) -> Result<(), Box<dyn Error>> {
+		self.display.draw(buffer)?;
+		self.drawn(buffer, None);
+		Ok(())
+	}
+
+	fn draw_bichromatic(&mut self, black_buffer: &[u8], color_buffer: &[u8]) -> Result<(), Box<dyn Error>> {
+		self.display.draw_bichromatic(black_buffer, color_buffer)?;
+		self.drawn(black_buffer, Some(color_buffer));
+		Ok(())
+	}
+
+	fn width(&self) -> usize {
+		self.display.width()
+	}
+
+	fn height(&self) -> usize {
+		self.display.height()
+	}
+}
+
+/** Keeps track of the GPIO lines claimed so far, so that displays can share them */
+#[derive(Default)]
+pub struct Hardware {
+	#[cfg(feature = "rpi")]
+	gpio: Option<rppal::gpio::Gpio>,
+	#[cfg(feature = "linux-gpiod")]
+	chips: HashMap<PathBuf, gpio_cdev::Chip>,
+	outputs: HashMap<LineKey, Arc<Mutex<Box<dyn OutputLine>>>>,
+	inputs: HashMap<LineKey, Arc<Mutex<Box<dyn InputLine>>>>,
+}
+
+impl Hardware {
+	pub fn new() -> Hardware {
+		Hardware::default()
+	}
+
+	/** Open every display in the configuration, in order. Walls have no hardware of their own and yield None. */
+	pub fn open_all(&mut self, config: &Config) -> Result<Vec<Option<Attached>>, Box<dyn Error>> {
+		let locks = lock_groups(&config.displays);
+		config
+			.displays
+			.iter()
+			.zip(locks)
+			.enumerate()
+			.map(|(index, (display, lock))| {
+				if display.is_wall() {
+					return Ok(None);
+				}
+				let name = config.display_name(index);
+				let display = self.open(display).map_err(|e| format!("display {}: {}", name, e))?;
+				Ok(Some(Attached::new(&name, display, lock)))
+			})
+			.collect()
+	}
+
+	/** Open a display (or its simulation) */
+	pub fn open(&mut self, config: &DisplayConfig) -> Result<Box<dyn EPDDisplay>, Box<dyn Error>> {
+		let (width, height) =
+			panel_size(&config.kind).ok_or_else(|| format!("invalid device type: {:?}", config.kind))?;
+		if let Some(path) = &config.simulate {
+			return simulated(width, height, path.clone());
+		}
+
+		let epd = self.open_epd(&config.connection())?;
+		Ok(match config.kind.as_str() {
+			"7in5bc" => Box::new(EPD7in5bc::new(epd)),
+			_ => Box::new(EPD7in5v2::new(epd)),
+		})
+	}
+
+	/** Open the SPI device and claim the GPIO lines (unless already claimed for another display) */
+	pub fn open_epd(&mut self, connection: &Connection) -> Result<EPD, Box<dyn Error>> {
+		let pins = &connection.pins;
+		let cs = match pins.cs {
+			Some(cs) => Some(self.output(connection, cs)?),
+			None => None,
+		};
+		let busy = self.input(connection, pins.busy)?;
+		let dc = self.output(connection, pins.dc)?;
+		let rst = self.output(connection, pins.rst)?;
+		connect(&connection.spi, busy, cs, dc, rst)
+	}
+
+	fn output(&mut self, connection: &Connection, pin: u32) -> Result<Box<dyn OutputLine>, Box<dyn Error>> {
+		let key = LineKey::new(connection, pin);
+		if self.inputs.contains_key(&key) {
+			return Err(format!("pin {} is used both as input and as output", pin).into());
+		}
+		let line = match self.outputs.get(&key) {
+			Some(line) => line.clone(),
+			None => {
+				let line = Arc::new(Mutex::new(self.claim_output(connection, pin)?));
+				self.outputs.insert(key, line.clone());
+				line
+			}
+		};
+		Ok(Box::new(SharedOutput(line)))
+	}
+
+	fn input(&mut self, connection: &Connection, pin: u32) -> Result<Box<dyn InputLine>, Box<dyn Error>> {
+		let key = LineKey::new(connection, pin);
+		if self.outputs.contains_key(&key) {
+			return Err(format!("pin {} is used both as input and as output", pin).into());
+		}
+		let line = match self.inputs.get(&key) {
+			Some(line) => line.clone(),
+			None => {
+				let line = Arc::new(Mutex::new(self.claim_input(connection, pin)?));
+				self.inputs.insert(key, line.clone());
+				line
+			}
+		};
+		Ok(Box::new(SharedInput(line)))
+	}
+
+	#[cfg_attr(not(any(feature = "rpi", feature = "linux-gpiod")), allow(unused_variables))]
+	fn claim_output(&mut self, connection: &Connection, pin: u32) -> Result<Box<dyn OutputLine>, Box<dyn Error>> {
+		match connection.gpio {
+			#[cfg(feature = "rpi")]
+			GpioBackend::Rpi => crate::rpi::output(self.gpio()?, pin),
+			#[cfg(feature = "linux-gpiod")]
+			GpioBackend::Gpiod => crate::gpiod::output(self.chip(connection)?, pin, &connection.gpio_consumer),
+			#[allow(unreachable_patterns)]
+			backend => Err(unsupported(backend)),
+		}
+	}
+
+	#[cfg_attr(not(any(feature = "rpi", feature = "linux-gpiod")), allow(unused_variables))]
+	fn claim_input(&mut self, connection: &Connection, pin: u32) -> Result<Box<dyn InputLine>, Box<dyn Error>> {
+		match connection.gpio {
+			#[cfg(feature = "rpi")]
+			GpioBackend::Rpi => crate::rpi::input(self.gpio()?, pin),
+			#[cfg(feature = "linux-gpiod")]
+			GpioBackend::Gpiod => crate::gpiod::input(self.chip(connection)?, pin, &connection.gpio_consumer),
+			#[allow(unreachable_patterns)]
+			backend => Err(unsupported(backend)),
+		}
+	}
+
+	#[cfg(feature = "rpi")]
+	fn gpio(&mut self) -> Result<&rppal::gpio::Gpio, Box<dyn Error>> {
+		if self.gpio.is_none() {
+			self.gpio = Some(rppal::gpio::Gpio::new()?);
+		}
+		Ok(self.gpio.as_ref().unwrap())
+	}
+
+	#[cfg(feature = "linux-gpiod")]
+	fn chip(&mut self, connection: &Connection) -> Result<&mut gpio_cdev::Chip, Box<dyn Error>> {
+		let path = &connection.gpio_chip;
+		if !self.chips.contains_key(path) {
+			let chip = crate::gpiod::open_chip(path)?;
+			self.chips.insert(path.clone(), chip);
+		}
+		Ok(self.chips.get_mut(path).unwrap())
+	}
+}
+
+#[allow(dead_code)]
+fn unsupported(backend: GpioBackend) -> Box<dyn Error> {
+	match backend {
+		GpioBackend::Rpi => "built without Raspberry Pi support (feature 'rpi')".into(),
+		GpioBackend::Gpiod => "built without GPIO character device support (feature 'linux-gpiod')".into(),
+	}
+}
+
+#[cfg(any(feature = "rpi", feature = "linux-gpiod"))]
+fn connect(
+	spi: &str,
+	busy: Box<dyn InputLine>,
+	cs: Option<Box<dyn OutputLine>>,
+	dc: Box<dyn OutputLine>,
+	rst: Box<dyn OutputLine>,
+) -> Result<EPD, Box<dyn Error>> {
+	crate::spi::connect(spi, busy, cs, dc, rst)
+}
+
+#[cfg(not(any(feature = "rpi", feature = "linux-gpiod")))]
+fn connect(
+	_spi: &str,
+	_busy: Box<dyn InputLine>,
+	_cs: Option<Box<dyn OutputLine>>,
+	_dc: Box<dyn OutputLine>,
+	_rst: Box<dyn OutputLine>,
+) -> Result<EPD, Box<dyn Error>> {
+	Err("built without SPI support (feature 'rpi' or 'linux-gpiod')".into())
+}
+
+#[cfg(feature = "sim")]
+fn simulated(width: usize, height: usize, path: PathBuf) -> Result<Box<dyn EPDDisplay>, Box<dyn Error>> {
+	Ok(Box::new(crate::sim::SimDisplay::new(width, height, path)))
+}
+
+#[cfg(not(feature = "sim"))]
+fn simulated(_width: usize, _height: usize, _path: PathBuf) -> Result<Box<dyn EPDDisplay>, Box<dyn Error>> {
+	Err("built without display simulation (feature 'sim')".into())
+}
+
+/** One lock per display, where displays that share a GPIO line or SPI device (directly or through another display)
+get the same lock */
+fn lock_groups(displays: &[DisplayConfig]) -> Vec<Arc<Mutex<()>>> {
+	#[derive(PartialEq)]
+	enum Resource {
+		Spi(String),
+		Line(LineKey),
+	}
+
+	let resources: Vec<Vec<Resource>> = displays
+		.iter()
+		.map(|display| {
+			if !display.has_hardware() {
+				return vec![];
+			}
+			let connection = display.connection();
+			let pins = &connection.pins;
+			let mut resources = vec![Resource::Spi(connection.spi.clone())];
+			for pin in pins.cs.iter().chain(&[pins.busy, pins.dc, pins.rst]) {
+				resources.push(Resource::Line(LineKey::new(&connection, *pin)));
+			}
+			resources
+		})
+		.collect();
+
+	// Each display starts in its own group; merge groups whenever two displays share something
+	let mut group: Vec<usize> = (0..displays.len()).collect();
+	for i in 0..displays.len() {
+		for j in 0..i {
+			if resources[i].iter().any(|r| resources[j].contains(r)) {
+				let (from, to) = (group[i], group[j]);
+				for g in group.iter_mut() {
+					if *g == from {
+						*g = to;
+					}
+				}
+			}
+		}
+	}
+
+	let mut locks: HashMap<usize, Arc<Mutex<()>>> = HashMap::new();
+	group.iter().map(|g| locks.entry(*g).or_default().clone()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn displays_sharing_pins_share_a_lock() {
+		let config = Config::parse(
+			r#"
+			[[display]]
+			spi = "/dev/spidev0.0"
+			cs_pin = "kernel"
+			[[display.source]]
+			url = "x"
+
+			[[display]]
+			spi = "/dev/spidev1.0"
+			cs_pin = "kernel"
+			busy_pin = 5
+			dc_pin = 6
+			rst_pin = 13
+			[[display.source]]
+			url = "x"
+
+			[[display]]
+			spi = "/dev/spidev0.1"
+			cs_pin = "kernel"
+			busy_pin = 16
+			dc_pin = 20
+			rst_pin = 17
+			[[display.source]]
+			url = "x"
+			"#,
+		)
+		.unwrap();
+
+		let locks = lock_groups(&config.displays);
+		assert!(Arc::ptr_eq(&locks[0], &locks[2]));
+		assert!(!Arc::ptr_eq(&locks[0], &locks[1]));
+	}
+
+	/** Display that only records which calls were made */
+	struct Recorder(Arc<Mutex<Vec<&'static str>>>);
+
+	impl EPDDisplay for Recorder {
+		fn init(&mut self) -> Result<(), Box<dyn Error>> {
+			self.0.lock().unwrap().push("init");
+			Ok(())
+		}
+
+		fn sleep(&mut self) -> Result<(), Box<dyn Error>> {
+			self.0.lock().unwrap().push("sleep");
+			Ok(())
+		}
+
+		fn draw(&mut self, _buffer: &[u8]) -> Result<(), Box<dyn Error>> {
+			Err("SPI transfer failed".into())
+		}
+
+		fn width(&self) -> usize {
+			8
+		}
+
+		fn height(&self) -> usize {
+			1
+		}
+	}
+
+	#[test]
+	fn panels_left_awake_are_put_to_sleep() {
+		let calls = Arc::new(Mutex::new(vec![]));
+		let mut attached = Attached::new("test", Box::new(Recorder(calls.clone())), Arc::new(Mutex::new(())));
+		attached
+			.locked(|display| display.init().and_then(|_| display.sleep()))
+			.unwrap();
+		drop(attached);
+		assert_eq!(*calls.lock().unwrap(), vec!["init", "sleep"]);
+
+		let mut attached = Attached::new("test", Box::new(Recorder(calls.clone())), Arc::new(Mutex::new(())));
+		let drawn = attached.locked(|display| display.init().and_then(|_| display.draw(&[0])));
+		assert!(drawn.is_err());
+		drop(attached);
+		assert_eq!(*calls.lock().unwrap(), vec!["init", "sleep", "init", "sleep"]);
+	}
+}
@@ -0,0 +1,112 @@
+/*! Turning images into packed bitmaps for a panel. */
+use crate::graphics::Bitmap;
+use crate::halftone;
+use crate::metrics;
+use crate::raw;
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+use std::time::Instant;
+
+/** Describes the panel an image is fetched for. This is sent along with the request so servers can render to fit. */
+#[derive(Debug, Clone)]
+pub struct Panel {
+	pub width: usize,
+	pub height: usize,
+	pub kind: String,
+}
+
+/** Read an image from a local file (PNG, or the raw format) the same way `fetch` would have received it */
+pub fn load(path: &Path, panel: &Panel) -> Result<Bitmap, Box<dyn Error>> {
+	let data = std::fs::read(path)?;
+	let bitmap = if raw::is_raw(&data) {
+		raw::decode(&data, panel)?
+	} else {
+		decode_png(&data)?
+	};
+	check_size(&bitmap, panel)?;
+	Ok(bitmap)
+}
+
+/** An image that cannot be shown on a panel (see `fetch::error_kind`) */
+#[derive(Debug)]
+pub enum ImageError {
+	/** The data is not a valid image */
+	Decode(String),
+	/** The image is not the size of the panel */
+	Size {
+		width: usize,
+		height: usize,
+		panel_width: usize,
+		panel_height: usize,
+	},
+}
+
+impl ImageError {
+	pub(crate) fn size(width: usize, height: usize, panel: &Panel) -> ImageError {
+		ImageError::Size {
+			width,
+			height,
+			panel_width: panel.width,
+			panel_height: panel.height,
+		}
+	}
+}
+
+impl fmt::Display for ImageError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ImageError::Decode(message) => f.write_str(message),
+			ImageError::Size {
+				width,
+				height,
+				panel_width,
+				panel_height,
+			} => write!(
+				f,
+				"image is {}x{}, but the display is {}x{}",
+				width, height, panel_width, panel_height
+			),
+		}
+	}
+}
+
+impl Error for ImageError {}
+
+pub(crate) fn check_size(bitmap: &Bitmap, panel: &Panel) -> Result<(), Box<dyn Error>> {
+	if bitmap.width as usize != panel.width || bitmap.height as usize != panel.height {
+		return Err(ImageError::size(bitmap.width as usize, bitmap.height as usize, panel).into());
+	}
+	Ok(())
+}
+
+/** Decode a PNG image and dither it to a black and white (packed) bitmap */
+pub fn decode_png(png: &[u8]) -> Result<Bitmap, Box<dyn Error>> {
+	// Read PNG
+	let started = Instant::now();
+	let decoder = png::Decoder::new(png);
+	let (info, mut reader) = decoder.read_info()?;
+	log::info!("image size {:?}x{:?}", info.width, info.height);
+
+	// Allocate the output buffer. The decoder expands palettes and strips 16-bit samples, so pixels are 8-bit gray
+	// or RGB (with or without alpha)
+	let mut buf = vec![0; reader.output_buffer_size()];
+	// Read the next frame. An APNG might contain multiple frames.
+	reader.next_frame(&mut buf)?;
+
+	let (width, height) = (info.width as usize, info.height as usize);
+	let samples = reader.output_color_type().0.samples();
+	let gray = halftone::grayscale(&buf[0..width * height * samples], samples);
+	metrics::decoded(started.elapsed());
+
+	let started = Instant::now();
+	let black = halftone::dither_stucki(&gray, width, height);
+	metrics::dithered(started.elapsed());
+
+	Ok(Bitmap {
+		width: (width.div_ceil(8) * 8) as u32,
+		height: info.height,
+		black,
+		color: None,
+	})
+}
@@ -0,0 +1,62 @@
+/*! Drivers for Waveshare 7.5" e-paper displays, and the pipeline that turns a fetched image into what the display shows.
+
+A typical program fetches an image from a `fetch::Source` (with the `http` feature), which yields a packed [`Bitmap`]
+(PNG images are dithered with [`halftone`], images in the [`raw`] format are used as-is), optionally draws on it
+through a [`Surface`], and hands the planes to an [`EPDDisplay`] such as [`EPD7in5v2`] or [`EPD7in5bc`]. Those talk to
+the panel through an [`EPD`], which can be opened on a Raspberry Pi with `rpi::open` (with the `rpi` feature), on other
+Linux boards with `gpiod::open` (with the `linux-gpiod` feature), or built from any SPI device and GPIO lines that
+implement [`epd::OutputLine`] and [`epd::InputLine`].
+
+Programs that drive several panels describe them in a [`config::Config`], open them all with
+[`hardware::Hardware`] (which lets panels share GPIO lines) and run a `runner::Runner` for each, or leave all of that
+to `runtime::run`. */
+pub mod archive;
+pub mod backoff;
+pub mod cache;
+pub mod config;
+#[cfg(target_os = "linux")]
+pub mod dbus;
+pub mod epd;
+pub mod epd7in5_v2;
+pub mod epd7in5bc;
+pub mod events;
+#[cfg(feature = "http")]
+pub mod fetch;
+#[cfg(feature = "linux-gpiod")]
+pub mod gpiod;
+pub mod graphics;
+pub mod halftone;
+pub mod hardware;
+pub mod image;
+pub mod metrics;
+pub mod network;
+pub mod overlay;
+pub mod qr;
+pub mod raw;
+#[cfg(feature = "http")]
+pub mod refresh;
+#[cfg(feature = "rpi")]
+pub mod rpi;
+#[cfg(feature = "http")]
+pub mod runner;
+#[cfg(feature = "http")]
+pub mod runtime;
+pub mod schedule;
+pub mod screen;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod service;
+#[cfg(feature = "sim")]
+pub mod sim;
+#[cfg(any(feature = "rpi", feature = "linux-gpiod"))]
+pub mod spi;
+pub mod status;
+pub mod system;
+#[cfg(feature = "http")]
+pub mod tls;
+pub mod wall;
+
+pub use epd::{EPDDisplay, EPD};
+pub use epd7in5_v2::EPD7in5v2;
+pub use epd7in5bc::EPD7in5bc;
+pub use graphics::{Bitmap, Surface};
@@ -1,27 +1,32 @@
-use rppal::gpio::Gpio;
-use spidev::{SpiModeFlags, Spidev, SpidevOptions};
 use std::error::Error;
-use std::process::Command;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::thread::sleep;
 use std::time::Duration;
 
-mod epd;
-mod epd7in5_v2;
-mod epd7in5bc;
-mod fetch;
-mod graphics;
-
 use chrono::prelude::*;
-use clap::{App, Arg, SubCommand};
-use epd::EPDDisplay;
-use epd7in5_v2::EPD7in5v2;
-use epd7in5bc::EPD7in5bc;
-use fetch::fetch;
-use graphics::Surface;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use sparkboard::archive::{self, ArchiveConfig};
+use sparkboard::config::{Config, DisplayConfig, SourceConfig};
+use sparkboard::events;
+#[cfg(feature = "http")]
+use sparkboard::fetch::fetch;
+use sparkboard::hardware::{self, GpioBackend, Hardware};
+use sparkboard::image::{self, Panel};
+use sparkboard::network::WifiCheck;
+use sparkboard::overlay::{self, Corner};
+use sparkboard::qr::Qr;
+use sparkboard::raw;
+#[cfg(feature = "http")]
+use sparkboard::runtime::run;
+use sparkboard::screen;
+use sparkboard::service::Service;
+use sparkboard::status;
+use sparkboard::{Bitmap, EPD7in5bc, EPD7in5v2, EPDDisplay, Surface};
 
 use embedded_graphics::{
-	fonts::{Font12x16, Font6x12, Text},
+	fonts::{Font12x16, Text},
 	pixelcolor::BinaryColor,
 	prelude::*,
 	primitives::Circle,
@@ -30,8 +35,6 @@ use embedded_graphics::{
 };
 
 fn main() -> Result<(), Box<dyn Error>> {
-	env_logger::init();
-
 	let matches = App::new("SparkBoard")
 		.version("1.0")
 		.author("Tommy van der Vorst")
@@ -56,7 +59,163 @@ fn main() -> Result<(), Box<dyn Error>> {
 				.default_value("7in5v2")
 				.takes_value(true),
 		)
+		.arg(
+			Arg::with_name("gpio")
+				.long("gpio")
+				.value_name("BACKEND")
+				.possible_value("rpi")
+				.possible_value("gpiod")
+				.default_value("rpi")
+				.help("How to access the GPIO pins: Raspberry Pi GPIO, or the Linux GPIO character device (other boards)"),
+		)
+		.arg(
+			Arg::with_name("gpio-chip")
+				.long("gpio-chip")
+				.value_name("PATH")
+				.default_value("/dev/gpiochip0")
+				.help("GPIO chip the pins are on (gpiod only; pins are line offsets on this chip)"),
+		)
+		.arg(
+			Arg::with_name("gpio-consumer")
+				.long("gpio-consumer")
+				.value_name("LABEL")
+				.default_value("sparkboard")
+				.help("Label to request the GPIO lines with (gpiod only)"),
+		)
+		.arg(
+			Arg::with_name("busy-pin")
+				.long("busy-pin")
+				.value_name("PIN")
+				.help("Pin connected to BUSY (default: 24)"),
+		)
+		.arg(
+			Arg::with_name("cs-pin")
+				.long("cs-pin")
+				.value_name("PIN")
+				.help("Pin connected to CS (default: 8), or 'kernel' to let the SPI controller drive CE0/CE1 of the SPI device"),
+		)
+		.arg(
+			Arg::with_name("dc-pin")
+				.long("dc-pin")
+				.value_name("PIN")
+				.help("Pin connected to DC (default: 25)"),
+		)
+		.arg(
+			Arg::with_name("rst-pin")
+				.long("rst-pin")
+				.value_name("PIN")
+				.help("Pin connected to RST (default: 17)"),
+		)
+		.arg(
+			Arg::with_name("simulate")
+				.long("simulate")
+				.value_name("PATH")
+				.help("Instead of using a display, write what it would show to this PNG file"),
+		)
+		.arg(
+			Arg::with_name("log-format")
+				.long("log-format")
+				.value_name("FORMAT")
+				.possible_value("text")
+				.possible_value("json")
+				.default_value("text")
+				.help("Log as text, or as JSON lines with the fields of fetches, refreshes and failures"),
+		)
 		.subcommand(SubCommand::with_name("test").about("perform tests"))
+		.subcommand(
+			SubCommand::with_name("qr")
+				.about("Display a QR code")
+				.arg(
+					Arg::with_name("module-size")
+						.short("m")
+						.long("module-size")
+						.value_name("PIXELS")
+						.help("Size of a single QR module (defaults to the largest size that fits)")
+						.takes_value(true),
+				)
+				.arg(
+					Arg::with_name("x")
+						.short("x")
+						.value_name("PIXELS")
+						.help("Horizontal position of the code (defaults to centered)")
+						.takes_value(true),
+				)
+				.arg(
+					Arg::with_name("y")
+						.short("y")
+						.value_name("PIXELS")
+						.help("Vertical position of the code (defaults to centered)")
+						.takes_value(true),
+				)
+				.arg(
+					Arg::with_name("text")
+						.takes_value(true)
+						.required(true)
+						.help("Text to encode"),
+				),
+		)
+		.subcommand(
+			SubCommand::with_name("encode")
+				.about("Dither a PNG image and convert it to the pre-packed raw format")
+				.arg(
+					Arg::with_name("output")
+						.short("o")
+						.long("output")
+						.value_name("PATH")
+						.required(true)
+						.help("File to write the raw image to"),
+				)
+				.arg(
+					Arg::with_name("compress")
+						.short("z")
+						.long("compress")
+						.help("Compress the pixel data"),
+				)
+				.arg(
+					Arg::with_name("input")
+						.takes_value(true)
+						.required(true)
+						.help("PNG file to convert"),
+				),
+		)
+		.subcommand(
+			SubCommand::with_name("render")
+				.about("Fetch an image and write what the display would show to a file, without using the display")
+				.arg(
+					Arg::with_name("output")
+						.short("o")
+						.long("output")
+						.value_name("PATH")
+						.required(true)
+						.help("PNG file to write the rendered image to"),
+				)
+				.arg(
+					Arg::with_name("raw")
+						.long("raw")
+						.value_name("PATH")
+						.help("Also write the packed buffer that would be sent to the display in the raw format"),
+				)
+				.arg(
+					Arg::with_name("compress")
+						.short("z")
+						.long("compress")
+						.requires("raw")
+						.help("Compress the pixel data of the raw file"),
+				)
+				.arg(
+					Arg::with_name("wifi")
+						.long("wifi")
+						.value_name("INTERFACE")
+						.help("Wi-Fi interface to show the signal level for in the status badge"),
+				)
+				.args(&overlay_args())
+				.args(&source_args())
+				.arg(
+					Arg::with_name("source")
+						.required(true)
+						.help("URL to fetch, or path of a local PNG or raw image"),
+				),
+		)
 		.subcommand(
 			SubCommand::with_name("fetch")
 				.about("Periodically fetch a PNG image from a URL and display")
@@ -70,86 +229,319 @@ fn main() -> Result<(), Box<dyn Error>> {
 						)
 						.default_value("0"),
 				)
+				.arg(
+					Arg::with_name("min-interval")
+						.long("min-interval")
+						.value_name("SECONDS")
+						.default_value("10")
+						.help("Never fetch sooner than this, even when the server asks for it"),
+				)
+				.arg(
+					Arg::with_name("max-interval")
+						.long("max-interval")
+						.value_name("SECONDS")
+						.help("Never wait longer than this, even when the server asks for it"),
+				)
+				.arg(
+					Arg::with_name("clock")
+						.long("clock")
+						.help("Refresh at the start of every minute, timing the refresh so the new image appears on the minute"),
+				)
 				.arg(
 					Arg::with_name("wifi")
 						.long("wifi")
 						.value_name("INTERFACE")
 						.help("Wait until Wi-Fi interface connects"),
 				)
+				.arg(
+					Arg::with_name("wifi-qr")
+						.long("wifi-qr")
+						.value_name("TEXT")
+						.requires("wifi")
+						.help("Show a QR code encoding this text while waiting for Wi-Fi (e.g. 'WIFI:S:ssid;T:WPA;P:pass;;')"),
+				)
+				.arg(
+					Arg::with_name("wifi-check")
+						.long("wifi-check")
+						.value_name("CHECK")
+						.possible_value("wpa_supplicant")
+						.possible_value("networkmanager")
+						.possible_value("iwd")
+						.possible_value("probe")
+						.default_value("wpa_supplicant")
+						.help("How to tell that the network is up (probe: default route, DNS and a HEAD request to the URL)"),
+				)
+				.arg(
+					Arg::with_name("wifi-timeout")
+						.long("wifi-timeout")
+						.value_name("SECONDS")
+						.requires("wifi")
+						.help("Stop waiting for Wi-Fi after this long and start anyway"),
+				)
+				.arg(
+					Arg::with_name("wifi-poll")
+						.long("wifi-poll")
+						.value_name("SECONDS")
+						.default_value("5")
+						.help("Time between checks while waiting for Wi-Fi"),
+				)
+				.arg(
+					Arg::with_name("wpa-control")
+						.long("wpa-control")
+						.value_name("DIR")
+						.default_value("/var/run/wpa_supplicant")
+						.help("Directory of the wpa_supplicant control sockets"),
+				)
+				.arg(
+					Arg::with_name("listen")
+						.long("listen")
+						.value_name("ADDRESS")
+						.help("Serve a status page and Prometheus metrics (at /metrics) on this address (e.g. '0.0.0.0:9100')"),
+				)
+				.arg(
+					Arg::with_name("event-log")
+						.long("event-log")
+						.value_name("PATH")
+						.help("Append fetches, refreshes and failures to this file (JSON lines, rotated at 1 MiB)"),
+				)
+				.arg(
+					Arg::with_name("archive")
+						.long("archive")
+						.value_name("DIR")
+						.help("Keep every frame sent to the display in this directory (see the history command)"),
+				)
+				.arg(
+					Arg::with_name("cache")
+						.long("cache")
+						.value_name("DIR")
+						.help("Keep the last image in this directory, to show it again at startup until the network is up"),
+				)
+				.args(&overlay_args())
+				.arg(
+					Arg::with_name("retry-initial")
+						.long("retry-initial")
+						.value_name("SECONDS")
+						.default_value("5")
+						.help("Delay before retrying after the first failed fetch"),
+				)
+				.arg(
+					Arg::with_name("retry-max")
+						.long("retry-max")
+						.value_name("SECONDS")
+						.default_value("300")
+						.help("Maximum delay between retries (the delay doubles after each failed fetch)"),
+				)
+				.arg(
+					Arg::with_name("retry-jitter")
+						.long("retry-jitter")
+						.value_name("FRACTION")
+						.default_value("0.1")
+						.help("Randomly vary each retry delay by up to this fraction"),
+				)
+				.arg(
+					Arg::with_name("error-after")
+						.long("error-after")
+						.value_name("FAILURES")
+						.default_value("3")
+						.help("Replace the last good image with an error message after this many consecutive failures"),
+				)
+				.arg(
+					Arg::with_name("error-grace")
+						.long("error-grace")
+						.value_name("SECONDS")
+						.help("Also show the error message once fetches have been failing for this long"),
+				)
+				.args(&source_args())
 				.arg(Arg::with_name("url").takes_value(true).help("URL to fetch")),
 		)
+		.subcommand(
+			SubCommand::with_name("run")
+				.about("Drive the displays described in a configuration file (ignores the display options above)")
+				.arg(
+					Arg::with_name("config")
+						.required(true)
+						.value_name("PATH")
+						.help("TOML file describing the displays and their sources"),
+				),
+		)
+		.subcommand(
+			SubCommand::with_name("history")
+				.about("List the frames in an archive, or draw one of them again on the display")
+				.arg(
+					Arg::with_name("archive")
+						.required(true)
+						.value_name("DIR")
+						.help("Archive directory"),
+				)
+				.arg(
+					Arg::with_name("display")
+						.long("display")
+						.value_name("NAME")
+						.help("Only list the frames of this display"),
+				)
+				.arg(
+					Arg::with_name("show")
+						.long("show")
+						.value_name("ID")
+						.conflicts_with("display")
+						.help("Draw the frame with this ID (as listed) on the display given by the display options above"),
+				),
+		)
 		.get_matches();
-
-	// Configure SPI
-	let spi_path = matches.value_of("device").unwrap_or("/dev/spidev0.0");
-	log::info!("Using SPI device at {:?}", spi_path);
-	let mut spi = Spidev::open(spi_path)?;
-	let options = SpidevOptions::new()
-		.bits_per_word(8)
-		.max_speed_hz(10_000_000)
-		.mode(SpiModeFlags::SPI_MODE_0)
-		.build();
-	spi.configure(&options)?;
-
-	// Configure GPIO
-	let gpio = Gpio::new()?;
-	let cs = gpio.get(8)?.into_output(); // CE0 = BCM8
-	let busy = gpio.get(24)?.into_input(); // BCM24, physical 18
-	let dc = gpio.get(25)?.into_output(); // BCM25
-	let rst = gpio.get(17)?.into_output(); // BCM17
+	Logger::init(matches.value_of("log-format") == Some("json"));
 
 	let device_type = matches.value_of("type").unwrap_or("7in5v2");
 
 	if let Some(_subcommand_matches) = matches.subcommand_matches("test") {
+		let display_config = display_config_from_args(&matches)?;
+		let epd = Hardware::new().open_epd(&display_config.connection())?;
+		// Let a signal end the test between refreshes, rather than halfway through one
+		let service = Service::new();
+		service.handle_signals()?;
 		match device_type {
 			"7in5bc" => {
-				let mut display = EPD7in5bc::new(spi, busy, cs, dc, rst);
-				test_7in5bc(&mut display)?;
+				let mut display = EPD7in5bc::new(epd);
+				test_7in5bc(&mut display, &service)?;
 			}
 			"7in5v2" => {
-				let mut display = EPD7in5v2::new(spi, busy, cs, dc, rst);
-				test_7in5v2(&mut display)?;
+				let mut display = EPD7in5v2::new(epd);
+				test_7in5v2(&mut display, &service)?;
 			}
 			_ => panic!("invalid device type: {:?}", device_type),
 		}
-	} else if let Some(subcommand_matches) = matches.subcommand_matches("fetch") {
-		let url = subcommand_matches.value_of("url").expect("please provide a URL");
-		let interval = u64::from_str(subcommand_matches.value_of("interval").unwrap())?;
+	} else if let Some(subcommand_matches) = matches.subcommand_matches("encode") {
+		let png = std::fs::read(subcommand_matches.value_of("input").unwrap())?;
+		let bitmap = image::decode_png(&png)?;
+		let data = raw::encode(&bitmap, subcommand_matches.is_present("compress"))?;
+		std::fs::write(subcommand_matches.value_of("output").unwrap(), &data)?;
+		log::info!(
+			"wrote {}x{} raw image ({} bytes)",
+			bitmap.width,
+			bitmap.height,
+			data.len()
+		);
+	} else if let Some(subcommand_matches) = matches.subcommand_matches("qr") {
+		let text = subcommand_matches.value_of("text").unwrap();
+		let mut display = Hardware::new().open(&display_config_from_args(&matches)?)?;
+		// Signals are only acted on once the code is drawn and the panel sleeps
+		Service::new().handle_signals()?;
+
+		let qr = Qr::new(text)?;
+		let w = display.width() as u32;
+		let h = display.height() as u32;
+		let module_size = match subcommand_matches.value_of("module-size") {
+			Some(m) => u32::from_str(m)?,
+			None => qr.module_size_for(w.min(h)),
+		};
+		if module_size == 0 {
+			return Err(format!("QR code for {:?} does not fit on the display", text).into());
+		}
 
-		let mut display: Box<dyn EPDDisplay> = match device_type {
-			"7in5bc" => Box::new(EPD7in5bc::new(spi, busy, cs, dc, rst)),
-			"7in5v2" => Box::new(EPD7in5v2::new(spi, busy, cs, dc, rst)),
-			_ => panic!("invalid device type: {:?}", device_type),
+		let size = qr.size(module_size);
+		let x = match subcommand_matches.value_of("x") {
+			Some(x) => i32::from_str(x)?,
+			None => (w.saturating_sub(size) / 2) as i32,
+		};
+		let y = match subcommand_matches.value_of("y") {
+			Some(y) => i32::from_str(y)?,
+			None => (h.saturating_sub(size) / 2) as i32,
 		};
 
-		if let Some(wifi) = subcommand_matches.value_of("wifi") {
-			log::info!("wait for Wi-Fi device {:?}...", wifi);
-			wait_for_wifi(&mut display, wifi)?;
-		}
+		let mut image = Surface::new(w, h);
+		let _ = qr.draw(&mut image, Point::new(x, y), module_size);
+		display.init()?;
+		display.draw(image.buffer())?;
+		display.sleep()?;
+	} else if let Some(subcommand_matches) = matches.subcommand_matches("render") {
+		let (width, height) = hardware::panel_size(device_type).expect("invalid device type");
+		let panel = Panel {
+			width,
+			height,
+			kind: device_type.to_string(),
+		};
+		let overlay = with_overlay_args(DisplayConfig::default(), subcommand_matches)?
+			.overlay(subcommand_matches.value_of("wifi"));
+		let location = subcommand_matches.value_of("source").unwrap();
+
+		let result = if location.starts_with("http://") || location.starts_with("https://") {
+			fetch_once(&source_config_from_args(location, subcommand_matches)?, &panel)
+		} else {
+			image::load(Path::new(location), &panel)
+		};
 
-		loop {
-			log::info!("fetching new image from {:?}", url);
-			match fetch(url) {
-				Ok(buffer) => {
-					log::info!("displaying new image");
-					display.init()?;
-					display.draw(&buffer)?;
-					sleep(Duration::from_millis(500));
-					display.sleep()?;
-
-					if interval > 0 {
-						log::info!("sleeping for {:?} seconds", interval);
-						sleep(Duration::from_secs(interval));
-					} else {
-						return Ok(());
-					}
-				}
-				Err(e) => {
-					log::error!("fetch of {:?} failed: {:?}", url, e);
-					display_string(&mut display, &format!("fetch failed: {:?}", e))?;
-					log::info!("sleeping for five seconds before trying again...");
-					sleep(Duration::from_secs(5));
-				}
+		// Write what the display loop would have shown, which is the error screen when the image cannot be loaded
+		let (bitmap, error) = match result {
+			Ok(bitmap) => (overlay::compose(&bitmap, overlay.as_ref(), Local::now()), None),
+			Err(e) => {
+				let text = format!("fetch failed: {:?}", e);
+				let image = screen::text_screen(width as u32, height as u32, &text, None);
+				let bitmap = Bitmap {
+					width: width as u32,
+					height: height as u32,
+					black: image.into_buffer(),
+					color: None,
+				};
+				(bitmap, Some(e))
+			}
+		};
+
+		std::fs::write(subcommand_matches.value_of("output").unwrap(), bitmap.to_png()?)?;
+		if let Some(raw_path) = subcommand_matches.value_of("raw") {
+			std::fs::write(
+				raw_path,
+				raw::encode(&bitmap, subcommand_matches.is_present("compress"))?,
+			)?;
+		}
+		if let Some(e) = error {
+			return Err(e);
+		}
+	} else if let Some(subcommand_matches) = matches.subcommand_matches("fetch") {
+		run(|| fetch_config_from_args(&matches, subcommand_matches))?;
+	} else if let Some(subcommand_matches) = matches.subcommand_matches("run") {
+		let path = Path::new(subcommand_matches.value_of("config").unwrap());
+		run(|| Config::load(path))?;
+	} else if let Some(subcommand_matches) = matches.subcommand_matches("history") {
+		let dir = Path::new(subcommand_matches.value_of("archive").unwrap());
+		let entries = archive::entries(dir)?;
+		if let Some(id) = subcommand_matches.value_of("show") {
+			let entry = entries
+				.iter()
+				.find(|e| e.id == id)
+				.ok_or_else(|| format!("no frame {:?} in {:?}", id, dir))?;
+			let bitmap = entry.load(dir)?;
+			let mut display = Hardware::new().open(&display_config_from_args(&matches)?)?;
+			// Signals are only acted on once the frame is drawn and the panel sleeps
+			Service::new().handle_signals()?;
+			if (bitmap.width as usize, bitmap.height as usize) != (display.width(), display.height()) {
+				return Err(format!(
+					"frame is {}x{}, but the display is {}x{}",
+					bitmap.width,
+					bitmap.height,
+					display.width(),
+					display.height()
+				)
+				.into());
+			}
+			screen::show_bitmap(display.as_mut(), &bitmap)?;
+		} else {
+			let display = subcommand_matches.value_of("display");
+			for entry in entries
+				.iter()
+				.filter(|e| display.map(|d| e.display == d).unwrap_or(true))
+			{
+				let what = match (&entry.source, &entry.text) {
+					(Some(source), _) => source.clone(),
+					(None, Some(text)) => format!("{:?}", text),
+					(None, None) => entry.content.clone(),
+				};
+				println!(
+					"{}  {}  {} ({})  {}",
+					entry.id,
+					entry.time.format("%F %T %:z"),
+					entry.display,
+					entry.panel,
+					what
+				);
 			}
 		}
 	}
@@ -157,57 +549,263 @@ fn main() -> Result<(), Box<dyn Error>> {
 	Ok(())
 }
 
-fn wait_for_wifi(display: &mut Box<dyn EPDDisplay>, interface: &str) -> Result<(), Box<dyn Error>> {
-	loop {
-		log::info!("Waiting for Wi-Fi interface {:?}", interface);
-		// Check Wi-Fi status
-		let wifi_status = Command::new("/usr/bin/wpa_cli")
-			.arg(&format!("-i{}", interface))
-			.arg("-s/var/run/wpa_supplicant")
-			.arg("status")
-			.output()?;
-		log::info!("wifi: status={:?}", wifi_status);
-
-		if !wifi_status.status.success() {
-			log::info!("wpa_cli command was not sucessful; try again in a few seconds...");
-			let status_string = String::from_utf8(wifi_status.stdout).unwrap();
-			let message = format!("Waiting for Wi-Fi: {:?}", status_string);
-			display_string(display, &message)?;
-		} else {
-			let status_string = String::from_utf8(wifi_status.stdout).unwrap();
-			if status_string.contains("wpa_state=COMPLETED\n") {
-				// Still scanning
-				log::info!("Not scanning anymore!");
-				return Ok(());
-			}
-			display_string(display, &status_string)?;
+/** Logs like env_logger (configured through RUST_LOG), and also keeps the lines at info level and above for the status
+page, whatever RUST_LOG says */
+struct Logger(env_logger::Logger);
+
+impl Logger {
+	/** `json` logs lines as JSON objects (see `events::json_line`) */
+	fn init(json: bool) {
+		let mut builder = env_logger::Builder::from_default_env();
+		if json {
+			builder.format(|out, record| writeln!(out, "{}", events::json_line(record)));
 		}
+		let logger = builder.build();
+		log::set_max_level(logger.filter().max(log::LevelFilter::Info));
+		log::set_boxed_logger(Box::new(Logger(logger))).expect("logger set only once");
 	}
 }
 
-fn display_string(display: &mut Box<dyn EPDDisplay>, text: &str) -> Result<(), Box<dyn Error>> {
-	display.init()?;
-	let mut image = Surface::new(display.width() as u32, display.height() as u32);
+impl log::Log for Logger {
+	fn enabled(&self, metadata: &log::Metadata) -> bool {
+		metadata.level() <= log::Level::Info || self.0.enabled(metadata)
+	}
 
-	let mut y = 10;
-	for txt in text.chars().collect::<Vec<char>>().chunks(100) {
-		let line = txt.iter().collect::<String>();
-		let _ = Text::new(&line, Point::new(10, y))
-			.into_styled(text_style!(
-				font = Font6x12,
-				text_color = BinaryColor::Off,
-				background_color = BinaryColor::On
-			))
-			.draw(&mut image);
-		y += 14;
+	fn log(&self, record: &log::Record) {
+		if record.level() <= log::Level::Info {
+			status::log(record.level(), record.args().to_string());
+		}
+		self.0.log(record);
 	}
 
-	display.draw(image.buffer())?;
-	display.sleep()?;
-	Ok(())
+	fn flush(&self) {
+		self.0.flush();
+	}
 }
 
-fn test_7in5v2(display: &mut EPD7in5v2) -> Result<(), Box<dyn Error>> {
+#[cfg(not(feature = "http"))]
+fn run(_load: impl Fn() -> Result<Config, Box<dyn Error>>) -> Result<(), Box<dyn Error>> {
+	Err("built without HTTP support (feature 'http')".into())
+}
+
+/** Describe the single display given on the command line, fetching from the URL given to the fetch command */
+fn fetch_config_from_args(matches: &ArgMatches, args: &ArgMatches) -> Result<Config, Box<dyn Error>> {
+	let url = args.value_of("url").expect("please provide a URL");
+	let display = with_overlay_args(display_config_from_args(matches)?, args)?;
+	let display = DisplayConfig {
+		sources: vec![source_config_from_args(url, args)?],
+		interval: u64::from_str(args.value_of("interval").unwrap())?,
+		min_interval: u64::from_str(args.value_of("min-interval").unwrap())?,
+		max_interval: match args.value_of("max-interval") {
+			Some(max) => Some(u64::from_str(max)?),
+			None => None,
+		},
+		clock: args.is_present("clock"),
+		retry_initial: f64::from_str(args.value_of("retry-initial").unwrap())?,
+		retry_max: f64::from_str(args.value_of("retry-max").unwrap())?,
+		retry_jitter: f64::from_str(args.value_of("retry-jitter").unwrap())?,
+		error_after: u32::from_str(args.value_of("error-after").unwrap())?,
+		error_grace: match args.value_of("error-grace") {
+			Some(g) => Some(u64::from_str(g)?),
+			None => None,
+		},
+		..display
+	};
+
+	let config = Config {
+		wifi: args.value_of("wifi").map(|w| w.to_string()),
+		wifi_qr: args.value_of("wifi-qr").map(|w| w.to_string()),
+		wifi_check: WifiCheck::from_str(args.value_of("wifi-check").unwrap())?,
+		wifi_timeout: match args.value_of("wifi-timeout") {
+			Some(timeout) => Some(u64::from_str(timeout)?),
+			None => None,
+		},
+		wifi_poll: u64::from_str(args.value_of("wifi-poll").unwrap())?,
+		wpa_control: PathBuf::from(args.value_of("wpa-control").unwrap()),
+		listen: args.value_of("listen").map(|l| l.to_string()),
+		event_log: args.value_of("event-log").map(PathBuf::from),
+		archive: args.value_of("archive").map(|path| ArchiveConfig {
+			path: PathBuf::from(path),
+			..ArchiveConfig::default()
+		}),
+		cache: args.value_of("cache").map(PathBuf::from),
+		displays: vec![display],
+	};
+	config.validate()?;
+	Ok(config)
+}
+
+/** Options for the status badge, shared by the commands that show fetched images */
+fn overlay_args() -> Vec<Arg<'static, 'static>> {
+	vec![
+		Arg::with_name("overlay")
+			.long("overlay")
+			.value_name("CORNER")
+			.possible_value("top-left")
+			.possible_value("top-right")
+			.possible_value("bottom-left")
+			.possible_value("bottom-right")
+			.help("Draw a status badge (last update, Wi-Fi signal, host name) in a corner of the image"),
+		Arg::with_name("stale-after")
+			.long("stale-after")
+			.value_name("SECONDS")
+			.default_value("3600")
+			.help("Show a warning in the status badge when the image has not been updated for this long"),
+	]
+}
+
+/** Options for connecting to a source, shared by the commands that fetch images */
+fn source_args() -> Vec<Arg<'static, 'static>> {
+	vec![
+		Arg::with_name("ca-file")
+			.long("ca-file")
+			.value_name("PATH")
+			.help("PEM file with additional CA certificates to trust"),
+		Arg::with_name("pin-sha256")
+			.long("pin-sha256")
+			.value_name("FINGERPRINT")
+			.help("Require the server certificate to have this SHA-256 fingerprint (hex, colons allowed)"),
+		Arg::with_name("client-cert")
+			.long("client-cert")
+			.value_name("PATH")
+			.requires("client-key")
+			.help("PEM file with a client certificate to present to the server"),
+		Arg::with_name("client-key")
+			.long("client-key")
+			.value_name("PATH")
+			.requires("client-cert")
+			.help("PEM file with the private key for the client certificate"),
+		Arg::with_name("insecure")
+			.long("insecure")
+			.help("Do not verify the server certificate (a pinned fingerprint is still checked)"),
+		Arg::with_name("header")
+			.short("H")
+			.long("header")
+			.value_name("HEADER")
+			.multiple(true)
+			.number_of_values(1)
+			.help("Additional request header ('Name: value'), may be repeated"),
+		Arg::with_name("basic-auth")
+			.long("basic-auth")
+			.value_name("USERNAME")
+			.conflicts_with("bearer-token")
+			.help("Authenticate using HTTP Basic authentication with this user name"),
+		Arg::with_name("password")
+			.long("password")
+			.value_name("SECRET")
+			.requires("basic-auth")
+			.help("Password for Basic authentication ('file:PATH' or 'env:NAME')"),
+		Arg::with_name("bearer-token")
+			.long("bearer-token")
+			.value_name("SECRET")
+			.help("Authenticate using a Bearer token ('file:PATH' or 'env:NAME')"),
+		Arg::with_name("connect-timeout")
+			.long("connect-timeout")
+			.value_name("SECONDS")
+			.default_value("10")
+			.help("Maximum time to wait for a connection (zero to wait indefinitely)"),
+		Arg::with_name("timeout")
+			.long("timeout")
+			.value_name("SECONDS")
+			.default_value("60")
+			.help("Maximum time for the whole request including the response body (zero to wait indefinitely)"),
+		Arg::with_name("max-body-size")
+			.long("max-body-size")
+			.value_name("BYTES")
+			.default_value("10485760")
+			.help("Maximum size of the response (zero for no limit)"),
+		Arg::with_name("proxy")
+			.long("proxy")
+			.value_name("URL")
+			.help("Send requests through this proxy"),
+		Arg::with_name("max-redirects")
+			.long("max-redirects")
+			.value_name("COUNT")
+			.default_value("10")
+			.help("Maximum number of redirects to follow (zero to not follow redirects)"),
+	]
+}
+
+/** Describe the display given by the global options */
+fn display_config_from_args(matches: &ArgMatches) -> Result<DisplayConfig, Box<dyn Error>> {
+	let defaults = DisplayConfig::default();
+	let pin = |name: &str, default: u32| -> Result<u32, Box<dyn Error>> {
+		Ok(match matches.value_of(name) {
+			Some(number) => u32::from_str(number)?,
+			None => default,
+		})
+	};
+
+	Ok(DisplayConfig {
+		kind: matches.value_of("type").unwrap_or("7in5v2").to_string(),
+		spi: matches.value_of("device").unwrap_or("/dev/spidev0.0").to_string(),
+		gpio: match matches.value_of("gpio").unwrap_or("rpi") {
+			"gpiod" => GpioBackend::Gpiod,
+			_ => GpioBackend::Rpi,
+		},
+		gpio_chip: PathBuf::from(matches.value_of("gpio-chip").unwrap()),
+		gpio_consumer: matches.value_of("gpio-consumer").unwrap().to_string(),
+		busy_pin: pin("busy-pin", defaults.busy_pin)?,
+		cs_pin: match matches.value_of("cs-pin") {
+			Some("kernel") => None,
+			Some(number) => Some(u32::from_str(number)?),
+			None => defaults.cs_pin,
+		},
+		dc_pin: pin("dc-pin", defaults.dc_pin)?,
+		rst_pin: pin("rst-pin", defaults.rst_pin)?,
+		simulate: matches.value_of("simulate").map(PathBuf::from),
+		..defaults
+	})
+}
+
+#[cfg(feature = "http")]
+fn fetch_once(source: &SourceConfig, panel: &Panel) -> Result<Bitmap, Box<dyn Error>> {
+	Ok(fetch(&source.source()?, panel)?.bitmap)
+}
+
+#[cfg(not(feature = "http"))]
+fn fetch_once(_source: &SourceConfig, _panel: &Panel) -> Result<Bitmap, Box<dyn Error>> {
+	Err("built without HTTP support (feature 'http')".into())
+}
+
+/** Describe a source from the connection options given on the command line */
+fn source_config_from_args(url: &str, args: &ArgMatches) -> Result<SourceConfig, Box<dyn Error>> {
+	Ok(SourceConfig {
+		url: url.to_string(),
+		headers: args
+			.values_of("header")
+			.map(|h| h.map(|h| h.to_string()).collect())
+			.unwrap_or_default(),
+		basic_auth: args.value_of("basic-auth").map(|u| u.to_string()),
+		password: args.value_of("password").map(|p| p.to_string()),
+		bearer_token: args.value_of("bearer-token").map(|t| t.to_string()),
+		ca_file: args.value_of("ca-file").map(PathBuf::from),
+		pin_sha256: args.value_of("pin-sha256").map(|p| p.to_string()),
+		client_cert: args.value_of("client-cert").map(PathBuf::from),
+		client_key: args.value_of("client-key").map(PathBuf::from),
+		insecure: args.is_present("insecure"),
+		connect_timeout: f64::from_str(args.value_of("connect-timeout").unwrap())?,
+		timeout: f64::from_str(args.value_of("timeout").unwrap())?,
+		max_body_size: u64::from_str(args.value_of("max-body-size").unwrap())?,
+		proxy: args.value_of("proxy").map(|p| p.to_string()),
+		max_redirects: usize::from_str(args.value_of("max-redirects").unwrap())?,
+	})
+}
+
+/** Apply the status badge options given on the command line */
+fn with_overlay_args(display: DisplayConfig, args: &ArgMatches) -> Result<DisplayConfig, Box<dyn Error>> {
+	Ok(DisplayConfig {
+		overlay: match args.value_of("overlay") {
+			Some(corner) => Some(Corner::from_str(corner)?),
+			None => None,
+		},
+		stale_after: u64::from_str(args.value_of("stale-after").unwrap())?,
+		..display
+	})
+}
+
+/** Draw a test screen every minute until stopped (SIGUSR1 draws it right away) */
+fn test_7in5v2(display: &mut EPD7in5v2, service: &Service) -> Result<(), Box<dyn Error>> {
 	display.init()?;
 	display.clear(false)?;
 	sleep(Duration::from_millis(500));
@@ -245,12 +843,14 @@ fn test_7in5v2(display: &mut EPD7in5v2) -> Result<(), Box<dyn Error>> {
 		sleep(Duration::from_millis(500));
 		display.sleep()?;
 		log::debug!("display sleeping");
-		sleep(Duration::from_millis(60000));
+		if !service.sleep_for("test", Duration::from_secs(60), service.refreshes()) {
+			return Ok(());
+		}
 		display.init()?;
 	}
 }
 
-fn test_7in5bc(display: &mut EPD7in5bc) -> Result<(), Box<dyn Error>> {
+fn test_7in5bc(display: &mut EPD7in5bc, service: &Service) -> Result<(), Box<dyn Error>> {
 	display.init()?;
 	display.clear(false)?;
 	sleep(Duration::from_millis(500));
@@ -283,7 +883,9 @@ fn test_7in5bc(display: &mut EPD7in5bc) -> Result<(), Box<dyn Error>> {
 		sleep(Duration::from_millis(500));
 		display.sleep()?;
 		log::debug!("display sleeping");
-		sleep(Duration::from_millis(60000));
+		if !service.sleep_for("test", Duration::from_secs(60), service.refreshes()) {
+			return Ok(());
+		}
 		display.init()?;
 	}
 }
@@ -0,0 +1,280 @@
+/*! Counters and timings of fetching and drawing, in the Prometheus text format (served by `server` at /metrics).
+
+Metrics of a display are labelled with its name. Decoding, dithering, SPI transfers and waiting for a panel are
+measured for the whole process. */
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/** Upper bounds (in seconds) of the buckets of timing histograms */
+const BUCKETS: [f64; 12] = [0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+	Counter,
+	Gauge,
+	Histogram,
+}
+
+enum Value {
+	Number(f64),
+	/** Number of observations per bucket (not cumulative), their sum and count */
+	Histogram {
+		buckets: Vec<u64>,
+		sum: f64,
+		count: u64,
+	},
+}
+
+struct Family {
+	help: &'static str,
+	kind: Kind,
+	/** By labels, formatted as they appear between the braces */
+	series: BTreeMap<String, Value>,
+}
+
+static METRICS: Mutex<BTreeMap<&'static str, Family>> = Mutex::new(BTreeMap::new());
+
+fn update(name: &'static str, help: &'static str, kind: Kind, labels: &[(&str, &str)], f: impl FnOnce(&mut Value)) {
+	let labels = labels
+		.iter()
+		.map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+		.collect::<Vec<_>>()
+		.join(",");
+	let mut metrics = METRICS.lock().unwrap();
+	let family = metrics.entry(name).or_insert_with(|| Family {
+		help,
+		kind,
+		series: BTreeMap::new(),
+	});
+	let value = family.series.entry(labels).or_insert_with(|| match kind {
+		Kind::Histogram => Value::Histogram {
+			buckets: vec![0; BUCKETS.len()],
+			sum: 0.0,
+			count: 0,
+		},
+		_ => Value::Number(0.0),
+	});
+	f(value);
+}
+
+fn add(name: &'static str, help: &'static str, labels: &[(&str, &str)], amount: f64) {
+	update(name, help, Kind::Counter, labels, |value| {
+		if let Value::Number(n) = value {
+			*n += amount;
+		}
+	});
+}
+
+fn set(name: &'static str, help: &'static str, labels: &[(&str, &str)], to: f64) {
+	update(name, help, Kind::Gauge, labels, |value| *value = Value::Number(to));
+}
+
+fn observe(name: &'static str, help: &'static str, labels: &[(&str, &str)], duration: Duration) {
+	let seconds = duration.as_secs_f64();
+	update(name, help, Kind::Histogram, labels, |value| {
+		if let Value::Histogram { buckets, sum, count } = value {
+			if let Some(bucket) = BUCKETS.iter().position(|le| seconds <= *le) {
+				buckets[bucket] += 1;
+			}
+			*sum += seconds;
+			*count += 1;
+		}
+	});
+}
+
+fn escape(value: &str) -> String {
+	value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/** An image was downloaded for a display */
+pub fn fetched(display: &str, bytes: usize, duration: Duration) {
+	observe(
+		"sparkboard_fetch_duration_seconds",
+		"Time taken to download an image",
+		&[("display", display)],
+		duration,
+	);
+	add(
+		"sparkboard_fetch_bytes_total",
+		"Bytes of images downloaded",
+		&[("display", display)],
+		bytes as f64,
+	);
+}
+
+/** A PNG or raw image was decoded (not including dithering) */
+pub fn decoded(duration: Duration) {
+	observe(
+		"sparkboard_decode_duration_seconds",
+		"Time taken to decode an image",
+		&[],
+		duration,
+	);
+}
+
+/** An image was dithered to black and white */
+pub fn dithered(duration: Duration) {
+	observe(
+		"sparkboard_dither_duration_seconds",
+		"Time taken to dither an image",
+		&[],
+		duration,
+	);
+}
+
+/** Bytes were sent to a panel */
+pub fn spi_transfer(bytes: usize, duration: Duration) {
+	add(
+		"sparkboard_spi_seconds_total",
+		"Time spent sending commands and data to panels",
+		&[],
+		duration.as_secs_f64(),
+	);
+	add("sparkboard_spi_bytes_total", "Bytes sent to panels", &[], bytes as f64);
+}
+
+/** A panel was busy for this long (one call of `EPD::wait_until_idle`) */
+pub fn busy_wait(duration: Duration) {
+	observe(
+		"sparkboard_busy_wait_duration_seconds",
+		"Time spent waiting for a panel to become idle",
+		&[],
+		duration,
+	);
+}
+
+/** The panels of a display were refreshed ('full' or 'partial') */
+pub fn refreshed(display: &str, kind: &str) {
+	add(
+		"sparkboard_refreshes_total",
+		"Refreshes of the panels of a display",
+		&[("display", display), ("kind", kind)],
+		1.0,
+	);
+}
+
+/** A refresh was skipped because the panels already show the image */
+pub fn skipped_identical(display: &str) {
+	add(
+		"sparkboard_skipped_identical_total",
+		"Refreshes skipped because the image did not change",
+		&[("display", display)],
+		1.0,
+	);
+}
+
+/** A fetch failed; `consecutive` is the number of failures since the last success */
+pub fn failed(display: &str, consecutive: u32) {
+	add(
+		"sparkboard_fetch_failures_total",
+		"Failed fetches",
+		&[("display", display)],
+		1.0,
+	);
+	consecutive_failures(display, consecutive);
+}
+
+/** A new image was fetched successfully */
+pub fn succeeded(display: &str) {
+	let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+	set(
+		"sparkboard_last_success_timestamp_seconds",
+		"Time of the last successful fetch",
+		&[("display", display)],
+		now.as_secs_f64(),
+	);
+	consecutive_failures(display, 0);
+}
+
+fn consecutive_failures(display: &str, failures: u32) {
+	set(
+		"sparkboard_consecutive_failures",
+		"Fetches that failed since the last success",
+		&[("display", display)],
+		failures as f64,
+	);
+}
+
+/** Waited this long for Wi-Fi to connect */
+pub fn waited_for_wifi(duration: Duration) {
+	set(
+		"sparkboard_wifi_wait_seconds",
+		"Time spent waiting for Wi-Fi before the displays started",
+		&[],
+		duration.as_secs_f64(),
+	);
+}
+
+/** All metrics in the Prometheus text exposition format */
+pub fn render() -> String {
+	let metrics = METRICS.lock().unwrap();
+	let mut out = String::new();
+	for (name, family) in metrics.iter() {
+		let kind = match family.kind {
+			Kind::Counter => "counter",
+			Kind::Gauge => "gauge",
+			Kind::Histogram => "histogram",
+		};
+		let _ = writeln!(out, "# HELP {} {}", name, family.help);
+		let _ = writeln!(out, "# TYPE {} {}", name, kind);
+		for (labels, value) in &family.series {
+			match value {
+				Value::Number(n) => {
+					let _ = writeln!(out, "{}{} {}", name, braced(labels, None), n);
+				}
+				Value::Histogram { buckets, sum, count } => {
+					let mut cumulative = 0;
+					for (le, n) in BUCKETS.iter().zip(buckets) {
+						cumulative += n;
+						let _ = writeln!(out, "{}_bucket{} {}", name, braced(labels, Some(*le)), cumulative);
+					}
+					let _ = writeln!(out, "{}_bucket{} {}", name, braced(labels, Some(f64::INFINITY)), count);
+					let _ = writeln!(out, "{}_sum{} {}", name, braced(labels, None), sum);
+					let _ = writeln!(out, "{}_count{} {}", name, braced(labels, None), count);
+				}
+			}
+		}
+	}
+	out
+}
+
+/** Labels in braces, with the `le` label of a histogram bucket if given; nothing without labels */
+fn braced(labels: &str, le: Option<f64>) -> String {
+	let le = le.map(|le| {
+		if le.is_infinite() {
+			"le=\"+Inf\"".to_string()
+		} else {
+			format!("le=\"{}\"", le)
+		}
+	});
+	let all: Vec<&str> = std::iter::once(labels)
+		.filter(|l| !l.is_empty())
+		.chain(le.as_deref())
+		.collect();
+	if all.is_empty() {
+		String::new()
+	} else {
+		format!("{{{}}}", all.join(","))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn renders_prometheus_text() {
+		fetched("hall \"east\"", 1200, Duration::from_millis(300));
+		fetched("hall \"east\"", 800, Duration::from_millis(700));
+		let text = render();
+		assert!(text.contains("# TYPE sparkboard_fetch_bytes_total counter\n"));
+		assert!(text.contains("sparkboard_fetch_bytes_total{display=\"hall \\\"east\\\"\"} 2000\n"));
+		assert!(text.contains("sparkboard_fetch_duration_seconds_bucket{display=\"hall \\\"east\\\"\",le=\"0.5\"} 1\n"));
+		assert!(
+			text.contains("sparkboard_fetch_duration_seconds_bucket{display=\"hall \\\"east\\\"\",le=\"+Inf\"} 2\n")
+		);
+		assert!(text.contains("sparkboard_fetch_duration_seconds_count{display=\"hall \\\"east\\\"\"} 2\n"));
+	}
+}
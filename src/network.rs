@@ -0,0 +1,342 @@
+/*! Whether the network is up, so that displays can wait for it before fetching (see `WifiCheck`).
+
+Each check asks once and reports a `Readiness`: whether the network is ready, and the state it is in as reported by
+the service asked, to show on the displays while waiting. Nothing is started as a subprocess: wpa_supplicant is asked
+over its control socket, NetworkManager and iwd over D-Bus (see `dbus`). Those three are only available on Linux; the
+probe works anywhere, although only Linux tells whether there is a default route. */
+#[cfg(target_os = "linux")]
+use crate::dbus::{self, Value};
+use serde::Deserialize;
+use std::error::Error;
+use std::fs;
+#[cfg(target_os = "linux")]
+use std::os::unix::net::UnixDatagram;
+#[cfg(any(target_os = "linux", feature = "http"))]
+use std::path::Path;
+use std::str::FromStr;
+#[cfg(target_os = "linux")]
+use std::time::Duration;
+
+/** How long to wait for wpa_supplicant to answer */
+#[cfg(target_os = "linux")]
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/** How to find out whether the network is ready */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+pub enum WifiCheck {
+	/** Ask wpa_supplicant (over its control socket) whether the interface is associated */
+	#[default]
+	#[serde(rename = "wpa_supplicant")]
+	WpaSupplicant,
+	/** Ask NetworkManager (over D-Bus) whether the interface is activated */
+	#[serde(rename = "networkmanager")]
+	NetworkManager,
+	/** Ask iwd (over D-Bus) whether the station on the interface is connected */
+	#[serde(rename = "iwd")]
+	Iwd,
+	/** Check that there is a default route, and that the first source resolves and answers, whatever runs the network */
+	#[serde(rename = "probe")]
+	Probe,
+}
+
+impl FromStr for WifiCheck {
+	type Err = Box<dyn Error>;
+
+	fn from_str(s: &str) -> Result<WifiCheck, Self::Err> {
+		match s {
+			"wpa_supplicant" => Ok(WifiCheck::WpaSupplicant),
+			"networkmanager" => Ok(WifiCheck::NetworkManager),
+			"iwd" => Ok(WifiCheck::Iwd),
+			"probe" => Ok(WifiCheck::Probe),
+			_ => Err(format!("invalid Wi-Fi check: {:?}", s).into()),
+		}
+	}
+}
+
+/** What a check found */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Readiness {
+	pub ready: bool,
+	/** The state the network is in, such as 'wpa_supplicant: SCANNING' */
+	pub state: String,
+}
+
+/** Check once whether the network on `interface` is ready. `control` is the directory of the wpa_supplicant control
+sockets; `source` is what the probe tries to reach. */
+#[cfg(feature = "http")]
+pub fn check(
+	check: WifiCheck,
+	interface: &str,
+	control: &Path,
+	source: Option<&crate::fetch::Source>,
+) -> Result<Readiness, Box<dyn Error>> {
+	match check {
+		#[cfg(target_os = "linux")]
+		WifiCheck::WpaSupplicant => wpa_supplicant(control, interface),
+		#[cfg(target_os = "linux")]
+		WifiCheck::NetworkManager => network_manager(interface),
+		#[cfg(target_os = "linux")]
+		WifiCheck::Iwd => iwd(interface),
+		#[cfg(not(target_os = "linux"))]
+		WifiCheck::WpaSupplicant | WifiCheck::NetworkManager | WifiCheck::Iwd => {
+			let _ = (interface, control);
+			Err("only the probe can check the network outside of Linux".into())
+		}
+		WifiCheck::Probe => probe(source.ok_or("there is no source to probe")?),
+	}
+}
+
+/** Ask wpa_supplicant for the state of an interface, through its control socket in `control` */
+#[cfg(target_os = "linux")]
+pub fn wpa_supplicant(control: &Path, interface: &str) -> Result<Readiness, Box<dyn Error>> {
+	let status = wpa_request(&control.join(interface), "STATUS")?;
+	let state = wpa_state(&status).unwrap_or("UNKNOWN");
+	Ok(Readiness {
+		ready: state == "COMPLETED",
+		state: format!("wpa_supplicant: {}", state),
+	})
+}
+
+/** Send a request to a wpa_supplicant control socket, and return the answer */
+#[cfg(target_os = "linux")]
+fn wpa_request(socket: &Path, request: &str) -> Result<String, Box<dyn Error>> {
+	// wpa_supplicant answers to the address the request came from, so the client socket needs a name too
+	let local = std::env::temp_dir().join(format!("sparkboard-wpa-{}", std::process::id()));
+	let _ = fs::remove_file(&local);
+	let client = UnixDatagram::bind(&local)?;
+	let exchange = || -> Result<String, Box<dyn Error>> {
+		client.set_read_timeout(Some(TIMEOUT))?;
+		client.connect(socket)?;
+		client.send(request.as_bytes())?;
+		let mut buffer = [0; 4096];
+		let size = client.recv(&mut buffer)?;
+		Ok(String::from_utf8_lossy(&buffer[..size]).into_owned())
+	};
+	let answer = exchange();
+	let _ = fs::remove_file(&local);
+	answer.map_err(|e| format!("could not ask wpa_supplicant at {:?}: {}", socket, e).into())
+}
+
+// The answer to STATUS has lines like "wpa_state=COMPLETED"
+#[cfg(target_os = "linux")]
+fn wpa_state(status: &str) -> Option<&str> {
+	status.lines().find_map(|line| line.strip_prefix("wpa_state="))
+}
+
+/** Ask NetworkManager for the state of the device of an interface */
+#[cfg(target_os = "linux")]
+pub fn network_manager(interface: &str) -> Result<Readiness, Box<dyn Error>> {
+	const SERVICE: &str = "org.freedesktop.NetworkManager";
+	let mut bus = dbus::Connection::system()?;
+	let device = bus.call(
+		SERVICE,
+		"/org/freedesktop/NetworkManager",
+		SERVICE,
+		"GetDeviceByIpIface",
+		&[interface],
+	)?;
+	let device = device
+		.first()
+		.and_then(Value::as_str)
+		.ok_or("NetworkManager returned no device")?
+		.to_string();
+	let state = bus
+		.property(SERVICE, &device, "org.freedesktop.NetworkManager.Device", "State")?
+		.as_u64()
+		.ok_or("NetworkManager returned no device state")?;
+	// NMDeviceState
+	let name = match state {
+		10 => "unmanaged",
+		20 => "unavailable",
+		30 => "disconnected",
+		40 => "prepare",
+		50 => "config",
+		60 => "need-auth",
+		70 => "ip-config",
+		80 => "ip-check",
+		90 => "secondaries",
+		100 => "activated",
+		110 => "deactivating",
+		120 => "failed",
+		_ => "unknown",
+	};
+	Ok(Readiness {
+		ready: state == 100,
+		state: format!("NetworkManager: {}", name),
+	})
+}
+
+/** Ask iwd for the state of the station on an interface */
+#[cfg(target_os = "linux")]
+pub fn iwd(interface: &str) -> Result<Readiness, Box<dyn Error>> {
+	let mut bus = dbus::Connection::system()?;
+	let objects = bus.call(
+		"net.connman.iwd",
+		"/",
+		"org.freedesktop.DBus.ObjectManager",
+		"GetManagedObjects",
+		&[],
+	)?;
+	let state = objects
+		.first()
+		.and_then(|objects| iwd_station_state(objects, interface))
+		.ok_or_else(|| format!("iwd has no station on {}", interface))?;
+	Ok(Readiness {
+		ready: state == "connected",
+		state: format!("iwd: {}", state),
+	})
+}
+
+/** State of the station on an interface, from the objects iwd manages (by path, then interface, then property) */
+#[cfg(target_os = "linux")]
+fn iwd_station_state<'a>(objects: &'a Value, interface: &str) -> Option<&'a str> {
+	objects.as_array()?.iter().find_map(|object| {
+		let interfaces = match object {
+			Value::Struct(fields) if fields.len() == 2 => &fields[1],
+			_ => return None,
+		};
+		if interfaces.get("net.connman.iwd.Device")?.get("Name")?.as_str()? != interface {
+			return None;
+		}
+		interfaces.get("net.connman.iwd.Station")?.get("State")?.as_str()
+	})
+}
+
+/** Check that the network reaches a source: there is a default route, its host resolves, and it answers a HEAD
+request (whatever the status, as the server did answer) */
+#[cfg(feature = "http")]
+pub fn probe(source: &crate::fetch::Source) -> Result<Readiness, Box<dyn Error>> {
+	use std::net::ToSocketAddrs;
+
+	let waiting = |state: String| Ok(Readiness { ready: false, state });
+	let route = default_route();
+	if cfg!(target_os = "linux") && route.is_none() {
+		return waiting("no default route".to_string());
+	}
+	let url = reqwest::Url::parse(&source.url)?;
+	// Through a proxy, the host is resolved by the proxy
+	if source.proxy.is_none() {
+		let host = url.host_str().ok_or("the source has no host")?;
+		let port = url.port_or_known_default().unwrap_or(80);
+		if !(host, port)
+			.to_socket_addrs()
+			.map(|mut a| a.next().is_some())
+			.unwrap_or(false)
+		{
+			return waiting(format!("cannot resolve {}", host));
+		}
+	}
+	match crate::fetch::head(source) {
+		Ok(status) => Ok(Readiness {
+			ready: true,
+			state: match route {
+				Some(route) => format!("{} answered {} (route through {})", url, status, route),
+				None => format!("{} answered {}", url, status),
+			},
+		}),
+		Err(e) => waiting(format!("{} does not answer: {}", url, e)),
+	}
+}
+
+/** Interface of the default route, from /proc/net/route (IPv4) or /proc/net/ipv6_route (so always None outside of Linux) */
+pub fn default_route() -> Option<String> {
+	let ipv4 = fs::read_to_string("/proc/net/route").ok();
+	let ipv6 = fs::read_to_string("/proc/net/ipv6_route").ok();
+	ipv4.as_deref()
+		.and_then(parse_default_route)
+		.or_else(|| ipv6.as_deref().and_then(parse_default_route_v6))
+}
+
+// Lines look like: "wlan0	00000000	0102A8C0	0003	0	0	600	00000000	0	0	0" (interface, destination,
+// gateway, flags, ..., mask); the first is a header
+fn parse_default_route(route: &str) -> Option<String> {
+	route.lines().skip(1).find_map(|line| {
+		let fields: Vec<&str> = line.split_whitespace().collect();
+		let up = fields.get(3).and_then(|f| u32::from_str_radix(f, 16).ok()).unwrap_or(0) & 1 != 0;
+		if fields.len() >= 8 && fields[1] == "00000000" && fields[7] == "00000000" && up {
+			Some(fields[0].to_string())
+		} else {
+			None
+		}
+	})
+}
+
+// Lines look like: "00000000000000000000000000000000 00 00000000000000000000000000000000 00
+// fe800000000000000000000000000001 00000400 00000001 00000000 00000003 wlan0" (destination, prefix length, source,
+// prefix length, next hop, metric, references, use, flags, interface)
+fn parse_default_route_v6(route: &str) -> Option<String> {
+	route.lines().find_map(|line| {
+		let fields: Vec<&str> = line.split_whitespace().collect();
+		let flags = fields.get(8).and_then(|f| u32::from_str_radix(f, 16).ok()).unwrap_or(0);
+		// Up, and not a route that rejects packets (the kernel adds one through the loopback interface)
+		let usable = flags & 0x1 != 0 && flags & 0x200 == 0;
+		if fields.len() >= 10 && fields[0].bytes().all(|b| b == b'0') && fields[1] == "00" && usable {
+			Some(fields[9].to_string())
+		} else {
+			None
+		}
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn finds_default_routes() {
+		let route = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+			wlan0\t0002A8C0\t00000000\t0001\t0\t0\t600\t00FFFFFF\t0\t0\t0\n\
+			wlan0\t00000000\t0102A8C0\t0003\t0\t0\t600\t00000000\t0\t0\t0\n";
+		assert_eq!(parse_default_route(route).as_deref(), Some("wlan0"));
+		assert_eq!(
+			parse_default_route(route.lines().take(2).collect::<Vec<_>>().join("\n").as_str()),
+			None
+		);
+
+		let zero = "00000000000000000000000000000000";
+		let reject = format!(
+			"{} 00 {} 00 {} ffffffff 00000001 00000000 00200200 lo\n",
+			zero, zero, zero
+		);
+		assert_eq!(parse_default_route_v6(&reject), None);
+		let route = format!(
+			"{}{} 00 {} 00 fe800000000000000000000000000001 00000400 00000001 00000000 00000003 wlan0\n",
+			reject, zero, zero
+		);
+		assert_eq!(parse_default_route_v6(&route).as_deref(), Some("wlan0"));
+	}
+
+	#[test]
+	#[cfg(target_os = "linux")]
+	fn finds_states() {
+		assert_eq!(
+			wpa_state("bssid=00:11:22:33:44:55\nssid=home\nwpa_state=COMPLETED\nip_address=192.168.2.5\n"),
+			Some("COMPLETED")
+		);
+		assert_eq!(wpa_state("FAIL\n"), None);
+
+		let text = |s: &str| Value::Str(s.to_string());
+		let entry = |key: &str, value: Value| Value::Struct(vec![text(key), value]);
+		let objects = Value::Array(vec![
+			Value::Struct(vec![
+				text("/net/connman/iwd"),
+				Value::Array(vec![entry("net.connman.iwd.AgentManager", Value::Array(vec![]))]),
+			]),
+			Value::Struct(vec![
+				text("/net/connman/iwd/0/4"),
+				Value::Array(vec![
+					entry(
+						"net.connman.iwd.Device",
+						Value::Array(vec![entry("Name", Value::Variant(Box::new(text("wlan0"))))]),
+					),
+					entry(
+						"net.connman.iwd.Station",
+						Value::Array(vec![entry("State", Value::Variant(Box::new(text("connecting"))))]),
+					),
+				]),
+			]),
+		]);
+		assert_eq!(iwd_station_state(&objects, "wlan0"), Some("connecting"));
+		assert_eq!(iwd_station_state(&objects, "wlan1"), None);
+	}
+}
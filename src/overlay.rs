@@ -0,0 +1,261 @@
+use crate::graphics::{Bitmap, Surface};
+use crate::system;
+use chrono::prelude::*;
+use embedded_graphics::{
+	fonts::{Font6x12, Text},
+	pixelcolor::BinaryColor,
+	prelude::*,
+	primitives::{Rectangle, Triangle},
+	style::PrimitiveStyle,
+	text_style,
+};
+use serde::Deserialize;
+use std::error::Error;
+use std::str::FromStr;
+
+const LINE_HEIGHT: i32 = 12;
+const CHAR_WIDTH: i32 = 6;
+const PADDING: i32 = 4;
+const MARGIN: i32 = 4;
+const GLYPH_SIZE: i32 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Corner {
+	TopLeft,
+	TopRight,
+	BottomLeft,
+	BottomRight,
+}
+
+impl FromStr for Corner {
+	type Err = Box<dyn Error>;
+
+	fn from_str(s: &str) -> Result<Corner, Self::Err> {
+		match s {
+			"top-left" => Ok(Corner::TopLeft),
+			"top-right" => Ok(Corner::TopRight),
+			"bottom-left" => Ok(Corner::BottomLeft),
+			"bottom-right" => Ok(Corner::BottomRight),
+			_ => Err(format!("invalid corner: {:?}", s).into()),
+		}
+	}
+}
+
+/** Status badge drawn on top of a (dithered) image */
+#[derive(Debug, Clone)]
+pub struct Overlay {
+	pub corner: Corner,
+	/** Show a warning glyph when the last successful update is older than this */
+	pub stale_after: chrono::Duration,
+	/** Wireless interface to show the signal level for */
+	pub wifi_interface: Option<String>,
+}
+
+impl Overlay {
+	/** Whether the warning glyph is shown for an image last updated at `last_update` */
+	pub fn is_stale(&self, last_update: DateTime<Local>, now: DateTime<Local>) -> bool {
+		now.signed_duration_since(last_update) > self.stale_after
+	}
+
+	/** Draw the badge onto the image and return the area it covers. `last_update` is the time of the last good fetch. */
+	pub fn draw(&self, image: &mut Surface, last_update: DateTime<Local>) -> Rectangle {
+		self.draw_at(image, last_update, Local::now())
+	}
+
+	fn draw_at(&self, image: &mut Surface, last_update: DateTime<Local>, now: DateTime<Local>) -> Rectangle {
+		let stale = self.is_stale(last_update, now);
+
+		let mut lines = vec![format!(
+			"Updated {} ({} ago)",
+			last_update.format("%Y-%m-%d %H:%M"),
+			format_age(now.signed_duration_since(last_update))
+		)];
+
+		if let Some(interface) = &self.wifi_interface {
+			lines.push(match system::wifi_signal(interface) {
+				Some(level) => format!("Wi-Fi {}: {} dBm", interface, level),
+				None => format!("Wi-Fi {}: no signal", interface),
+			});
+		}
+
+		let host = system::hostname().unwrap_or_else(|| "unknown".to_string());
+		lines.push(match system::local_ip() {
+			Some(ip) => format!("{} ({})", host, ip),
+			None => host,
+		});
+
+		let text_width = lines.iter().map(|l| l.chars().count() as i32).max().unwrap_or(0) * CHAR_WIDTH;
+		let glyph_width = if stale { GLYPH_SIZE + PADDING } else { 0 };
+		let badge_width = text_width + glyph_width + 2 * PADDING;
+		let badge_height = (lines.len() as i32 * LINE_HEIGHT).max(if stale { GLYPH_SIZE } else { 0 }) + 2 * PADDING;
+
+		let size = image.size();
+		let (w, h) = (size.width as i32, size.height as i32);
+		let origin = match self.corner {
+			Corner::TopLeft => Point::new(MARGIN, MARGIN),
+			Corner::TopRight => Point::new(w - badge_width - MARGIN, MARGIN),
+			Corner::BottomLeft => Point::new(MARGIN, h - badge_height - MARGIN),
+			Corner::BottomRight => Point::new(w - badge_width - MARGIN, h - badge_height - MARGIN),
+		};
+		let badge = Rectangle::new(origin, origin + Point::new(badge_width - 1, badge_height - 1));
+
+		// White box with a black border
+		let _ = badge
+			.into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+			.draw(image);
+		let _ = badge
+			.into_styled(PrimitiveStyle::with_stroke(BinaryColor::Off, 1))
+			.draw(image);
+
+		if stale {
+			draw_warning_glyph(image, origin + Point::new(PADDING, PADDING));
+		}
+
+		let text_origin = origin + Point::new(PADDING + glyph_width, PADDING);
+		for (i, line) in lines.iter().enumerate() {
+			let _ = Text::new(line, text_origin + Point::new(0, i as i32 * LINE_HEIGHT))
+				.into_styled(text_style!(
+					font = Font6x12,
+					text_color = BinaryColor::Off,
+					background_color = BinaryColor::On
+				))
+				.draw(image);
+		}
+		badge
+	}
+}
+
+/** The image as it should appear on the display: the fetched image with the status overlay (if any) on top */
+pub fn compose(bitmap: &Bitmap, overlay: Option<&Overlay>, last_update: DateTime<Local>) -> Bitmap {
+	match overlay {
+		Some(overlay) => {
+			let mut image = Surface::from_buffer(bitmap.width, bitmap.height, bitmap.black.clone());
+			let badge = overlay.draw(&mut image, last_update);
+			// Colored pixels would show over the badge, so there are none under it
+			let color = bitmap.color.as_ref().map(|color| {
+				let mut plane = Surface::from_buffer(bitmap.width, bitmap.height, color.clone());
+				let _ = badge
+					.into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+					.draw(&mut plane);
+				plane.into_buffer()
+			});
+			Bitmap {
+				black: image.into_buffer(),
+				color,
+				..bitmap.clone()
+			}
+		}
+		None => bitmap.clone(),
+	}
+}
+
+/** Black triangle with a white exclamation mark */
+fn draw_warning_glyph(image: &mut Surface, top_left: Point) {
+	let _ = Triangle::new(
+		top_left + Point::new(GLYPH_SIZE / 2, 0),
+		top_left + Point::new(0, GLYPH_SIZE - 1),
+		top_left + Point::new(GLYPH_SIZE - 1, GLYPH_SIZE - 1),
+	)
+	.into_styled(PrimitiveStyle::with_fill(BinaryColor::Off))
+	.draw(image);
+
+	let x = GLYPH_SIZE / 2 - 1;
+	let _ = Rectangle::new(top_left + Point::new(x, 7), top_left + Point::new(x + 1, 13))
+		.into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+		.draw(image);
+	let _ = Rectangle::new(top_left + Point::new(x, 15), top_left + Point::new(x + 1, 16))
+		.into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+		.draw(image);
+}
+
+fn format_age(age: chrono::Duration) -> String {
+	if age.num_days() > 0 {
+		format!("{}d", age.num_days())
+	} else if age.num_hours() > 0 {
+		format!("{}h", age.num_hours())
+	} else {
+		format!("{}m", age.num_minutes().max(0))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn overlay(corner: Corner) -> Overlay {
+		Overlay {
+			corner,
+			stale_after: chrono::Duration::minutes(30),
+			wifi_interface: None,
+		}
+	}
+
+	#[test]
+	fn formats_ages() {
+		assert_eq!(format_age(chrono::Duration::seconds(-5)), "0m");
+		assert_eq!(format_age(chrono::Duration::minutes(59)), "59m");
+		assert_eq!(format_age(chrono::Duration::minutes(61)), "1h");
+		assert_eq!(format_age(chrono::Duration::hours(47)), "1d");
+	}
+
+	#[test]
+	fn warns_once_the_image_is_stale() {
+		let overlay = overlay(Corner::TopLeft);
+		let now = Local.ymd(2021, 1, 4).and_hms(12, 0, 0);
+		assert!(!overlay.is_stale(now - chrono::Duration::minutes(30), now));
+		assert!(overlay.is_stale(now - chrono::Duration::minutes(31), now));
+
+		// The glyph makes room for itself next to the text
+		let badge = |minutes| {
+			let mut image = Surface::new(400, 300);
+			overlay.draw_at(&mut image, now - chrono::Duration::minutes(minutes), now)
+		};
+		let (fresh, stale) = (badge(29), badge(31));
+		assert_eq!(stale.bottom_right.x - fresh.bottom_right.x, GLYPH_SIZE + PADDING);
+	}
+
+	#[test]
+	fn places_the_badge_in_its_corner() {
+		let now = Local::now();
+		for corner in &[
+			Corner::TopLeft,
+			Corner::TopRight,
+			Corner::BottomLeft,
+			Corner::BottomRight,
+		] {
+			let mut image = Surface::new(400, 300);
+			let badge = overlay(*corner).draw_at(&mut image, now, now);
+			let left = badge.top_left.x == MARGIN;
+			let top = badge.top_left.y == MARGIN;
+			let right = badge.bottom_right.x == 400 - MARGIN - 1;
+			let bottom = badge.bottom_right.y == 300 - MARGIN - 1;
+			let expected = match corner {
+				Corner::TopLeft => (true, true, false, false),
+				Corner::TopRight => (false, true, true, false),
+				Corner::BottomLeft => (true, false, false, true),
+				Corner::BottomRight => (false, false, true, true),
+			};
+			assert_eq!((left, top, right, bottom), expected, "{:?}", corner);
+			// The border is black
+			let index = (badge.top_left.y * 400 / 8 + badge.top_left.x / 8) as usize;
+			assert_ne!(image.buffer()[index] & 0x80 >> (badge.top_left.x % 8), 0);
+		}
+	}
+
+	#[test]
+	fn clears_color_under_the_badge() {
+		let bitmap = Bitmap {
+			width: 400,
+			height: 300,
+			black: vec![0; 400 * 300 / 8],
+			color: Some(vec![0xFF; 400 * 300 / 8]),
+		};
+		let composed = compose(&bitmap, Some(&overlay(Corner::TopLeft)), Local::now());
+		let color = composed.color.unwrap();
+		// Inside the badge (past the border and margin), and at the opposite corner
+		let row = (MARGIN as usize + 2) * 400 / 8;
+		assert_eq!(color[row + 1], 0);
+		assert_eq!(color[color.len() - 1], 0xFF);
+	}
+}
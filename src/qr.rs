@@ -0,0 +1,103 @@
+use crate::graphics::{Bitmap, Surface};
+use crate::overlay::Corner;
+use embedded_graphics::{pixelcolor::BinaryColor, prelude::*, primitives::Rectangle, style::PrimitiveStyle};
+use qrcode::{Color, QrCode};
+use std::error::Error;
+
+/** Number of light modules drawn around the code, as required by the QR specification */
+const QUIET_ZONE: u32 = 4;
+
+/** Distance between a code drawn in a corner and the edges of the image */
+const MARGIN: i32 = 10;
+
+/** QR code that can be drawn on a `Surface` */
+pub struct Qr {
+	code: QrCode,
+}
+
+impl Qr {
+	pub fn new(text: &str) -> Result<Qr, Box<dyn Error>> {
+		Ok(Qr {
+			code: QrCode::new(text.as_bytes())?,
+		})
+	}
+
+	/** Size in pixels of one side of the code (including quiet zone) when drawn at the indicated module size */
+	pub fn size(&self, module_size: u32) -> u32 {
+		(self.code.width() as u32 + 2 * QUIET_ZONE) * module_size
+	}
+
+	/** Largest module size at which the code (including quiet zone) fits in `max_size` pixels, or zero if it can't */
+	pub fn module_size_for(&self, max_size: u32) -> u32 {
+		max_size / (self.code.width() as u32 + 2 * QUIET_ZONE)
+	}
+
+	/** Draw the code with the top left corner of its quiet zone at `origin`, each module `module_size` pixels square */
+	pub fn draw<D: DrawTarget<BinaryColor>>(
+		&self,
+		target: &mut D,
+		origin: Point,
+		module_size: u32,
+	) -> Result<(), D::Error> {
+		let size = self.size(module_size) as i32;
+		Rectangle::new(origin, origin + Point::new(size - 1, size - 1))
+			.into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+			.draw(target)?;
+
+		let dark = PrimitiveStyle::with_fill(BinaryColor::Off);
+		let width = self.code.width();
+		let module = module_size as i32;
+		let offset = origin + Point::new((QUIET_ZONE * module_size) as i32, (QUIET_ZONE * module_size) as i32);
+		for (index, color) in self.code.to_colors().iter().enumerate() {
+			if *color == Color::Dark {
+				let top_left = offset + Point::new((index % width) as i32 * module, (index / width) as i32 * module);
+				Rectangle::new(top_left, top_left + Point::new(module - 1, module - 1))
+					.into_styled(dark)
+					.draw(target)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+/** A QR code drawn in a corner of every image a display shows */
+pub struct QrOverlay {
+	pub qr: Qr,
+	pub corner: Corner,
+	/** Pixels per module; by default the largest at which the code fits in a quarter of the shorter side */
+	pub module_size: Option<u32>,
+}
+
+impl QrOverlay {
+	/** The image with the code drawn on top */
+	pub fn compose(&self, bitmap: &Bitmap) -> Bitmap {
+		let module_size = self
+			.module_size
+			.unwrap_or_else(|| self.qr.module_size_for(bitmap.width.min(bitmap.height) / 4))
+			.max(1);
+		let size = self.qr.size(module_size) as i32;
+		let (w, h) = (bitmap.width as i32, bitmap.height as i32);
+		let origin = match self.corner {
+			Corner::TopLeft => Point::new(MARGIN, MARGIN),
+			Corner::TopRight => Point::new(w - size - MARGIN, MARGIN),
+			Corner::BottomLeft => Point::new(MARGIN, h - size - MARGIN),
+			Corner::BottomRight => Point::new(w - size - MARGIN, h - size - MARGIN),
+		};
+
+		let mut image = Surface::from_buffer(bitmap.width, bitmap.height, bitmap.black.clone());
+		let _ = self.qr.draw(&mut image, origin, module_size);
+		// Colored pixels would show over the code, so there are none under it
+		let color = bitmap.color.as_ref().map(|color| {
+			let mut plane = Surface::from_buffer(bitmap.width, bitmap.height, color.clone());
+			let _ = Rectangle::new(origin, origin + Point::new(size - 1, size - 1))
+				.into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+				.draw(&mut plane);
+			plane.into_buffer()
+		});
+		Bitmap {
+			black: image.into_buffer(),
+			color,
+			..bitmap.clone()
+		}
+	}
+}
@@ -0,0 +1,233 @@
+/*! Pre-packed framebuffer format, so that servers can do the dithering and devices only need to copy bytes.
+
+All numbers are big-endian. The header is 12 bytes:
+
+| Offset | Size | Contents                                                               |
+|--------|------|------------------------------------------------------------------------|
+| 0      | 4    | Magic `SPKB`                                                           |
+| 4      | 1    | Format version (1)                                                     |
+| 5      | 1    | Flags. Bit 0 set: payload is zlib-compressed                           |
+| 6      | 2    | Width in pixels (multiple of 8)                                        |
+| 8      | 2    | Height in pixels                                                       |
+| 10     | 1    | Bits per pixel (1)                                                     |
+| 11     | 1    | Number of planes: 1 (black) or 2 (black followed by color)             |
+
+The payload holds the planes one after the other, each `width / 8 * height` bytes. A plane is packed as the displays
+expect it: rows top to bottom, eight pixels per byte with the leftmost pixel in the most significant bit, and a set
+bit meaning black (or colored, for the second plane). */
+use crate::graphics::Bitmap;
+use crate::image::{ImageError, Panel};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::error::Error;
+use std::io::{Read, Write};
+
+/** Content type servers should use when responding with this format */
+pub const CONTENT_TYPE: &str = "application/vnd.sparkboard.1bpp";
+
+const MAGIC: &[u8; 4] = b"SPKB";
+const VERSION: u8 = 1;
+const FLAG_COMPRESSED: u8 = 0x01;
+const HEADER_SIZE: usize = 12;
+
+/** Whether the data looks like a raw image (starts with the magic bytes) */
+pub fn is_raw(data: &[u8]) -> bool {
+	data.starts_with(MAGIC)
+}
+
+/** Decode a raw image for the panel. The size in the header is checked before the payload is read, so a bogus
+header cannot make us allocate (or decompress) more than the panel needs. */
+pub fn decode(data: &[u8], panel: &Panel) -> Result<Bitmap, Box<dyn Error>> {
+	if data.len() < HEADER_SIZE || &data[0..4] != MAGIC {
+		return Err(ImageError::Decode("not a Sparkboard raw image".to_string()).into());
+	}
+	if data[4] != VERSION {
+		return Err(ImageError::Decode(format!("unsupported raw image version {}", data[4])).into());
+	}
+
+	let flags = data[5];
+	let width = u16::from_be_bytes([data[6], data[7]]) as u32;
+	let height = u16::from_be_bytes([data[8], data[9]]) as u32;
+	let depth = data[10];
+	let planes = data[11] as usize;
+	if depth != 1 {
+		return Err(ImageError::Decode(format!("unsupported bit depth {}", depth)).into());
+	}
+	if !width.is_multiple_of(8) {
+		return Err(ImageError::Decode(format!("width {} is not a multiple of eight", width)).into());
+	}
+	if planes != 1 && planes != 2 {
+		return Err(ImageError::Decode(format!("unsupported number of planes {}", planes)).into());
+	}
+	if width as usize != panel.width || height as usize != panel.height {
+		return Err(ImageError::size(width as usize, height as usize, panel).into());
+	}
+
+	let plane_size = (width / 8 * height) as usize;
+	let payload = if flags & FLAG_COMPRESSED != 0 {
+		let mut payload = Vec::with_capacity(plane_size * planes);
+		ZlibDecoder::new(&data[HEADER_SIZE..])
+			.take((plane_size * planes) as u64 + 1)
+			.read_to_end(&mut payload)
+			.map_err(|e| ImageError::Decode(format!("cannot decompress raw image: {}", e)))?;
+		payload
+	} else {
+		data[HEADER_SIZE..].to_vec()
+	};
+
+	if payload.len() != plane_size * planes {
+		return Err(ImageError::Decode(format!(
+			"raw image payload is {} bytes, expected {} for {}x{} with {} plane(s)",
+			payload.len(),
+			plane_size * planes,
+			width,
+			height,
+			planes
+		))
+		.into());
+	}
+
+	Ok(Bitmap {
+		width,
+		height,
+		black: payload[0..plane_size].to_vec(),
+		color: if planes == 2 {
+			Some(payload[plane_size..].to_vec())
+		} else {
+			None
+		},
+	})
+}
+
+pub fn encode(bitmap: &Bitmap, compress: bool) -> Result<Vec<u8>, Box<dyn Error>> {
+	if !bitmap.width.is_multiple_of(8) || bitmap.width > u16::MAX as u32 || bitmap.height > u16::MAX as u32 {
+		return Err(format!("cannot encode a {}x{} image", bitmap.width, bitmap.height).into());
+	}
+
+	let mut data = Vec::with_capacity(HEADER_SIZE + bitmap.black.len() * 2);
+	data.extend_from_slice(MAGIC);
+	data.push(VERSION);
+	data.push(if compress { FLAG_COMPRESSED } else { 0 });
+	data.extend_from_slice(&(bitmap.width as u16).to_be_bytes());
+	data.extend_from_slice(&(bitmap.height as u16).to_be_bytes());
+	data.push(1);
+	data.push(if bitmap.color.is_some() { 2 } else { 1 });
+
+	if compress {
+		let mut encoder = ZlibEncoder::new(data, Compression::best());
+		encoder.write_all(&bitmap.black)?;
+		if let Some(color) = &bitmap.color {
+			encoder.write_all(color)?;
+		}
+		Ok(encoder.finish()?)
+	} else {
+		data.extend_from_slice(&bitmap.black);
+		if let Some(color) = &bitmap.color {
+			data.extend_from_slice(color);
+		}
+		Ok(data)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn panel() -> Panel {
+		Panel {
+			width: 16,
+			height: 3,
+			kind: "test".to_string(),
+		}
+	}
+
+	fn bitmap(color: bool) -> Bitmap {
+		Bitmap {
+			width: 16,
+			height: 3,
+			black: vec![0x80, 0x01, 0xff, 0x00, 0x55, 0xaa],
+			color: if color {
+				Some(vec![0x01, 0x80, 0x00, 0xff, 0xaa, 0x55])
+			} else {
+				None
+			},
+		}
+	}
+
+	#[test]
+	fn round_trip() {
+		for &compress in &[false, true] {
+			for &color in &[false, true] {
+				let original = bitmap(color);
+				let data = encode(&original, compress).unwrap();
+				assert!(is_raw(&data));
+				assert_eq!(data[5], if compress { FLAG_COMPRESSED } else { 0 });
+				let decoded = decode(&data, &panel()).unwrap();
+				assert_eq!(decoded.width, original.width);
+				assert_eq!(decoded.height, original.height);
+				assert_eq!(decoded.black, original.black);
+				assert_eq!(decoded.color, original.color);
+			}
+		}
+	}
+
+	#[test]
+	fn rejects_bad_headers() {
+		let data = encode(&bitmap(true), false).unwrap();
+		let with = |offset: usize, value: u8| {
+			let mut data = data.clone();
+			data[offset] = value;
+			decode(&data, &panel())
+		};
+
+		let error = with(0, b'X').unwrap_err();
+		assert!(matches!(
+			error.downcast_ref::<ImageError>(),
+			Some(ImageError::Decode(_))
+		));
+		assert!(with(4, 2).is_err());
+		assert!(with(10, 8).is_err());
+		assert!(with(11, 0).is_err());
+		assert!(with(11, 3).is_err());
+		assert!(decode(&data[..HEADER_SIZE - 1], &panel()).is_err());
+	}
+
+	#[test]
+	fn rejects_other_sizes_before_reading_the_payload() {
+		// A tiny compressed image that claims to be huge
+		let mut data = encode(&bitmap(true), true).unwrap();
+		data[6..8].copy_from_slice(&65528u16.to_be_bytes());
+		data[8..10].copy_from_slice(&65535u16.to_be_bytes());
+		let error = decode(&data, &panel()).unwrap_err();
+		assert!(matches!(
+			error.downcast_ref::<ImageError>(),
+			Some(ImageError::Size { .. })
+		));
+		assert!(error.to_string().contains("65528x65535"), "{}", error);
+
+		let other = Panel {
+			width: 8,
+			height: 3,
+			kind: "test".to_string(),
+		};
+		assert!(decode(&encode(&bitmap(false), false).unwrap(), &other).is_err());
+	}
+
+	#[test]
+	fn rejects_truncated_payloads() {
+		for &compress in &[false, true] {
+			let data = encode(&bitmap(true), compress).unwrap();
+			assert!(decode(&data[..data.len() - 1], &panel()).is_err());
+			if !compress {
+				let mut longer = data.clone();
+				longer.push(0);
+				assert!(decode(&longer, &panel()).is_err());
+			}
+		}
+		// Only the black plane, while the header says there are two
+		let mut data = encode(&bitmap(false), false).unwrap();
+		data[11] = 2;
+		assert!(decode(&data, &panel()).is_err());
+	}
+}
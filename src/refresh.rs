@@ -0,0 +1,196 @@
+use chrono::prelude::*;
+use reqwest::header::{HeaderMap, AGE, CACHE_CONTROL, DATE, EXPIRES, RETRY_AFTER};
+use std::time::Duration;
+
+/** Custom header with which a server can say in how many seconds the image should be fetched again */
+pub const SPARKBOARD_REFRESH: &str = "X-Sparkboard-Refresh";
+
+/** Determine when the server wants us to fetch again, based on the response headers. In order of preference:
+X-Sparkboard-Refresh, Retry-After, Cache-Control max-age and Expires. */
+pub fn refresh_hint(headers: &HeaderMap) -> Option<Duration> {
+	let now = Utc::now();
+	header_str(headers, SPARKBOARD_REFRESH)
+		.and_then(parse_seconds)
+		.or_else(|| retry_after(headers))
+		.or_else(|| max_age(headers))
+		.or_else(|| expires(headers, now))
+}
+
+/** Keep a refresh hint from the server between the configured minimum and maximum interval */
+pub fn bounded(hint: Duration, min: Duration, max: Duration) -> Duration {
+	hint.max(min).min(max)
+}
+
+/** Value of the Retry-After header (either a number of seconds or an HTTP date) */
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+	let value = header_str(headers, RETRY_AFTER.as_str())?;
+	parse_seconds(value).or_else(|| until(parse_http_date(value)?, server_now(headers, Utc::now())))
+}
+
+fn max_age(headers: &HeaderMap) -> Option<Duration> {
+	let cache_control = header_str(headers, CACHE_CONTROL.as_str())?;
+	let directives: Vec<&str> = cache_control.split(',').map(|d| d.trim()).collect();
+	if directives
+		.iter()
+		.any(|d| d.eq_ignore_ascii_case("no-cache") || d.eq_ignore_ascii_case("no-store"))
+	{
+		return None;
+	}
+
+	let max_age = directives.iter().find_map(|d| {
+		let (name, value) = d.split_at(d.find('=')?);
+		if name.trim().eq_ignore_ascii_case("max-age") {
+			parse_seconds(&value[1..])
+		} else {
+			None
+		}
+	})?;
+
+	// The response may have been sitting in a cache for a while already
+	let age = header_str(headers, AGE.as_str())
+		.and_then(parse_seconds)
+		.unwrap_or_default();
+	Some(max_age.checked_sub(age).unwrap_or_default())
+}
+
+fn expires(headers: &HeaderMap, now: DateTime<Utc>) -> Option<Duration> {
+	let expires = parse_http_date(header_str(headers, EXPIRES.as_str())?)?;
+	until(expires, server_now(headers, now))
+}
+
+/** The server's idea of the current time (from the Date header), so differences in clocks do not matter */
+fn server_now(headers: &HeaderMap, now: DateTime<Utc>) -> DateTime<Utc> {
+	header_str(headers, DATE.as_str())
+		.and_then(parse_http_date)
+		.unwrap_or(now)
+}
+
+fn until(time: DateTime<Utc>, now: DateTime<Utc>) -> Option<Duration> {
+	Some(time.signed_duration_since(now).to_std().unwrap_or_default())
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+	headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+fn parse_seconds(value: &str) -> Option<Duration> {
+	value.trim().parse::<u64>().ok().map(Duration::from_secs)
+}
+
+/** HTTP dates look like 'Sun, 06 Nov 1994 08:49:37 GMT', which is valid RFC 2822 */
+fn parse_http_date(value: &str) -> Option<DateTime<Utc>> {
+	DateTime::parse_from_rfc2822(value.trim())
+		.ok()
+		.map(|d| d.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use reqwest::header::HeaderValue;
+
+	const DATE_VALUE: &str = "Sun, 06 Nov 1994 08:49:37 GMT";
+
+	fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+		let mut headers = HeaderMap::new();
+		for (name, value) in pairs {
+			headers.insert(*name, HeaderValue::from_static(value));
+		}
+		headers
+	}
+
+	#[test]
+	fn reads_max_age() {
+		let hint = refresh_hint(&headers(&[("cache-control", "public, max-age=300")]));
+		assert_eq!(hint, Some(Duration::from_secs(300)));
+
+		let hint = refresh_hint(&headers(&[("cache-control", "max-age=300"), ("age", "100")]));
+		assert_eq!(hint, Some(Duration::from_secs(200)));
+
+		let hint = refresh_hint(&headers(&[("cache-control", "max-age=300"), ("age", "400")]));
+		assert_eq!(hint, Some(Duration::from_secs(0)));
+
+		assert_eq!(
+			refresh_hint(&headers(&[("cache-control", "no-cache, max-age=300")])),
+			None
+		);
+		assert_eq!(refresh_hint(&headers(&[("cache-control", "no-store")])), None);
+	}
+
+	#[test]
+	fn reads_retry_after() {
+		assert_eq!(
+			retry_after(&headers(&[("retry-after", "120")])),
+			Some(Duration::from_secs(120))
+		);
+		assert_eq!(
+			retry_after(&headers(&[
+				("retry-after", "Sun, 06 Nov 1994 08:51:37 GMT"),
+				("date", DATE_VALUE)
+			])),
+			Some(Duration::from_secs(120))
+		);
+		// A date in the past means right away
+		assert_eq!(
+			retry_after(&headers(&[
+				("retry-after", "Sun, 06 Nov 1994 08:48:37 GMT"),
+				("date", DATE_VALUE)
+			])),
+			Some(Duration::from_secs(0))
+		);
+		assert_eq!(retry_after(&headers(&[])), None);
+	}
+
+	#[test]
+	fn reads_expires() {
+		let hint = refresh_hint(&headers(&[
+			("expires", "Sun, 06 Nov 1994 09:49:37 GMT"),
+			("date", DATE_VALUE),
+		]));
+		assert_eq!(hint, Some(Duration::from_secs(3600)));
+
+		let hint = refresh_hint(&headers(&[
+			("expires", "Sun, 06 Nov 1994 07:49:37 GMT"),
+			("date", DATE_VALUE),
+		]));
+		assert_eq!(hint, Some(Duration::from_secs(0)));
+	}
+
+	#[test]
+	fn prefers_headers_in_order() {
+		let all = [
+			(SPARKBOARD_REFRESH, "10"),
+			("retry-after", "20"),
+			("cache-control", "max-age=30"),
+			("expires", "Sun, 06 Nov 1994 09:49:37 GMT"),
+			("date", DATE_VALUE),
+		];
+		assert_eq!(refresh_hint(&headers(&all)), Some(Duration::from_secs(10)));
+		assert_eq!(refresh_hint(&headers(&all[1..])), Some(Duration::from_secs(20)));
+		assert_eq!(refresh_hint(&headers(&all[2..])), Some(Duration::from_secs(30)));
+		assert_eq!(refresh_hint(&headers(&all[3..])), Some(Duration::from_secs(3600)));
+	}
+
+	#[test]
+	fn ignores_invalid_values() {
+		for value in &["-5", "soon", "1.5", ""] {
+			let mut map = headers(&[("cache-control", "max-age=30")]);
+			map.insert(SPARKBOARD_REFRESH, HeaderValue::from_static(value));
+			map.insert(RETRY_AFTER, HeaderValue::from_static(value));
+			map.insert(EXPIRES, HeaderValue::from_static(value));
+			assert_eq!(refresh_hint(&map), Some(Duration::from_secs(30)), "{:?}", value);
+		}
+		assert_eq!(refresh_hint(&headers(&[("cache-control", "max-age=-30")])), None);
+		assert_eq!(refresh_hint(&headers(&[("cache-control", "max-age")])), None);
+		assert_eq!(refresh_hint(&headers(&[("expires", "0")])), None);
+	}
+
+	#[test]
+	fn bounds_hints() {
+		let min = Duration::from_secs(10);
+		let max = Duration::from_secs(3600);
+		assert_eq!(bounded(Duration::from_secs(0), min, max), min);
+		assert_eq!(bounded(Duration::from_secs(60), min, max), Duration::from_secs(60));
+		assert_eq!(bounded(Duration::from_secs(86400), min, max), max);
+	}
+}
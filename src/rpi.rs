@@ -0,0 +1,57 @@
+/*! Raspberry Pi GPIO through rppal */
+use crate::epd::{InputLine, OutputLine, Pins, EPD};
+use crate::spi;
+use rppal::gpio::{Gpio, InputPin, OutputPin, Pin};
+use std::convert::TryFrom;
+use std::error::Error;
+
+impl OutputLine for OutputPin {
+	fn set_high(&mut self) -> Result<(), Box<dyn Error>> {
+		OutputPin::set_high(self);
+		Ok(())
+	}
+
+	fn set_low(&mut self) -> Result<(), Box<dyn Error>> {
+		OutputPin::set_low(self);
+		Ok(())
+	}
+}
+
+impl InputLine for InputPin {
+	fn is_high(&mut self) -> Result<bool, Box<dyn Error>> {
+		Ok(InputPin::is_high(self))
+	}
+}
+
+/** Open a display connected to the given SPI device, with its pins given as BCM numbers */
+pub fn open(spi_path: &str, pins: &Pins) -> Result<EPD, Box<dyn Error>> {
+	let gpio = Gpio::new()?;
+	let cs = match pins.cs {
+		Some(cs) => Some(output(&gpio, cs)?),
+		None => None,
+	};
+	spi::connect(
+		spi_path,
+		input(&gpio, pins.busy)?,
+		cs,
+		output(&gpio, pins.dc)?,
+		output(&gpio, pins.rst)?,
+	)
+}
+
+/** Claim a pin (by BCM number) as output */
+pub fn output(gpio: &Gpio, number: u32) -> Result<Box<dyn OutputLine>, Box<dyn Error>> {
+	Ok(Box::new(pin(gpio, number)?.into_output()))
+}
+
+/** Claim a pin (by BCM number) as input */
+pub fn input(gpio: &Gpio, number: u32) -> Result<Box<dyn InputLine>, Box<dyn Error>> {
+	Ok(Box::new(pin(gpio, number)?.into_input()))
+}
+
+fn pin(gpio: &Gpio, number: u32) -> Result<Pin, Box<dyn Error>> {
+	let number = u8::try_from(number).map_err(|_| format!("invalid BCM pin number {}", number))?;
+	Ok(gpio
+		.get(number)
+		.map_err(|e| format!("could not claim pin {}: {}", number, e))?)
+}
@@ -0,0 +1,801 @@
+/*! The fetch-and-display loop, one per display (or wall) */
+use crate::archive::{self, Content};
+use crate::backoff::Backoff;
+use crate::cache;
+use crate::config::{Config, DisplayConfig, TileConfig};
+use crate::epd::EPDDisplay;
+use crate::events::{self, Event};
+use crate::fetch::{self, fetch, HttpError, Source};
+use crate::graphics::Bitmap;
+use crate::hardware::Attached;
+use crate::image::Panel;
+use crate::metrics;
+use crate::overlay::{self, Overlay};
+use crate::qr::QrOverlay;
+use crate::refresh;
+use crate::schedule::{Schedule, State};
+use crate::screen;
+use crate::service::{Command, Service};
+use crate::status;
+use crate::wall;
+use chrono::prelude::*;
+use std::error::Error;
+use std::fmt;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+
+/** A display that a runner draws on, and the part of the image it shows when it is a tile of a wall */
+struct Output {
+	attached: Attached,
+	tile: Option<TileConfig>,
+}
+
+/** Fetches images for one display (or all tiles of a wall) and shows them, retrying and showing errors as configured */
+pub struct Runner {
+	name: String,
+	outputs: Vec<Output>,
+	panel: Panel,
+	sources: Vec<Source>,
+	overlay: Option<Overlay>,
+	qr: Option<QrOverlay>,
+	interval: u64,
+	schedule: Option<Schedule>,
+	closed_text: String,
+	min_interval: Duration,
+	max_interval: Duration,
+	/** Timing of refreshes in clock mode */
+	clock: Option<Clock>,
+	/** The image the panels show, with the overlay; None while they show text */
+	shown: Option<Bitmap>,
+	/** Move on to the next source, even if the current one is failing (asked for from the status page) */
+	skip_source: bool,
+	/** A refresh was forced, so the next image is drawn even when the panels already show it (to clear ghosting) */
+	forced: bool,
+	/** Number of refreshes forced on the service when last looked at (see `Service::refreshes`) */
+	refreshes: u64,
+	backoff: Backoff,
+	error_after: u32,
+	error_grace: Option<Duration>,
+	service: Arc<Service>,
+}
+
+impl Runner {
+	/** `wifi` is the interface whose signal level the status badge shows */
+	pub fn new(
+		name: &str,
+		config: &DisplayConfig,
+		wifi: Option<&str>,
+		attached: Attached,
+		service: Arc<Service>,
+	) -> Result<Runner, Box<dyn Error>> {
+		let panel = Panel {
+			width: attached.display.width(),
+			height: attached.display.height(),
+			kind: config.kind.clone(),
+		};
+		let outputs = vec![Output { attached, tile: None }];
+		Runner::with_outputs(name, config, wifi, outputs, panel, service)
+	}
+
+	/** Span the images across the displays of the tiles of a wall, which are refreshed simultaneously */
+	pub fn wall(
+		name: &str,
+		config: &DisplayConfig,
+		wifi: Option<&str>,
+		tiles: Vec<(Attached, TileConfig)>,
+		service: Arc<Service>,
+	) -> Result<Runner, Box<dyn Error>> {
+		let sizes: Vec<_> = tiles
+			.iter()
+			.map(|(a, tile)| (tile, a.display.width() as u32, a.display.height() as u32))
+			.collect();
+		let (width, height) = wall::size(&sizes);
+		log::info!("[{}] wall of {} tiles, {}x{} pixels", name, tiles.len(), width, height);
+		let panel = Panel {
+			width: width as usize,
+			height: height as usize,
+			kind: config.kind.clone(),
+		};
+		let outputs = tiles
+			.into_iter()
+			.map(|(attached, tile)| Output {
+				attached,
+				tile: Some(tile),
+			})
+			.collect();
+		Runner::with_outputs(name, config, wifi, outputs, panel, service)
+	}
+
+	fn with_outputs(
+		name: &str,
+		config: &DisplayConfig,
+		wifi: Option<&str>,
+		outputs: Vec<Output>,
+		panel: Panel,
+		service: Arc<Service>,
+	) -> Result<Runner, Box<dyn Error>> {
+		let min_interval = Duration::from_secs(config.min_interval);
+		let max_interval = match config.max_interval {
+			Some(max) => Duration::from_secs(max),
+			None => Duration::from_secs(u64::MAX),
+		}
+		.max(min_interval);
+		let sources: Vec<Source> = config.sources.iter().map(|s| s.source()).collect::<Result<_, _>>()?;
+		let panels = outputs.iter().map(|o| o.attached.name.clone()).collect();
+		let urls: Vec<&str> = sources.iter().map(|s| s.url.as_str()).collect();
+		status::display(name, panels, &urls);
+
+		Ok(Runner {
+			name: name.to_string(),
+			outputs,
+			panel,
+			sources,
+			overlay: config.overlay(wifi),
+			qr: config.qr()?,
+			interval: config.interval,
+			schedule: config.schedule()?,
+			closed_text: config.closed_text.clone(),
+			min_interval,
+			max_interval,
+			clock: if config.clock { Some(Clock::default()) } else { None },
+			shown: None,
+			skip_source: false,
+			forced: false,
+			refreshes: service.refreshes(),
+			backoff: Backoff::new(
+				Duration::from_secs_f64(config.retry_initial),
+				Duration::from_secs_f64(config.retry_max),
+				config.retry_jitter,
+			),
+			error_after: config.error_after,
+			error_grace: config.error_grace.map(Duration::from_secs),
+			service,
+		})
+	}
+
+	/** Fetch and display images until showing them fails or the service stops (or once, without interval or schedule) */
+	pub fn run(&mut self) -> Result<(), Box<dyn Error>> {
+		// The last successfully fetched image, the time it was fetched and where from
+		let mut last_good: Option<(Bitmap, DateTime<Local>, String)> = None;
+		let mut failures: u32 = 0;
+		let mut failing_since: Option<Instant> = None;
+		let mut showing_error = false;
+		let mut showing_stale = false;
+		let mut showing_closed = false;
+		// Sources are shown in turn; one that fails is retried until it succeeds
+		let mut next_source = 0;
+
+		loop {
+			// In clock mode, work ahead so that the image for a scheduled time can be fetched and drawn by then
+			let ahead = self
+				.clock
+				.as_ref()
+				.map(|c| c.budget())
+				.unwrap_or_else(chrono::Duration::zero);
+			if let Some(state) = self.schedule.as_ref().map(|s| s.state(Utc::now() + ahead)) {
+				if state != State::Open {
+					if state == State::Closed && !showing_closed {
+						log::info!("[{}] closed; showing closed screen", self.name);
+						self.service.status(&self.name, "showing closed screen");
+						let text = self.closed_text.clone();
+						self.show_text(&text)?;
+						// What is on screen now is no longer the last good image
+						last_good = None;
+						showing_closed = true;
+					}
+					match self.schedule.as_ref().and_then(|s| s.next_event(Utc::now() + ahead)) {
+						Some(next) if self.sleep_until(next - ahead)? => continue,
+						_ => return Ok(()),
+					}
+				}
+				showing_closed = false;
+			}
+
+			let target = match &self.clock {
+				Some(clock) => match clock.next_target(self.schedule.as_ref(), Utc::now()) {
+					Some(target) => Some((target, clock.budget())),
+					None => return Ok(()),
+				},
+				None => None,
+			};
+			if let Some((target, budget)) = target {
+				if !self.sleep_until(target - budget)? {
+					return Ok(());
+				}
+			}
+			let target = target.map(|(target, _)| target);
+
+			if std::mem::take(&mut self.skip_source) && failures > 0 {
+				log::info!(
+					"[{}] skipping failing source {:?}",
+					self.name,
+					self.sources[next_source].url
+				);
+				next_source = (next_source + 1) % self.sources.len();
+			}
+			let fetching = next_source;
+			let mut source = self.sources[fetching].clone();
+			if let Some(target) = target {
+				// Let the server render the image for the time it will be shown, rather than the time of the request
+				source
+					.headers
+					.push(("X-Sparkboard-Time".to_string(), target.to_rfc3339()));
+			}
+
+			log::info!("[{}] fetching new image from {:?}", self.name, source.url);
+			self.service.status(&self.name, &format!("fetching {}", source.url));
+			let fetch_started = Instant::now();
+			match fetch(&source, &self.panel) {
+				Ok(fetched) => {
+					metrics::fetched(&self.name, fetched.bytes, fetched.download_time);
+					let event = Event {
+						source: Some(source.url.clone()),
+						duration_ms: events::millis(fetched.download_time),
+						..Event::new("fetched", Some(&self.name))
+					};
+					let message = format!(
+						"[{}] fetched {:?} in {:?}",
+						self.name, source.url, fetched.download_time
+					);
+					events::record(log::Level::Info, event, &message);
+					metrics::succeeded(&self.name);
+					let bitmap = fetched.bitmap;
+					if failures > 0 {
+						log::info!("[{}] source recovered after {} failed attempt(s)", self.name, failures);
+					}
+					failures = 0;
+					failing_since = None;
+					showing_error = false;
+					showing_stale = false;
+					self.backoff.reset();
+					next_source = (next_source + 1) % self.sources.len();
+					status::fetched(&self.name, fetching, None, next_source);
+
+					if let (Some(clock), Some(target)) = (&mut self.clock, target) {
+						clock.learn_fetch(fetch_started.elapsed());
+						let shown = target.with_timezone(&Local);
+						let frame = self.compose(&bitmap, shown);
+						let force = std::mem::take(&mut self.forced);
+						self.show_frame(frame, Some(target), &source.url, force)?;
+						last_good = Some((bitmap, shown, source.url));
+						continue;
+					}
+
+					log::info!("[{}] displaying new image", self.name);
+					let now = Local::now();
+					let frame = self.compose(&bitmap, now);
+					let force = std::mem::take(&mut self.forced);
+					self.show_frame(frame, None, &source.url, force)?;
+					last_good = Some((bitmap, now, source.url));
+
+					let sleeping = match self.schedule.as_ref().map(|s| s.next_event(Utc::now())) {
+						Some(Some(next)) => self.sleep_until(next)?,
+						Some(None) => false,
+						None if self.interval > 0 => {
+							let delay = match fetched.refresh_after {
+								Some(hint) => refresh::bounded(hint, self.min_interval, self.max_interval),
+								None => Duration::from_secs(self.interval),
+							};
+							log::info!("[{}] sleeping for {:?}", self.name, delay);
+							self.sleep_for(delay)?
+						}
+						None => false,
+					};
+					if !sleeping {
+						return Ok(());
+					}
+				}
+				Err(e) => {
+					failures += 1;
+					metrics::failed(&self.name, failures);
+					status::fetched(&self.name, fetching, Some(e.to_string()), next_source);
+					let since = *failing_since.get_or_insert_with(Instant::now);
+					let event = Event {
+						source: Some(source.url.clone()),
+						duration_ms: events::millis(fetch_started.elapsed()),
+						error_kind: Some(fetch::error_kind(e.as_ref()).to_string()),
+						error: Some(e.to_string()),
+						..Event::new("fetch_failed", Some(&self.name))
+					};
+					let message = format!(
+						"[{}] fetch of {:?} failed ({} consecutive failures): {:?}",
+						self.name, source.url, failures, e
+					);
+					events::record(log::Level::Error, event, &message);
+
+					let grace_expired = self.error_grace.map(|g| since.elapsed() >= g).unwrap_or(false);
+					// An image shown before a restart (see `cache`) counts as a good one
+					let cached = self.outputs.iter().all(|o| cache::shows_image(&o.attached.name));
+					if (last_good.is_none() && !cached) || failures >= self.error_after || grace_expired {
+						if !showing_error {
+							let text = format!("fetch failed: {:?}", e);
+							self.service.status(&self.name, "showing error");
+							self.show_text(&text)?;
+							showing_error = true;
+						}
+					} else if let (Some(overlay), Some((bitmap, updated, url))) = (&self.overlay, &last_good) {
+						// Keep showing the last good image, but redraw it once when it becomes stale so the badge warns
+						if !showing_stale && overlay.is_stale(*updated, Local::now()) {
+							log::info!("[{}] last good image is stale; redrawing with warning", self.name);
+							let frame = self.compose(bitmap, *updated);
+							self.show_frame(frame, None, url, false)?;
+							showing_stale = true;
+						}
+					}
+
+					// Honor Retry-After when the server sends it, but never retry faster than the backoff says
+					let mut delay = self.backoff.next_delay();
+					if let Some(retry_after) = e.downcast_ref::<HttpError>().and_then(|e| e.retry_after) {
+						delay = delay.max(retry_after.min(self.max_interval));
+					}
+					// Do not retry past the next scheduled change (such as closing time)
+					if let Some(next) = self.schedule.as_ref().and_then(|s| s.next_event(Utc::now())) {
+						delay = delay.min((next - Utc::now()).to_std().unwrap_or_default());
+					}
+					log::info!("[{}] sleeping for {:?} before trying again...", self.name, delay);
+					if !self.sleep_for(delay)? {
+						return Ok(());
+					}
+				}
+			}
+		}
+	}
+
+	/** The image as the panels should show it: with the status badge and QR code (if any) on top */
+	fn compose(&self, bitmap: &Bitmap, last_update: DateTime<Local>) -> Bitmap {
+		let frame = overlay::compose(bitmap, self.overlay.as_ref(), last_update);
+		match &self.qr {
+			Some(qr) => qr.compose(&frame),
+			None => frame,
+		}
+	}
+
+	/** Show an image with the overlay unless the panels show it already (and not `force`d); in clock mode, at `target` */
+	fn show_frame(
+		&mut self,
+		frame: Bitmap,
+		target: Option<DateTime<Utc>>,
+		url: &str,
+		force: bool,
+	) -> Result<(), Box<dyn Error>> {
+		if !force && self.shown.as_ref() == Some(&frame) {
+			log::info!("[{}] image did not change; not refreshing", self.name);
+			metrics::skipped_identical(&self.name);
+			// The panels do show a fetched image
+			self.service.ready();
+			return Ok(());
+		}
+		let started = Instant::now();
+		match (&mut self.clock, target) {
+			(Some(clock), Some(target)) => {
+				let service = self.service.clone();
+				if !show_on_time(&self.name, &service, &mut self.outputs, &frame, clock, target)? {
+					return Ok(());
+				}
+			}
+			_ => {
+				self.service.status(&self.name, "refreshing");
+				show_image(&mut self.outputs, &frame)?;
+			}
+		}
+		self.shown = Some(frame);
+		self.drawn(started.elapsed(), Content::Image(url));
+		Ok(())
+	}
+
+	fn show_text(&mut self, text: &str) -> Result<(), Box<dyn Error>> {
+		let started = Instant::now();
+		draw_all(&mut self.outputs, |d, _| screen::show_text(d, text, None))?;
+		self.shown = None;
+		self.drawn(started.elapsed(), Content::Text(text));
+		Ok(())
+	}
+
+	/** The panels were refreshed with `content`, which took `took` */
+	fn drawn(&self, took: Duration, content: Content) {
+		// The panel drivers only do full refreshes
+		metrics::refreshed(&self.name, "full");
+		for output in &self.outputs {
+			// Archive what was actually sent to the panel, which for a wall is a part of the image
+			if let Some(sent) = status::panel(&output.attached.name) {
+				archive::save(&self.name, &output.attached.name, &sent, content);
+				if let Content::Image(url) = content {
+					cache::save(&self.name, &output.attached.name, &sent, url);
+				}
+			}
+			let event = Event {
+				panel: Some(output.attached.name.clone()),
+				duration_ms: events::millis(took),
+				..Event::new("refreshed", Some(&self.name))
+			};
+			let message = format!("[{}] refreshed {} in {:?}", self.name, output.attached.name, took);
+			events::record(log::Level::Info, event, &message);
+		}
+		// Error and closed screens do not count as having started
+		if let Content::Image(_) = content {
+			self.service.ready();
+		}
+	}
+
+	/** Blank the panels */
+	fn clear(&mut self) -> Result<(), Box<dyn Error>> {
+		let started = Instant::now();
+		draw_all(&mut self.outputs, |d, _| screen::clear(d))?;
+		self.shown = None;
+		self.drawn(started.elapsed(), Content::Blank);
+		Ok(())
+	}
+
+	/** Returns false when the service is stopping */
+	fn sleep_until(&mut self, time: DateTime<Utc>) -> Result<bool, Box<dyn Error>> {
+		log::info!("[{}] sleeping until {}", self.name, time.with_timezone(&Local));
+		self.rest(|service, name, refreshes| service.sleep_until(name, time, refreshes))
+	}
+
+	/** Returns false when the service is stopping */
+	fn sleep_for(&mut self, duration: Duration) -> Result<bool, Box<dyn Error>> {
+		let until = Instant::now().checked_add(duration);
+		self.rest(|service, name, refreshes| match until {
+			Some(until) => service.sleep_for(name, until.saturating_duration_since(Instant::now()), refreshes),
+			None => service.sleep_for(name, duration, refreshes),
+		})
+	}
+
+	/** Sleep with `sleep` (false when stopping) until it is time to fetch again, carrying out commands meanwhile */
+	fn rest(&mut self, sleep: impl Fn(&Service, &str, u64) -> bool) -> Result<bool, Box<dyn Error>> {
+		let service = self.service.clone();
+		loop {
+			// A refresh forced while fetching or drawing ends the sleep right away
+			if !sleep(&service, &self.name, self.refreshes) {
+				return Ok(false);
+			}
+			let refreshes = service.refreshes();
+			if refreshes != self.refreshes {
+				self.refreshes = refreshes;
+				self.forced = true;
+			}
+			match service.take_command(&self.name) {
+				Some(Command::Clear) => {
+					log::info!("[{}] clearing the panels", self.name);
+					service.status(&self.name, "clearing");
+					self.clear()?;
+				}
+				Some(Command::NextSource) => {
+					self.skip_source = true;
+					return Ok(true);
+				}
+				Some(Command::Refresh) => {
+					self.forced = true;
+					return Ok(true);
+				}
+				None => return Ok(true),
+			}
+		}
+	}
+}
+
+/** Draw a packed image; walls show the part of each tile on its display */
+fn show_image(outputs: &mut [Output], bitmap: &Bitmap) -> Result<(), Box<dyn Error>> {
+	draw_all(outputs, |display, tile| match tile {
+		Some(tile) => {
+			let (width, height) = (display.width() as u32, display.height() as u32);
+			screen::show_bitmap(display, &wall::cut(bitmap, tile, width, height))
+		}
+		None => screen::show_bitmap(display, bitmap),
+	})
+}
+
+/** Like `show_image`, but prepare the image first and start drawing just early enough for it to appear at `target`. The
+panels are only put to sleep once all of them show the image, and the drift from `target` is logged. Only stopping
+cuts the wait short, in which case it returns false without drawing. */
+fn show_on_time(
+	name: &str,
+	service: &Service,
+	outputs: &mut [Output],
+	bitmap: &Bitmap,
+	clock: &mut Clock,
+	target: DateTime<Utc>,
+) -> Result<bool, Box<dyn Error>> {
+	// The parts of a wall, by the name of the display of their tile
+	let parts: Vec<(String, Bitmap)> = outputs
+		.iter()
+		.filter_map(|output| {
+			let tile = output.tile.as_ref()?;
+			let display = &output.attached.display;
+			let part = wall::cut(bitmap, tile, display.width() as u32, display.height() as u32);
+			Some((tile.display.clone(), part))
+		})
+		.collect();
+
+	// Commands and forced refreshes are carried out once the image is shown
+	if !service.wait_until(name, target - clock.lead()) {
+		return Ok(false);
+	}
+	service.status(name, "refreshing");
+	let started = Instant::now();
+	let visible = Mutex::new(started);
+	draw_all(outputs, |display, tile| {
+		let part = match tile {
+			Some(tile) => {
+				&parts
+					.iter()
+					.find(|(name, _)| *name == tile.display)
+					.expect("cut above")
+					.1
+			}
+			None => bitmap,
+		};
+		screen::draw_bitmap(display, part)?;
+		let now = Instant::now();
+		let mut visible = visible.lock().unwrap();
+		*visible = (*visible).max(now);
+		drop(visible);
+		sleep(Duration::from_millis(500));
+		display.sleep()
+	})?;
+
+	// The image is visible once the slowest panel has finished
+	let visible = visible.into_inner().unwrap();
+	clock.learn_lead(visible - started);
+	let drift = Utc::now()
+		- chrono::Duration::from_std(visible.elapsed()).unwrap_or_else(|_| chrono::Duration::zero())
+		- target;
+	log::info!(
+		"[{}] image for {} visible {:+} ms from target",
+		name,
+		target.with_timezone(&Local).format("%H:%M:%S"),
+		drift.num_milliseconds()
+	);
+	Ok(true)
+}
+
+/** Drawing on a panel failed */
+#[derive(Debug)]
+struct PanelError {
+	panel: String,
+	error: String,
+}
+
+impl fmt::Display for PanelError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(&self.error)
+	}
+}
+
+impl Error for PanelError {}
+
+/** Draw on every output at the same time, each while holding the lock of its display. `draw` is given the display and
+the tile it shows, if any. */
+fn draw_all<F>(outputs: &mut [Output], draw: F) -> Result<(), Box<dyn Error>>
+where
+	F: Fn(&mut dyn EPDDisplay, Option<&TileConfig>) -> Result<(), Box<dyn Error>> + Sync,
+{
+	if let [output] = outputs {
+		let tile = output.tile.as_ref();
+		return output.attached.locked(|display| draw(display, tile)).map_err(|e| {
+			PanelError {
+				panel: output.attached.name.clone(),
+				error: e.to_string(),
+			}
+			.into()
+		});
+	}
+
+	let draw = &draw;
+	let errors: Vec<PanelError> = thread::scope(|scope| {
+		let handles: Vec<_> = outputs
+			.iter_mut()
+			.map(|output| {
+				scope.spawn(move || {
+					let tile = output.tile.as_ref();
+					output
+						.attached
+						.locked(|display| draw(display, tile))
+						.map_err(|e| PanelError {
+							panel: output.attached.name.clone(),
+							error: e.to_string(),
+						})
+				})
+			})
+			.collect();
+		handles
+			.into_iter()
+			.filter_map(|handle| handle.join().expect("drawing thread panicked").err())
+			.collect()
+	});
+	match errors.into_iter().next() {
+		Some(e) => Err(e.into()),
+		None => Ok(()),
+	}
+}
+
+/** Create a runner for every display that has sources, drawing on the opened displays (as returned by
+`Hardware::open_all`) */
+pub fn runners(
+	config: &Config,
+	mut displays: Vec<Option<Attached>>,
+	service: &Arc<Service>,
+) -> Result<Vec<Runner>, Box<dyn Error>> {
+	status::clear_displays();
+	let mut runners = vec![];
+	for (index, display) in config.displays.iter().enumerate() {
+		if display.sources.is_empty() {
+			continue;
+		}
+		let name = config.display_name(index);
+		let wifi = config.wifi.as_deref();
+		let runner = if display.is_wall() {
+			let tiles = display
+				.tiles
+				.iter()
+				.map(|tile| {
+					let index = config.display_index(&tile.display).expect("validated");
+					let attached = displays[index].take().expect("tile opened");
+					(attached, tile.clone())
+				})
+				.collect();
+			Runner::wall(&name, display, wifi, tiles, service.clone())?
+		} else {
+			Runner::new(
+				&name,
+				display,
+				wifi,
+				displays[index].take().expect("display opened"),
+				service.clone(),
+			)?
+		};
+		runners.push(runner);
+	}
+	Ok(runners)
+}
+
+/** Run each runner on its own thread. Returns the first error, or Ok once all runners have finished. */
+pub fn run_all(runners: Vec<Runner>) -> Result<(), Box<dyn Error>> {
+	let service = match runners.first() {
+		Some(runner) => runner.service.clone(),
+		None => return Ok(()),
+	};
+	let (sender, receiver) = mpsc::channel();
+	let mut threads = Vec::with_capacity(runners.len());
+	let mut result = Ok(());
+	for mut runner in runners {
+		let sender = sender.clone();
+		let spawned = thread::Builder::new().name(runner.name.clone()).spawn(move || {
+			// Only drawing can make a runner fail
+			let result = runner.run().map_err(|e| {
+				let event = Event {
+					panel: e.downcast_ref::<PanelError>().map(|e| e.panel.clone()),
+					error_kind: Some("panel".to_string()),
+					error: Some(e.to_string()),
+					..Event::new("refresh_failed", Some(&runner.name))
+				};
+				events::record(
+					log::Level::Error,
+					event,
+					&format!("[{}] refresh failed: {}", runner.name, e),
+				);
+				format!("display {}: {}", runner.name, e)
+			});
+			let (service, name) = (runner.service.clone(), runner.name.clone());
+			// Let go of the panels (which puts them to sleep) before the process may exit or open them again
+			drop(runner);
+			service.finish(&name);
+			let _ = sender.send(result);
+		});
+		match spawned {
+			Ok(thread) => threads.push(thread),
+			Err(e) => {
+				result = Err(e.into());
+				break;
+			}
+		}
+	}
+	drop(sender);
+
+	if result.is_ok() {
+		for _ in 0..threads.len() {
+			match receiver.recv() {
+				Ok(Ok(())) => {}
+				Ok(Err(e)) => {
+					result = Err(e.into());
+					break;
+				}
+				Err(e) => {
+					result = Err(e.into());
+					break;
+				}
+			}
+		}
+	}
+	// When one runner fails, stop the others, and only return once all have finished drawing and let go of the panels
+	if result.is_err() {
+		service.stop();
+	}
+	for thread in threads {
+		let _ = thread.join();
+	}
+	result
+}
+
+/** Timing of refreshes that should appear right on the minute (or at the times of the schedule), learned from how long
+earlier fetches and refreshes took */
+#[derive(Default)]
+struct Clock {
+	/** Time from starting to draw until the image is visible on all panels */
+	lead: Option<Duration>,
+	/** Time taken to fetch an image */
+	fetch: Option<Duration>,
+}
+
+impl Clock {
+	/** Guess for the time a full refresh takes, until one has been measured */
+	const INITIAL_LEAD: Duration = Duration::from_secs(5);
+	/** Guess for the time a fetch takes, until one has been measured */
+	const INITIAL_FETCH: Duration = Duration::from_secs(2);
+	/** Time to spare between fetching and drawing, for fetches that are slower than usual */
+	const MARGIN: Duration = Duration::from_secs(1);
+
+	fn lead(&self) -> chrono::Duration {
+		to_chrono(self.lead.unwrap_or(Clock::INITIAL_LEAD))
+	}
+
+	/** How long before the target time to start fetching */
+	fn budget(&self) -> chrono::Duration {
+		self.lead() + to_chrono(self.fetch.unwrap_or(Clock::INITIAL_FETCH) + Clock::MARGIN)
+	}
+
+	/** The next time an image should appear that leaves enough time to fetch and draw it; None if there is none */
+	fn next_target(&self, schedule: Option<&Schedule>, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+		// The margin may be used up, so that right after waking up for a target it is still the next one
+		let earliest = now + self.budget() - to_chrono(Clock::MARGIN);
+		match schedule {
+			Some(schedule) => schedule.next_event(earliest),
+			None => Some(next_minute(earliest)),
+		}
+	}
+
+	fn learn_fetch(&mut self, took: Duration) {
+		self.fetch = Some(average(self.fetch, took));
+	}
+
+	fn learn_lead(&mut self, took: Duration) {
+		self.lead = Some(average(self.lead, took));
+	}
+}
+
+/** Average out measured durations, so one slow measurement does not throw off the timing */
+fn average(previous: Option<Duration>, took: Duration) -> Duration {
+	match previous {
+		Some(previous) => (previous + took) / 2,
+		None => took,
+	}
+}
+
+fn to_chrono(duration: Duration) -> chrono::Duration {
+	chrono::Duration::from_std(duration).unwrap_or_else(|_| chrono::Duration::max_value())
+}
+
+/** The start of the first minute after `t` */
+fn next_minute(t: DateTime<Utc>) -> DateTime<Utc> {
+	let minute = t.with_second(0).and_then(|t| t.with_nanosecond(0)).expect("valid time");
+	minute + chrono::Duration::minutes(1)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn clock_targets_minutes_it_can_make() {
+		let mut clock = Clock::default();
+		clock.learn_lead(Duration::from_secs(4));
+		clock.learn_fetch(Duration::from_secs(1));
+		// Fetching, drawing and the margin take 6 seconds
+		let at = |h, m, s| Utc.ymd(2021, 1, 4).and_hms(h, m, s);
+		assert_eq!(clock.next_target(None, at(12, 0, 30)), Some(at(12, 1, 0)));
+		assert_eq!(clock.next_target(None, at(12, 0, 54)), Some(at(12, 1, 0)));
+		assert_eq!(clock.next_target(None, at(12, 0, 56)), Some(at(12, 2, 0)));
+		assert_eq!(at(12, 1, 0) - clock.budget(), at(12, 0, 54));
+	}
+}
@@ -0,0 +1,202 @@
+/*! Running displays as a service: what the `run` and `fetch` commands do.
+
+`run` opens the displays of a configuration, shows what they showed before (see `cache`), waits for the network if
+asked to (showing its state and the Wi-Fi QR code meanwhile), and then hands the displays to runners until the service
+stops. On SIGHUP it starts over with the configuration loaded again; the web server keeps running across reloads. */
+use crate::archive::{self, Content};
+use crate::cache;
+use crate::config::Config;
+use crate::events::{self, Event};
+use crate::hardware::{Attached, Hardware};
+use crate::metrics;
+use crate::network;
+use crate::qr::Qr;
+use crate::runner;
+use crate::screen;
+use crate::service::Service;
+use crate::status;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/** Open all displays and fetch and display images on each of them until told to stop (or once, when a display has no
+interval). On SIGHUP, the displays are opened again with the configuration `load` gives then. */
+pub fn run(load: impl Fn() -> Result<Config, Box<dyn Error>>) -> Result<(), Box<dyn Error>> {
+	let service = Service::new();
+	service.handle_signals()?;
+	service.start_watchdog()?;
+	let mut config = load()?;
+	status::config(&config);
+	events::open_log(config.event_log.as_deref())?;
+	archive::open(config.archive.as_ref())?;
+	cache::open(config.cache.as_deref())?;
+	events::record(log::Level::Info, Event::new("started", None), "started");
+	// The server keeps running across reloads, on the address it was first started on
+	if let Some(address) = &config.listen {
+		serve(address, &service)?;
+	}
+	loop {
+		run_displays(&config, &service)?;
+		if !service.take_reload() {
+			events::record(log::Level::Info, Event::new("stopped", None), "stopped");
+			return Ok(());
+		}
+		match load() {
+			Ok(reloaded) => {
+				config = reloaded;
+				status::config(&config);
+				if let Err(e) = events::open_log(config.event_log.as_deref()) {
+					log::error!("{}", e);
+				}
+				if let Err(e) = archive::open(config.archive.as_ref()) {
+					log::error!("{}", e);
+				}
+				if let Err(e) = cache::open(config.cache.as_deref()) {
+					log::error!("{}", e);
+				}
+			}
+			Err(e) => log::error!("could not reload configuration, keeping the current one: {}", e),
+		}
+	}
+}
+
+#[cfg(feature = "server")]
+fn serve(address: &str, service: &Arc<Service>) -> Result<(), Box<dyn Error>> {
+	crate::server::start(address, service.clone())
+}
+
+#[cfg(not(feature = "server"))]
+fn serve(_address: &str, _service: &Arc<Service>) -> Result<(), Box<dyn Error>> {
+	Err("built without the web server (feature 'server')".into())
+}
+
+/** Run all displays until the service stops or reloads. The panels are asleep by the time this returns. */
+pub fn run_displays(config: &Config, service: &Arc<Service>) -> Result<(), Box<dyn Error>> {
+	let mut displays = Hardware::new().open_all(config)?;
+	show_cached(&mut displays, config)?;
+
+	if let Some(wifi) = &config.wifi {
+		log::info!("wait for Wi-Fi device {:?}...", wifi);
+		let qr = match &config.wifi_qr {
+			Some(text) => Some(Qr::new(text)?),
+			None => None,
+		};
+		let started = Instant::now();
+		wait_for_wifi(&mut displays, config, wifi, qr.as_ref(), service)?;
+		metrics::waited_for_wifi(started.elapsed());
+		if service.is_stopping() {
+			return Ok(());
+		}
+	}
+
+	let runners = runner::runners(config, displays, service)?;
+	runner::run_all(runners)
+}
+
+/** Wait until the network is up (as `config.wifi_check` tells), showing its state on the displays whenever it changes.
+Gives up after `config.wifi_timeout`, leaving it to the runners to deal with the network being down. */
+pub fn wait_for_wifi(
+	displays: &mut [Option<Attached>],
+	config: &Config,
+	interface: &str,
+	qr: Option<&Qr>,
+	service: &Service,
+) -> Result<(), Box<dyn Error>> {
+	let source = match config.displays.iter().find_map(|d| d.sources.first()) {
+		Some(source) => Some(source.source()?),
+		None => None,
+	};
+	let started = Instant::now();
+	let mut shown: Option<String> = None;
+	loop {
+		let state = match network::check(config.wifi_check, interface, &config.wpa_control, source.as_ref()) {
+			Ok(readiness) if readiness.ready => {
+				log::info!("network on {} is up ({})", interface, readiness.state);
+				break;
+			}
+			Ok(readiness) => readiness.state,
+			Err(e) => e.to_string(),
+		};
+		if let Some(timeout) = config.wifi_timeout {
+			if started.elapsed() >= Duration::from_secs(timeout) {
+				log::warn!(
+					"network on {} is still not up after {} s ({}); starting anyway",
+					interface,
+					timeout,
+					state
+				);
+				break;
+			}
+		}
+		if shown.as_ref() != Some(&state) {
+			log::info!("waiting for Wi-Fi on {}: {}", interface, state);
+			service.status(interface, &format!("waiting for Wi-Fi: {}", state));
+			show_text_on_all(displays, config, &format!("Waiting for Wi-Fi: {}", state), qr)?;
+			shown = Some(state);
+		}
+		if !service.sleep_for(interface, Duration::from_secs(config.wifi_poll), service.refreshes()) {
+			return Ok(());
+		}
+	}
+	service.finish(interface);
+	Ok(())
+}
+
+/** Show the images the panels showed before (see `cache`) until new ones are fetched, unless they still show them */
+pub fn show_cached(displays: &mut [Option<Attached>], config: &Config) -> Result<(), Box<dyn Error>> {
+	for (index, attached) in displays.iter_mut().enumerate() {
+		let attached = match attached {
+			Some(attached) => attached,
+			None => continue,
+		};
+		let (entry, bitmap) = match cache::cached(&attached.name) {
+			Some(cached) => cached,
+			None => continue,
+		};
+		let size = (attached.display.width() as u32, attached.display.height() as u32);
+		if entry.shows {
+			log::info!(
+				"[{}] still shows the image from {} fetched at {}",
+				attached.name,
+				entry.source,
+				entry.time.format("%F %T")
+			);
+		} else if (bitmap.width, bitmap.height) == size {
+			log::info!(
+				"[{}] showing the image from {} fetched at {} until a new one is fetched",
+				attached.name,
+				entry.source,
+				entry.time.format("%F %T")
+			);
+			attached.locked(|display| screen::show_bitmap(display, &bitmap))?;
+			archive_drawn(config, index, attached, Content::Image(&entry.source));
+		}
+	}
+	Ok(())
+}
+
+/** Show the same text on every display, except those that show the image they showed before (see `show_cached`) */
+pub fn show_text_on_all(
+	displays: &mut [Option<Attached>],
+	config: &Config,
+	text: &str,
+	qr: Option<&Qr>,
+) -> Result<(), Box<dyn Error>> {
+	for (index, attached) in displays.iter_mut().enumerate() {
+		let attached = match attached {
+			Some(attached) if !cache::shows_image(&attached.name) => attached,
+			_ => continue,
+		};
+		attached.locked(|display| screen::show_text(display, text, qr))?;
+		archive_drawn(config, index, attached, Content::Text(text));
+	}
+	Ok(())
+}
+
+/** Archive what the panel of the display at `index` was just sent, like runners do for the frames they draw */
+fn archive_drawn(config: &Config, index: usize, attached: &Attached, content: Content) {
+	if let Some(sent) = status::panel(&attached.name) {
+		let display = config.display_name(config.wall_of(index).unwrap_or(index));
+		archive::save(&display, &attached.name, &sent, content);
+	}
+}
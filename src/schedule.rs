@@ -0,0 +1,451 @@
+/*! When to refresh a display: cron expressions or fixed intervals within time windows, and closed hours.
+
+A schedule is a list of rules. At any moment the first rule whose window (days of the week and times of day) contains
+the current time applies; when no rule applies, the display is left alone. Rules refresh at the times given by a cron
+expression, or every so many seconds aligned to the clock (`every = 300` refreshes at :00, :05, :10...). A closed rule
+shows a fixed screen and puts the panel to sleep until the window ends; the display is refreshed as soon as it opens.
+
+````toml
+[[display.schedule]]
+days = ["mon", "tue", "wed", "thu", "fri"]
+from = "08:00"
+until = "18:00"
+every = 300
+
+[[display.schedule]]
+from = "22:00"
+until = "06:00"
+closed = true
+
+[[display.schedule]]
+cron = "0 0 * * * *" # hourly otherwise (seconds, minutes, hours, day of month, month, day of week)
+```` */
+use chrono::prelude::*;
+use chrono::LocalResult;
+use chrono_tz::Tz;
+use serde::Deserialize;
+use std::error::Error;
+use std::str::FromStr;
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/** A schedule rule as written in the configuration */
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuleConfig {
+	/** Days of the week the rule applies to ('mon', 'tue'...); every day when empty */
+	pub days: Vec<String>,
+	/** Start of the window ('HH:MM'); a window that ends before it starts runs past midnight */
+	pub from: Option<String>,
+	/** End of the window ('HH:MM', exclusive) */
+	pub until: Option<String>,
+	/** Cron expression with seconds ('0 0/5 * * * *') giving the times to refresh at */
+	pub cron: Option<String>,
+	/** Refresh every this many seconds, counted from midnight as shown on the clock (also when it is changed for DST) */
+	pub every: Option<u64>,
+	/** Show the closed screen and let the panel sleep */
+	pub closed: bool,
+}
+
+#[derive(Debug, Clone)]
+enum Action {
+	Cron(Box<cron::Schedule>),
+	Every(u64),
+	Closed,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+	/** Days the window starts on; None for every day */
+	days: Option<Vec<Weekday>>,
+	/** Window in minutes since midnight; `until` may be 1440 (midnight) and is before `from` for overnight windows */
+	from: u32,
+	until: u32,
+	action: Action,
+}
+
+impl Rule {
+	fn parse(config: &RuleConfig) -> Result<Rule, Box<dyn Error>> {
+		let action = match (&config.cron, config.every, config.closed) {
+			(Some(expression), None, false) => Action::Cron(Box::new(
+				cron::Schedule::from_str(expression)
+					.map_err(|e| format!("invalid cron expression {:?}: {}", expression, e))?,
+			)),
+			(None, Some(0), false) => return Err("'every' should be at least one second".into()),
+			(None, Some(every), false) => Action::Every(every),
+			(None, None, true) => Action::Closed,
+			_ => return Err("a schedule rule needs exactly one of 'cron', 'every' or 'closed'".into()),
+		};
+
+		let days = if config.days.is_empty() {
+			None
+		} else {
+			Some(
+				config
+					.days
+					.iter()
+					.map(|d| Weekday::from_str(d).map_err(|_| format!("invalid day {:?}", d)))
+					.collect::<Result<_, _>>()?,
+			)
+		};
+
+		let from = match &config.from {
+			Some(time) => parse_time(time)?,
+			None => 0,
+		};
+		let until = match &config.until {
+			Some(time) => parse_time(time)?,
+			None => MINUTES_PER_DAY,
+		};
+		if from == until {
+			return Err(format!("window from {:?} until {:?} is empty", config.from, config.until).into());
+		}
+
+		Ok(Rule {
+			days,
+			from,
+			until,
+			action,
+		})
+	}
+
+	fn starts_on(&self, day: Weekday) -> bool {
+		self.days.as_ref().map(|days| days.contains(&day)).unwrap_or(true)
+	}
+
+	fn contains<Z: TimeZone>(&self, t: &DateTime<Z>) -> bool {
+		let seconds = t.num_seconds_from_midnight();
+		let (from, until) = (self.from * 60, self.until * 60);
+		if from < until {
+			seconds >= from && seconds < until && self.starts_on(t.weekday())
+		} else {
+			(seconds >= from && self.starts_on(t.weekday())) || (seconds < until && self.starts_on(t.weekday().pred()))
+		}
+	}
+
+	/** Whether the window changes during the day, or differs between days */
+	fn has_window(&self) -> bool {
+		self.days.is_some() || self.from != 0 || self.until != MINUTES_PER_DAY
+	}
+
+	/** The first refresh after `t`, or None for closed rules */
+	fn next_refresh<Z: TimeZone>(&self, t: &DateTime<Z>) -> Option<DateTime<Z>> {
+		match &self.action {
+			Action::Cron(schedule) => schedule.after(t).next(),
+			Action::Every(every) => {
+				let mut date = t.date().naive_local();
+				let mut next = (t.num_seconds_from_midnight() as u64 / every + 1) * every;
+				// A time in the hour that is repeated when clocks are turned back may only come around the second time
+				loop {
+					if next >= (MINUTES_PER_DAY * 60) as u64 {
+						date = date.succ();
+						next = 0;
+					}
+					if let Some(time) = local_time_after(&t.timezone(), date, next as u32, t) {
+						return Some(time);
+					}
+					next += every;
+				}
+			}
+			Action::Closed => None,
+		}
+	}
+}
+
+/** "HH:MM" to minutes since midnight; "24:00" is allowed as the end of the day */
+fn parse_time(time: &str) -> Result<u32, Box<dyn Error>> {
+	let invalid = || format!("invalid time {:?} (expected HH:MM)", time);
+	let mut parts = time.splitn(2, ':');
+	let hours = parts.next().and_then(|h| u32::from_str(h).ok()).ok_or_else(invalid)?;
+	let minutes = parts.next().and_then(|m| u32::from_str(m).ok()).ok_or_else(invalid)?;
+	if minutes >= 60 || hours > 24 || (hours == 24 && minutes > 0) {
+		return Err(invalid().into());
+	}
+	Ok(hours * 60 + minutes)
+}
+
+/** The first time after `after` that the clock shows the given number of seconds after midnight on a date in a time
+zone (either of the two, when clocks are turned back; just after the skipped hour when they are turned forward) */
+fn local_time_after<Z: TimeZone>(
+	timezone: &Z,
+	date: NaiveDate,
+	seconds: u32,
+	after: &DateTime<Z>,
+) -> Option<DateTime<Z>> {
+	let wall = date.and_hms(0, 0, 0) + chrono::Duration::seconds(seconds as i64);
+	let time = match timezone.from_local_datetime(&wall) {
+		LocalResult::Single(time) => time,
+		LocalResult::Ambiguous(earliest, latest) => {
+			if earliest > *after {
+				earliest
+			} else {
+				latest
+			}
+		}
+		LocalResult::None => {
+			let midnight = timezone.from_local_datetime(&date.and_hms(0, 0, 0)).earliest()?;
+			midnight + chrono::Duration::seconds(seconds as i64)
+		}
+	};
+	Some(time).filter(|time| time > after)
+}
+
+/** What to do at a point in time */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+	/** Refresh at the times the applicable rule gives */
+	Open,
+	/** Show the closed screen */
+	Closed,
+	/** No rule applies; leave the display as it is */
+	Idle,
+}
+
+#[derive(Debug, Clone)]
+pub struct Schedule {
+	/** None for the system time zone */
+	timezone: Option<Tz>,
+	rules: Vec<Rule>,
+}
+
+impl Schedule {
+	/** `timezone` is a name from the tz database such as 'Europe/Amsterdam'; None for the system time zone */
+	pub fn new(timezone: Option<&str>, rules: &[RuleConfig]) -> Result<Schedule, Box<dyn Error>> {
+		Ok(Schedule {
+			timezone: match timezone {
+				Some(name) => Some(Tz::from_str(name).map_err(|_| format!("unknown time zone {:?}", name))?),
+				None => None,
+			},
+			rules: rules.iter().map(Rule::parse).collect::<Result<_, _>>()?,
+		})
+	}
+
+	pub fn state(&self, t: DateTime<Utc>) -> State {
+		match self.timezone {
+			Some(tz) => self.state_in(&t.with_timezone(&tz)),
+			None => self.state_in(&t.with_timezone(&Local)),
+		}
+	}
+
+	/** The next time after `t` to refresh or close the display (or open it, when closed); None if there is none */
+	pub fn next_event(&self, t: DateTime<Utc>) -> Option<DateTime<Utc>> {
+		match self.timezone {
+			Some(tz) => self.next_event_in(t.with_timezone(&tz)),
+			None => self.next_event_in(t.with_timezone(&Local)),
+		}
+	}
+
+	fn rule_at<Z: TimeZone>(&self, t: &DateTime<Z>) -> Option<&Rule> {
+		self.rules.iter().find(|rule| rule.contains(t))
+	}
+
+	fn state_in<Z: TimeZone>(&self, t: &DateTime<Z>) -> State {
+		match self.rule_at(t).map(|rule| &rule.action) {
+			Some(Action::Closed) => State::Closed,
+			Some(_) => State::Open,
+			None => State::Idle,
+		}
+	}
+
+	fn next_event_in<Z: TimeZone>(&self, after: DateTime<Z>) -> Option<DateTime<Utc>> {
+		let initial = self.state_in(&after);
+		let mut t = after.clone();
+		// Walk from one window boundary to the next (a week of them is plenty to find the next event)
+		for _ in 0..(7 * 2 * self.rules.len() + 14) {
+			let boundary = self.next_boundary(&t);
+			match self.state_in(&t) {
+				// Refresh as soon as the display opens, then at the times the rule gives
+				State::Open if t != after && initial != State::Open => return Some(t.with_timezone(&Utc)),
+				State::Open => {
+					// Once past a boundary, a refresh right at the boundary counts too
+					let from = if t == after {
+						t.clone()
+					} else {
+						t.clone() - chrono::Duration::seconds(1)
+					};
+					let refresh = self.rule_at(&t).and_then(|rule| rule.next_refresh(&from));
+					if let Some(refresh) = refresh {
+						if boundary.as_ref().map(|b| refresh < *b).unwrap_or(true) {
+							return Some(refresh.with_timezone(&Utc));
+						}
+					}
+				}
+				State::Closed if initial != State::Closed => return Some(t.with_timezone(&Utc)),
+				State::Closed | State::Idle => {}
+			}
+			t = boundary?;
+		}
+		None
+	}
+
+	/** The first time after `t` at which another rule may apply */
+	fn next_boundary<Z: TimeZone>(&self, t: &DateTime<Z>) -> Option<DateTime<Z>> {
+		let timezone = t.timezone();
+		let today = t.date().naive_local();
+		let mut next: Option<DateTime<Z>> = None;
+		for rule in self.rules.iter().filter(|rule| rule.has_window()) {
+			for date in [today, today.succ()].iter() {
+				for minutes in [0, rule.from, rule.until % MINUTES_PER_DAY].iter() {
+					if let Some(candidate) = local_time_after(&timezone, *date, *minutes * 60, t) {
+						if next.as_ref().map(|n| candidate < *n).unwrap_or(true) {
+							next = Some(candidate);
+						}
+					}
+				}
+			}
+		}
+		next
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn office_hours() -> Schedule {
+		let rule = |days: &[&str], from: &str, until: &str| RuleConfig {
+			days: days.iter().map(|d| d.to_string()).collect(),
+			from: Some(from.to_string()),
+			until: Some(until.to_string()),
+			..RuleConfig::default()
+		};
+		Schedule::new(
+			Some("Europe/Amsterdam"),
+			&[
+				RuleConfig {
+					every: Some(300),
+					..rule(&["mon", "tue", "wed", "thu", "fri"], "08:00", "18:00")
+				},
+				RuleConfig {
+					closed: true,
+					..rule(&[], "22:00", "06:00")
+				},
+				RuleConfig {
+					cron: Some("0 0 * * * *".to_string()),
+					..RuleConfig::default()
+				},
+			],
+		)
+		.unwrap()
+	}
+
+	/** A time in Amsterdam (UTC+1 in January) */
+	fn at(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+		chrono_tz::Europe::Amsterdam
+			.ymd(2021, 1, day)
+			.and_hms(hour, minute, 0)
+			.with_timezone(&Utc)
+	}
+
+	#[test]
+	fn refreshes_on_clock_boundaries_within_windows() {
+		let schedule = office_hours();
+		// Monday 4 January 2021
+		assert_eq!(schedule.state(at(4, 9, 2)), State::Open);
+		assert_eq!(schedule.next_event(at(4, 9, 2)), Some(at(4, 9, 5)));
+		assert_eq!(schedule.next_event(at(4, 17, 57)), Some(at(4, 18, 0)));
+		assert_eq!(schedule.next_event(at(4, 18, 0)), Some(at(4, 19, 0)));
+		// Saturday: hourly
+		assert_eq!(schedule.next_event(at(9, 9, 2)), Some(at(9, 10, 0)));
+	}
+
+	#[test]
+	fn closes_at_night() {
+		let schedule = office_hours();
+		assert_eq!(schedule.next_event(at(4, 21, 30)), Some(at(4, 22, 0)));
+		assert_eq!(schedule.state(at(4, 23, 0)), State::Closed);
+		assert_eq!(schedule.state(at(5, 5, 59)), State::Closed);
+		// Opens at 06:00 (hourly, so it would otherwise wait until 07:00), then follows office hours
+		assert_eq!(schedule.next_event(at(4, 23, 0)), Some(at(5, 6, 0)));
+		assert_eq!(schedule.next_event(at(5, 7, 0)), Some(at(5, 8, 0)));
+		assert_eq!(schedule.next_event(at(5, 8, 0)), Some(at(5, 8, 5)));
+	}
+
+	#[test]
+	fn rejects_invalid_rules() {
+		let invalid = |rule: RuleConfig| Schedule::new(None, &[rule]).is_err();
+		assert!(invalid(RuleConfig::default()));
+		assert!(invalid(RuleConfig {
+			every: Some(60),
+			closed: true,
+			..RuleConfig::default()
+		}));
+		assert!(invalid(RuleConfig {
+			cron: Some("every minute".to_string()),
+			..RuleConfig::default()
+		}));
+		assert!(invalid(RuleConfig {
+			every: Some(60),
+			from: Some("8:70".to_string()),
+			..RuleConfig::default()
+		}));
+		assert!(invalid(RuleConfig {
+			every: Some(60),
+			days: vec!["someday".to_string()],
+			..RuleConfig::default()
+		}));
+		assert!(Schedule::new(Some("Mars/Olympus_Mons"), &[]).is_err());
+	}
+
+	#[test]
+	fn parses_times() {
+		assert_eq!(parse_time("08:30").unwrap(), 510);
+		assert_eq!(parse_time("24:00").unwrap(), MINUTES_PER_DAY);
+		for time in &[
+			"24:01",
+			"25:00",
+			"8:60",
+			"4294967295:00",
+			"71582789:00",
+			"08",
+			"08:",
+			"-1:00",
+			"ab:cd",
+		] {
+			assert!(parse_time(time).is_err(), "{}", time);
+		}
+	}
+
+	#[test]
+	fn keeps_to_the_clock_when_it_changes() {
+		let every = |seconds: u64| {
+			Schedule::new(
+				Some("Europe/Amsterdam"),
+				&[RuleConfig {
+					every: Some(seconds),
+					..RuleConfig::default()
+				}],
+			)
+			.unwrap()
+		};
+		// Sunday 28 March 2021: clocks go from 02:00 to 03:00 (UTC+1 to UTC+2)
+		let at = |hour: u32, minute: u32| {
+			chrono_tz::Europe::Amsterdam
+				.ymd(2021, 3, 28)
+				.and_hms(hour, minute, 0)
+				.with_timezone(&Utc)
+		};
+		assert_eq!(every(300).next_event(at(9, 2)), Some(at(9, 5)));
+		assert_eq!(every(3600).next_event(at(1, 30)), Some(at(3, 0)));
+		assert_eq!(every(3600).next_event(at(3, 30)), Some(at(4, 0)));
+
+		// Sunday 31 October 2021: clocks go from 03:00 back to 02:00 (UTC+2 to UTC+1)
+		let utc = |hour: u32, minute: u32| Utc.ymd(2021, 10, 31).and_hms(hour, minute, 0);
+		// 02:30 the first time round, then the second time
+		assert_eq!(every(300).next_event(utc(0, 30)), Some(utc(0, 35)));
+		assert_eq!(every(300).next_event(utc(1, 30)), Some(utc(1, 35)));
+		assert_eq!(every(300).next_event(utc(1, 55)), Some(utc(2, 0)));
+		assert_eq!(every(3600).next_event(utc(1, 30)), Some(utc(2, 0)));
+		// The window boundaries are found the second time round as well
+		let window = Schedule::new(
+			Some("Europe/Amsterdam"),
+			&[RuleConfig {
+				from: Some("02:45".to_string()),
+				closed: true,
+				..RuleConfig::default()
+			}],
+		)
+		.unwrap();
+		assert_eq!(window.next_event(utc(1, 30)), Some(utc(1, 45)));
+	}
+}
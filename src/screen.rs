@@ -0,0 +1,91 @@
+/*! Drawing complete screens (fetched images, messages) on a display */
+use crate::epd::EPDDisplay;
+use crate::graphics::{Bitmap, Surface};
+use crate::overlay::{self, Overlay};
+use crate::qr::Qr;
+use chrono::prelude::*;
+use embedded_graphics::{
+	fonts::{Font6x12, Text},
+	pixelcolor::BinaryColor,
+	prelude::*,
+	text_style,
+};
+use std::error::Error;
+use std::thread::sleep;
+use std::time::Duration;
+
+/** Draw a packed image on the display, with the status overlay (if any) on top */
+pub fn show_image(
+	display: &mut dyn EPDDisplay,
+	bitmap: &Bitmap,
+	overlay: Option<&Overlay>,
+	last_update: DateTime<Local>,
+) -> Result<(), Box<dyn Error>> {
+	show_bitmap(display, &overlay::compose(bitmap, overlay, last_update))
+}
+
+/** Draw a packed image on the display as-is */
+pub fn show_bitmap(display: &mut dyn EPDDisplay, bitmap: &Bitmap) -> Result<(), Box<dyn Error>> {
+	draw_bitmap(display, bitmap)?;
+	sleep(Duration::from_millis(500));
+	display.sleep()?;
+	Ok(())
+}
+
+/** Wake the panel and draw a packed image, returning once it is visible. The panel is left awake. */
+pub fn draw_bitmap(display: &mut dyn EPDDisplay, bitmap: &Bitmap) -> Result<(), Box<dyn Error>> {
+	display.init()?;
+	match &bitmap.color {
+		Some(color) => display.draw_bichromatic(&bitmap.black, color),
+		None => display.draw(&bitmap.black),
+	}
+}
+
+/** Blank the display */
+pub fn clear(display: &mut dyn EPDDisplay) -> Result<(), Box<dyn Error>> {
+	let (width, height) = (display.width() as u32, display.height() as u32);
+	let blank = Bitmap {
+		width,
+		height,
+		black: vec![0; (width * height) as usize / 8],
+		color: None,
+	};
+	show_bitmap(display, &blank)
+}
+
+/** Display text, and optionally a QR code in the bottom right corner */
+pub fn show_text(display: &mut dyn EPDDisplay, text: &str, qr: Option<&Qr>) -> Result<(), Box<dyn Error>> {
+	display.init()?;
+	let image = text_screen(display.width() as u32, display.height() as u32, text, qr);
+	display.draw(image.buffer())?;
+	display.sleep()?;
+	Ok(())
+}
+
+/** Lay out text, and optionally a QR code in the bottom right corner, on an image of the given size */
+pub fn text_screen(w: u32, h: u32, text: &str, qr: Option<&Qr>) -> Surface {
+	let mut image = Surface::new(w, h);
+
+	if let Some(qr) = qr {
+		// Use at most half of the display height so the text above remains readable
+		let module_size = qr.module_size_for(h / 2).max(1);
+		let size = qr.size(module_size);
+		let origin = Point::new(w.saturating_sub(size + 10) as i32, h.saturating_sub(size + 10) as i32);
+		let _ = qr.draw(&mut image, origin, module_size);
+	}
+
+	let mut y = 10;
+	for txt in text.chars().collect::<Vec<char>>().chunks(100) {
+		let line = txt.iter().collect::<String>();
+		let _ = Text::new(&line, Point::new(10, y))
+			.into_styled(text_style!(
+				font = Font6x12,
+				text_color = BinaryColor::Off,
+				background_color = BinaryColor::On
+			))
+			.draw(&mut image);
+		y += 14;
+	}
+
+	image
+}
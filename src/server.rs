@@ -0,0 +1,221 @@
+/*! Built-in web server: a status page (at /) showing what the panels display and how their sources are doing, with
+buttons to control the displays, and metrics for Prometheus to scrape (at /metrics) */
+use crate::config;
+use crate::metrics;
+use crate::service::{Command, Service};
+use crate::status;
+use std::error::Error;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::thread;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/** Serve requests on a thread of its own, for as long as the process runs */
+pub fn start(address: &str, service: Arc<Service>) -> Result<(), Box<dyn Error>> {
+	let server = Server::http(address).map_err(|e| format!("cannot listen on {}: {}", address, e))?;
+	log::info!("serving status page on http://{}/", address);
+	thread::Builder::new().name("server".to_string()).spawn(move || {
+		for request in server.incoming_requests() {
+			handle(request, &service);
+		}
+	})?;
+	Ok(())
+}
+
+fn handle(request: Request, service: &Service) {
+	let path = request.url().split('?').next().unwrap_or_default().to_string();
+	let segments: Vec<String> = path.split('/').skip(1).map(decode).collect();
+	let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+	let response = match (request.method(), segments.as_slice()) {
+		(Method::Get, [""]) => {
+			Response::from_string(page(service)).with_header(content_type("text/html; charset=utf-8"))
+		}
+		(Method::Get, ["metrics"]) => {
+			Response::from_string(metrics::render()).with_header(content_type("text/plain; version=0.0.4"))
+		}
+		(Method::Get, ["panel", file]) => match file.strip_suffix(".png").and_then(status::panel) {
+			Some(bitmap) => match bitmap.to_png() {
+				Ok(png) => Response::from_data(png).with_header(content_type("image/png")),
+				Err(e) => Response::from_string(e.to_string()).with_status_code(500),
+			},
+			None => not_found(),
+		},
+		(Method::Post, ["display", display, action]) if status::displays().contains_key(*display) => {
+			let command = match *action {
+				"refresh" => Some(Command::Refresh),
+				"clear" => Some(Command::Clear),
+				"next" => Some(Command::NextSource),
+				_ => None,
+			};
+			match command {
+				Some(command) => {
+					log::info!("[{}] {:?} requested from the status page", display, command);
+					service.command(display, command);
+					// Back to the page, so reloading it does not post again
+					Response::from_string("")
+						.with_status_code(303)
+						.with_header(Header::from_bytes("Location", "/").expect("valid header"))
+				}
+				None => not_found(),
+			}
+		}
+		_ => not_found(),
+	};
+	if let Err(e) = request.respond(response) {
+		log::warn!("could not respond to request: {}", e);
+	}
+}
+
+fn content_type(value: &str) -> Header {
+	Header::from_bytes("Content-Type", value).expect("valid header")
+}
+
+fn not_found() -> Response<std::io::Cursor<Vec<u8>>> {
+	Response::from_string("not found").with_status_code(404)
+}
+
+/** The status page */
+fn page(service: &Service) -> String {
+	let statuses = service.statuses();
+	let mut out = String::new();
+	out.push_str(concat!(
+		"<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><meta http-equiv=\"refresh\" content=\"30\">",
+		"<title>SparkBoard</title><style>",
+		"body{font-family:sans-serif;margin:1em 2em}img{max-width:100%;border:1px solid #888;margin:0 .5em .5em 0}",
+		"form{display:inline}table{border-collapse:collapse}td,th{border:1px solid #ccc;padding:.2em .5em}",
+		"pre{background:#f4f4f4;padding:.5em;overflow:auto}.error{color:#b00}",
+		"</style></head><body>\n<h1>SparkBoard</h1>\n"
+	));
+
+	out.push_str("<ul>\n");
+	for (task, text) in &statuses {
+		let _ = writeln!(out, "<li>{}: {}</li>", escape(task), escape(text));
+	}
+	out.push_str("</ul>\n");
+
+	for (name, display) in status::displays() {
+		let _ = writeln!(out, "<h2>{}</h2>", escape(&name));
+		out.push_str("<p>");
+		for panel in &display.panels {
+			if status::panel(panel).is_some() {
+				let _ = write!(
+					out,
+					"<img src=\"/panel/{}.png\" alt=\"{}\" title=\"{}\">",
+					encode(panel),
+					escape(panel),
+					escape(panel)
+				);
+			} else {
+				let _ = write!(out, "{}: nothing drawn yet ", escape(panel));
+			}
+		}
+		out.push_str("</p>\n<p>");
+		for (action, label) in &[("refresh", "Refresh now"), ("clear", "Clear"), ("next", "Next source")] {
+			let _ = write!(
+				out,
+				"<form method=\"post\" action=\"/display/{}/{}\"><button>{}</button></form> ",
+				encode(&name),
+				action,
+				label
+			);
+		}
+		out.push_str(
+			"</p>\n<table>\n<tr><th>Source</th><th>Last success</th><th>Failures</th><th>Last error</th></tr>\n",
+		);
+		for (index, source) in display.sources.iter().enumerate() {
+			let next = if index == display.next_source { " (next)" } else { "" };
+			let success = source.last_success.map(|t| t.format("%F %T").to_string());
+			let error = source
+				.last_error
+				.as_ref()
+				.map(|(t, e)| format!("{}: {}", t.format("%F %T"), e));
+			let _ = writeln!(
+				out,
+				"<tr><td>{}{}</td><td>{}</td><td>{}</td><td class=\"error\">{}</td></tr>",
+				escape(&config::redact_url(&source.url)),
+				next,
+				success.unwrap_or_default(),
+				source.failures,
+				escape(&error.unwrap_or_default())
+			);
+		}
+		out.push_str("</table>\n");
+	}
+
+	let _ = writeln!(
+		out,
+		"<h2>Configuration</h2>\n<pre>{}</pre>",
+		escape(&status::config_text())
+	);
+	out.push_str("<h2>Log</h2>\n<pre>");
+	for line in status::log_lines() {
+		let class = if line.level <= log::Level::Warn {
+			" class=\"error\""
+		} else {
+			""
+		};
+		let _ = writeln!(
+			out,
+			"<span{}>{} {:5} {}</span>",
+			class,
+			line.time.format("%F %T"),
+			line.level,
+			escape(&line.text)
+		);
+	}
+	out.push_str("</pre>\n</body></html>\n");
+	out
+}
+
+fn escape(text: &str) -> String {
+	text.replace('&', "&amp;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;")
+		.replace('"', "&quot;")
+}
+
+/** Percent-encode a display name for use in a path */
+fn encode(name: &str) -> String {
+	name.bytes()
+		.map(|b| match b {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+			_ => format!("%{:02X}", b),
+		})
+		.collect()
+}
+
+/** Undo percent-encoding; invalid escapes are kept as they are */
+fn decode(segment: &str) -> String {
+	let bytes = segment.as_bytes();
+	let mut out = Vec::with_capacity(bytes.len());
+	let mut i = 0;
+	while i < bytes.len() {
+		let hex = bytes
+			.get(i + 1..i + 3)
+			.filter(|h| h.iter().all(u8::is_ascii_hexdigit))
+			.and_then(|h| std::str::from_utf8(h).ok());
+		match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+			Some(byte) if bytes[i] == b'%' => {
+				out.push(byte);
+				i += 3;
+			}
+			_ => {
+				out.push(bytes[i]);
+				i += 1;
+			}
+		}
+	}
+	String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn encodes_display_names() {
+		assert_eq!(encode("#2 hall"), "%232%20hall");
+		assert_eq!(decode(&encode("#2 hall/ü")), "#2 hall/ü");
+		assert_eq!(decode("100%"), "100%");
+	}
+}
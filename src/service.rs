@@ -0,0 +1,412 @@
+/*! Running as a systemd service (`Type=notify`): readiness and status notifications, the watchdog, and signals.
+
+SIGINT and SIGTERM stop the service, SIGHUP reloads its configuration and SIGUSR1 refreshes all displays right away.
+Signals never interrupt a refresh: runners act on them once done drawing, when they would otherwise go to sleep.
+
+Every long-running activity (a runner, or waiting for Wi-Fi) is a task that reports what it is doing. Work must finish
+within the watchdog interval (`WatchdogSec`) and sleeps must end on time; as long as all tasks keep to that, the watchdog
+is kept happy. A task that hangs (for instance waiting for a panel that never stops being busy) stops the pings, so
+systemd restarts the service. Without `NOTIFY_SOCKET` (when not started by systemd) notifications are not sent.
+
+Notifications are only sent on Linux, and signals are only handled on Unix; elsewhere (such as when previewing with a
+simulated display) both are left out. */
+use chrono::prelude::*;
+#[cfg(unix)]
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1};
+#[cfg(unix)]
+use signal_hook::iterator::Signals;
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/** What a task is doing, and when it should have made progress by */
+struct Task {
+	status: String,
+	deadline: Instant,
+}
+
+/** Something a runner is asked to do (from the status page) */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+	/** Fetch and show an image now, even if the panels already show it */
+	Refresh,
+	/** Blank the panels until the next refresh */
+	Clear,
+	/** Fetch from the next source now, skipping the current one if it is failing */
+	NextSource,
+}
+
+/** What the service has been asked to do */
+#[derive(Default)]
+struct Requests {
+	/** Runners should return at their next sleep */
+	stop: bool,
+	/** Start again with a freshly loaded configuration once stopped */
+	reload: bool,
+	/** Number of forced refreshes so far */
+	refreshes: u64,
+	/** Commands for tasks that have not been carried out yet */
+	commands: BTreeMap<String, Command>,
+}
+
+/** State shared by all tasks of the service */
+pub struct Service {
+	tasks: Mutex<BTreeMap<String, Task>>,
+	requests: Mutex<Requests>,
+	/** Notified when a request comes in, to wake up sleeping tasks */
+	wake: Condvar,
+	ready: AtomicBool,
+	/** How often systemd expects to hear that the service is alive */
+	watchdog: Option<Duration>,
+}
+
+impl Service {
+	/** Read the watchdog interval that systemd passes in the environment (if any) */
+	pub fn new() -> Arc<Service> {
+		Arc::new(Service::with_watchdog(watchdog_interval()))
+	}
+
+	fn with_watchdog(watchdog: Option<Duration>) -> Service {
+		Service {
+			tasks: Mutex::new(BTreeMap::new()),
+			requests: Mutex::new(Requests::default()),
+			wake: Condvar::new(),
+			ready: AtomicBool::new(false),
+			watchdog,
+		}
+	}
+
+	/** Stop on SIGINT and SIGTERM, reload on SIGHUP and refresh on SIGUSR1 */
+	#[cfg(unix)]
+	pub fn handle_signals(self: &Arc<Self>) -> Result<(), Box<dyn Error>> {
+		let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP, SIGUSR1])?;
+		let service = self.clone();
+		thread::Builder::new().name("signals".to_string()).spawn(move || {
+			for signal in signals.forever() {
+				match signal {
+					SIGHUP => {
+						log::info!("received SIGHUP; reloading configuration after the current refresh");
+						service.reload();
+					}
+					SIGUSR1 => {
+						log::info!("received SIGUSR1; refreshing now");
+						service.refresh();
+					}
+					_ => {
+						log::info!("received signal {}; stopping after the current refresh", signal);
+						service.stop();
+					}
+				}
+			}
+		})?;
+		Ok(())
+	}
+
+	/** Without Unix signals, the process is simply ended (by Ctrl+C) */
+	#[cfg(not(unix))]
+	pub fn handle_signals(self: &Arc<Self>) -> Result<(), Box<dyn Error>> {
+		Ok(())
+	}
+
+	/** Ping the watchdog for as long as all tasks are making progress */
+	pub fn start_watchdog(self: &Arc<Self>) -> Result<(), Box<dyn Error>> {
+		let interval = match self.watchdog {
+			Some(interval) => interval,
+			None => return Ok(()),
+		};
+		let service = self.clone();
+		thread::Builder::new().name("watchdog".to_string()).spawn(move || {
+			// Keep pinging while stopping, as finishing the current refresh may take a while
+			let mut stalled = false;
+			loop {
+				thread::sleep(interval / 2);
+				let stalled_task = service.stalled_task();
+				match &stalled_task {
+					None => notify("WATCHDOG=1"),
+					Some(name) if !stalled => log::warn!("{} is not making progress; no longer pinging watchdog", name),
+					Some(_) => {}
+				}
+				stalled = stalled_task.is_some();
+			}
+		})?;
+		Ok(())
+	}
+
+	/** Tell systemd that the service has started (or reloaded); only the first call has any effect */
+	pub fn ready(&self) {
+		if !self.ready.swap(true, Ordering::SeqCst) {
+			notify("READY=1");
+		}
+	}
+
+	/** Let runners finish what they are doing and return at their next sleep */
+	pub fn stop(&self) {
+		notify("STOPPING=1");
+		let mut requests = self.requests.lock().unwrap();
+		requests.stop = true;
+		requests.reload = false;
+		self.wake.notify_all();
+	}
+
+	/** Stop runners like `stop`, to start them again with a new configuration (see `take_reload`) */
+	pub fn reload(&self) {
+		let mut requests = self.requests.lock().unwrap();
+		if requests.stop && !requests.reload {
+			return;
+		}
+		notify("RELOADING=1");
+		requests.stop = true;
+		requests.reload = true;
+		self.wake.notify_all();
+	}
+
+	/** Wake up all runners so they refresh right away */
+	pub fn refresh(&self) {
+		self.requests.lock().unwrap().refreshes += 1;
+		self.wake.notify_all();
+	}
+
+	/** Number of refreshes forced with `refresh` so far; a task that sees it change knows it was woken up for one */
+	pub fn refreshes(&self) -> u64 {
+		self.requests.lock().unwrap().refreshes
+	}
+
+	/** Wake up a task to carry out a command (see `take_command`); replaces any command it has not taken yet */
+	pub fn command(&self, task: &str, command: Command) {
+		self.requests.lock().unwrap().commands.insert(task.to_string(), command);
+		self.wake.notify_all();
+	}
+
+	/** The command that woke up a task, if any */
+	pub fn take_command(&self, task: &str) -> Option<Command> {
+		self.requests.lock().unwrap().commands.remove(task)
+	}
+
+	pub fn is_stopping(&self) -> bool {
+		self.requests.lock().unwrap().stop
+	}
+
+	/** Whether runners were stopped to reload the configuration. If so, they may be started again. */
+	pub fn take_reload(&self) -> bool {
+		let mut requests = self.requests.lock().unwrap();
+		if !requests.reload {
+			return false;
+		}
+		requests.stop = false;
+		requests.reload = false;
+		self.ready.store(false, Ordering::SeqCst);
+		true
+	}
+
+	/** Report what a task is doing. It should report again (or sleep) within the watchdog interval. */
+	pub fn status(&self, task: &str, status: &str) {
+		let deadline = Instant::now() + self.watchdog.unwrap_or_default();
+		self.update(task, status.to_string(), deadline);
+	}
+
+	/** What each task is doing, by name */
+	pub fn statuses(&self) -> Vec<(String, String)> {
+		let tasks = self.tasks.lock().unwrap();
+		tasks
+			.iter()
+			.map(|(name, task)| (name.clone(), task.status.clone()))
+			.collect()
+	}
+
+	/** Remove a task that has finished */
+	pub fn finish(&self, task: &str) {
+		let status = {
+			let mut tasks = self.tasks.lock().unwrap();
+			tasks.remove(task);
+			status_line(&tasks)
+		};
+		notify(&format!("STATUS={}", status));
+	}
+
+	/** Sleep for a task until the given time, a forced refresh or a command. Returns false when the service is stopping. */
+	// `refreshes` is the number of forced refreshes the task knows of, so one forced while it was busy ends the sleep at once
+	pub fn sleep_until(&self, task: &str, time: DateTime<Utc>, refreshes: u64) -> bool {
+		let local = time.with_timezone(&Local);
+		let duration = (time - Utc::now()).to_std().unwrap_or_default();
+		self.sleep_task(
+			task,
+			format!("sleeping until {}", local.format("%H:%M:%S")),
+			duration,
+			Some(refreshes),
+		)
+	}
+
+	/** Like `sleep_until`, but only stopping ends it early; forced refreshes and commands wait for the next sleep */
+	pub fn wait_until(&self, task: &str, time: DateTime<Utc>) -> bool {
+		let local = time.with_timezone(&Local);
+		let duration = (time - Utc::now()).to_std().unwrap_or_default();
+		self.sleep_task(
+			task,
+			format!("waiting until {}", local.format("%H:%M:%S")),
+			duration,
+			None,
+		)
+	}
+
+	/** Like `sleep_until`, but for the given time */
+	pub fn sleep_for(&self, task: &str, duration: Duration, refreshes: u64) -> bool {
+		self.sleep_task(task, format!("sleeping for {:?}", duration), duration, Some(refreshes))
+	}
+
+	fn sleep_task(&self, task: &str, status: String, duration: Duration, refreshes: Option<u64>) -> bool {
+		let deadline = Instant::now()
+			.checked_add(duration.saturating_add(self.watchdog.unwrap_or_default()))
+			.unwrap_or_else(|| Instant::now() + Duration::from_secs(100 * 365 * 24 * 3600));
+		self.update(task, status, deadline);
+		!self.sleep(task, duration, refreshes)
+	}
+
+	/** Sleep until the time is up, a refresh is forced, the task gets a command or the service stops; true if stopping */
+	// Without `refreshes`, forced refreshes and commands do not end the sleep
+	fn sleep(&self, task: &str, duration: Duration, refreshes: Option<u64>) -> bool {
+		let requests = self.requests.lock().unwrap();
+		let (requests, _) = self
+			.wake
+			.wait_timeout_while(requests, duration, |r| {
+				!r.stop
+					&& refreshes
+						.map(|refreshes| r.refreshes == refreshes && !r.commands.contains_key(task))
+						.unwrap_or(true)
+			})
+			.unwrap();
+		requests.stop
+	}
+
+	fn update(&self, task: &str, status: String, deadline: Instant) {
+		let line = {
+			let mut tasks = self.tasks.lock().unwrap();
+			let changed = tasks.get(task).map(|t| t.status != status).unwrap_or(true);
+			tasks.insert(task.to_string(), Task { status, deadline });
+			if !changed {
+				return;
+			}
+			status_line(&tasks)
+		};
+		notify(&format!("STATUS={}", line));
+	}
+
+	/** The name of a task that has not made progress in time, if any */
+	fn stalled_task(&self) -> Option<String> {
+		let now = Instant::now();
+		let tasks = self.tasks.lock().unwrap();
+		tasks
+			.iter()
+			.find(|(_, task)| task.deadline < now)
+			.map(|(name, _)| name.clone())
+	}
+}
+
+fn status_line(tasks: &BTreeMap<String, Task>) -> String {
+	let statuses: Vec<String> = tasks
+		.iter()
+		.map(|(name, task)| format!("{}: {}", name, task.status))
+		.collect();
+	statuses.join("; ")
+}
+
+/** The watchdog interval systemd asks for, if it is meant for this process */
+fn watchdog_interval() -> Option<Duration> {
+	if let Some(pid) = env::var("WATCHDOG_PID").ok().and_then(|p| p.parse::<u32>().ok()) {
+		if pid != std::process::id() {
+			return None;
+		}
+	}
+	let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+	Some(Duration::from_micros(usec)).filter(|d| !d.is_zero())
+}
+
+/** Send a notification to systemd (see sd_notify(3)); does nothing when not running under systemd */
+#[cfg(target_os = "linux")]
+pub fn notify(state: &str) {
+	use std::os::linux::net::SocketAddrExt;
+	use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+	let path = match env::var("NOTIFY_SOCKET") {
+		Ok(path) => path,
+		Err(_) => return,
+	};
+	let sent = UnixDatagram::unbound().and_then(|socket| match path.strip_prefix('@') {
+		Some(name) => socket.send_to_addr(state.as_bytes(), &SocketAddr::from_abstract_name(name)?),
+		None => socket.send_to(state.as_bytes(), &path),
+	});
+	if let Err(e) = sent {
+		log::warn!("could not notify systemd at {:?}: {}", path, e);
+	}
+}
+
+/** There is no systemd to notify */
+#[cfg(not(target_os = "linux"))]
+pub fn notify(_state: &str) {}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn watchdog_waits_for_all_tasks() {
+		let service = Service::with_watchdog(Some(Duration::from_millis(50)));
+		service.status("lobby", "fetching");
+		assert!(service.sleep_for("hall", Duration::from_millis(100), 0));
+		// Sleeping longer than the watchdog interval is fine, but the fetch is taking too long
+		assert_eq!(service.stalled_task(), Some("lobby".to_string()));
+		service.status("lobby", "drawing");
+		assert_eq!(service.stalled_task(), None);
+	}
+
+	#[test]
+	fn stopping_ends_sleeps() {
+		let service = Arc::new(Service::with_watchdog(None));
+		let stopper = service.clone();
+		let handle = thread::spawn(move || stopper.sleep_for("lobby", Duration::from_secs(60), 0));
+		thread::sleep(Duration::from_millis(50));
+		service.stop();
+		assert!(!handle.join().unwrap());
+		assert!(!service.sleep_for("lobby", Duration::from_secs(60), 0));
+	}
+
+	#[test]
+	fn refreshes_and_reloads_end_sleeps() {
+		let service = Arc::new(Service::with_watchdog(None));
+		let refresher = service.clone();
+		let handle = thread::spawn(move || refresher.sleep_for("lobby", Duration::from_secs(60), 0));
+		thread::sleep(Duration::from_millis(50));
+		service.refresh();
+		assert!(handle.join().unwrap());
+
+		// A refresh forced before the sleep (while the task was busy) ends it at once
+		let started = Instant::now();
+		assert_eq!(service.refreshes(), 1);
+		assert!(service.sleep_for("lobby", Duration::from_secs(60), 0));
+		assert!(started.elapsed() < Duration::from_secs(1));
+
+		service.reload();
+		assert!(!service.sleep_for("lobby", Duration::from_secs(60), 1));
+		assert!(service.take_reload());
+		assert!(!service.is_stopping());
+		service.stop();
+		assert!(!service.take_reload());
+	}
+
+	#[test]
+	fn commands_wake_their_task() {
+		let service = Service::with_watchdog(None);
+		service.command("lobby", Command::Clear);
+		// Waiting leaves the command (and forced refreshes) for later
+		let started = Instant::now();
+		service.refresh();
+		assert!(service.wait_until("lobby", Utc::now() + chrono::Duration::milliseconds(100)));
+		assert!(started.elapsed() >= Duration::from_millis(100));
+		assert!(service.sleep_for("lobby", Duration::from_secs(60), 1));
+		assert_eq!(service.take_command("hall"), None);
+		assert_eq!(service.take_command("lobby"), Some(Command::Clear));
+	}
+}
@@ -0,0 +1,76 @@
+/*! Simulated display that writes what it would show to a PNG file, for running without a panel attached */
+use crate::epd::EPDDisplay;
+use crate::graphics::Bitmap;
+use std::error::Error;
+use std::path::PathBuf;
+
+pub struct SimDisplay {
+	width: usize,
+	height: usize,
+	path: PathBuf,
+}
+
+impl SimDisplay {
+	/** Simulate a panel of the given size; every drawn image replaces the contents of the file at `path` */
+	pub fn new(width: usize, height: usize, path: PathBuf) -> SimDisplay {
+		SimDisplay { width, height, path }
+	}
+
+	fn write(&self, black: &[u8], color: Option<&[u8]>) -> Result<(), Box<dyn Error>> {
+		let size = self.width * self.height / 8;
+		if black.len() != size || color.map(|c| c.len() != size).unwrap_or(false) {
+			return Err(format!("invalid buffer size (the display takes {} bytes)", size).into());
+		}
+		let bitmap = Bitmap {
+			width: self.width as u32,
+			height: self.height as u32,
+			black: black.to_vec(),
+			color: color.map(|c| c.to_vec()),
+		};
+		std::fs::write(&self.path, bitmap.to_png()?)?;
+		log::info!("simulated display written to {:?}", self.path);
+		Ok(())
+	}
+}
+
+impl EPDDisplay for SimDisplay {
+	fn init(&mut self) -> Result<(), Box<dyn Error>> {
+		Ok(())
+	}
+
+	fn sleep(&mut self) -> Result<(), Box<dyn Error>> {
+		Ok(())
+	}
+
+	fn draw(&mut self, buffer: &[u8]) -> Result<(), Box<dyn Error>> {
+		self.write(buffer, None)
+	}
+
+	fn draw_bichromatic(&mut self, black_buffer: &[u8], color_buffer: &[u8]) -> Result<(), Box<dyn Error>> {
+		self.write(black_buffer, Some(color_buffer))
+	}
+
+	fn width(&self) -> usize {
+		self.width
+	}
+
+	fn height(&self) -> usize {
+		self.height
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn rejects_other_buffer_sizes() {
+		let path = std::env::temp_dir().join(format!("sparkboard-sim-{}.png", std::process::id()));
+		let mut display = SimDisplay::new(16, 2, path.clone());
+		assert!(display.draw(&[0; 3]).is_err());
+		assert!(display.draw_bichromatic(&[0; 4], &[0; 3]).is_err());
+		assert!(!path.exists());
+		display.draw_bichromatic(&[0; 4], &[0; 4]).unwrap();
+		std::fs::remove_file(&path).unwrap();
+	}
+}
@@ -0,0 +1,62 @@
+use crate::epd::{InputLine, OutputLine, DEFAULT_MAX_TRANSFER_SIZE, EPD};
+use spidev::{SpiModeFlags, Spidev, SpidevOptions};
+use std::error::Error;
+
+/** Where spidev reports the largest transfer it accepts (set with the bufsiz module parameter) */
+const BUFSIZ_PATH: &str = "/sys/module/spidev/parameters/bufsiz";
+
+/** Open and configure a spidev device (such as /dev/spidev0.0) the way the displays expect: 8 bits per word, mode 0.
+With `kernel_cs` the SPI controller drives chip select during each transfer; otherwise it leaves chip select alone
+(SPI_NO_CS) so a GPIO line can be used instead. Some drivers reject SPI_NO_CS; then the kernel chip select still
+toggles, which is harmless as long as its pin is not wired to a display. */
+pub fn open(path: &str, kernel_cs: bool) -> Result<Spidev, Box<dyn Error>> {
+	log::info!("Using SPI device at {:?}", path);
+	let mut spi = Spidev::open(path)?;
+	let options = |mode| {
+		SpidevOptions::new()
+			.bits_per_word(8)
+			.max_speed_hz(10_000_000)
+			.mode(mode)
+			.build()
+	};
+	let mut result = spi.configure(&options(if kernel_cs {
+		SpiModeFlags::SPI_MODE_0
+	} else {
+		SpiModeFlags::SPI_MODE_0 | SpiModeFlags::SPI_NO_CS
+	}));
+	if let Err(e) = &result {
+		if !kernel_cs && e.kind() == std::io::ErrorKind::InvalidInput {
+			log::warn!(
+				"SPI device {:?} does not support SPI_NO_CS ({}), its kernel chip select will still toggle",
+				path,
+				e
+			);
+			result = spi.configure(&options(SpiModeFlags::SPI_MODE_0));
+		}
+	}
+	result.map_err(|e| format!("could not configure SPI device {:?}: {}", path, e))?;
+	Ok(spi)
+}
+
+/** Largest transfer spidev accepts */
+pub fn max_transfer_size() -> usize {
+	std::fs::read_to_string(BUFSIZ_PATH)
+		.ok()
+		.and_then(|s| s.trim().parse().ok())
+		.unwrap_or(DEFAULT_MAX_TRANSFER_SIZE)
+}
+
+/** Open the SPI device and combine it with the GPIO lines into a display connection. Chip select is left to the SPI
+controller when `cs` is None. */
+pub fn connect(
+	path: &str,
+	busy: Box<dyn InputLine>,
+	cs: Option<Box<dyn OutputLine>>,
+	dc: Box<dyn OutputLine>,
+	rst: Box<dyn OutputLine>,
+) -> Result<EPD, Box<dyn Error>> {
+	let spi = open(path, cs.is_none())?;
+	let mut epd = EPD::new(Box::new(spi), busy, cs, dc, rst);
+	epd.set_max_transfer_size(max_transfer_size());
+	Ok(epd)
+}
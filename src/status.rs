@@ -0,0 +1,154 @@
+/*! What the displays are doing, for the status page served by `server`: the image last drawn on each panel, the state
+of the sources of each display, the configuration in effect and recent log lines. */
+use crate::config::Config;
+use crate::graphics::Bitmap;
+use chrono::prelude::*;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+
+/** Number of log lines kept */
+const LOG_LINES: usize = 200;
+
+/** How a source of a display has been doing */
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceState {
+	pub url: String,
+	pub last_success: Option<DateTime<Local>>,
+	/** Time and description of the last failure */
+	pub last_error: Option<(DateTime<Local>, String)>,
+	/** Failures since the last success */
+	pub failures: u32,
+}
+
+/** A display (or wall) that has a runner */
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisplayState {
+	/** The panels it draws on (the displays of the tiles of a wall) */
+	pub panels: Vec<String>,
+	pub sources: Vec<SourceState>,
+	/** Index of the source that is fetched next */
+	pub next_source: usize,
+}
+
+/** A logged line */
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogLine {
+	pub time: DateTime<Local>,
+	pub level: log::Level,
+	pub text: String,
+}
+
+static PANELS: Mutex<BTreeMap<String, Bitmap>> = Mutex::new(BTreeMap::new());
+static DISPLAYS: Mutex<BTreeMap<String, DisplayState>> = Mutex::new(BTreeMap::new());
+static CONFIG: Mutex<String> = Mutex::new(String::new());
+static LOG: Mutex<VecDeque<LogLine>> = Mutex::new(VecDeque::new());
+
+/** A panel was sent an image (as passed to `EPDDisplay::draw` or `draw_bichromatic`) */
+pub fn drawn(panel: &str, bitmap: Bitmap) {
+	PANELS.lock().unwrap().insert(panel.to_string(), bitmap);
+}
+
+/** The image last drawn on a panel, if any */
+pub fn panel(panel: &str) -> Option<Bitmap> {
+	PANELS.lock().unwrap().get(panel).cloned()
+}
+
+/** Forget the displays that ran before (the configuration was loaded again) */
+pub fn clear_displays() {
+	DISPLAYS.lock().unwrap().clear();
+}
+
+/** A runner was started for a display that draws on `panels` and fetches from `urls` in turn */
+pub fn display(display: &str, panels: Vec<String>, urls: &[&str]) {
+	let sources = urls
+		.iter()
+		.map(|url| SourceState {
+			url: url.to_string(),
+			last_success: None,
+			last_error: None,
+			failures: 0,
+		})
+		.collect();
+	DISPLAYS.lock().unwrap().insert(
+		display.to_string(),
+		DisplayState {
+			panels,
+			sources,
+			next_source: 0,
+		},
+	);
+}
+
+/** Fetching from a source of a display succeeded (`error` is None) or failed; `next` is the source to fetch next */
+pub fn fetched(display: &str, source: usize, error: Option<String>, next: usize) {
+	let mut displays = DISPLAYS.lock().unwrap();
+	let state = match displays.get_mut(display) {
+		Some(state) => state,
+		None => return,
+	};
+	state.next_source = next;
+	if let Some(source) = state.sources.get_mut(source) {
+		match error {
+			Some(error) => {
+				source.last_error = Some((Local::now(), error));
+				source.failures += 1;
+			}
+			None => {
+				source.last_success = Some(Local::now());
+				source.failures = 0;
+			}
+		}
+	}
+}
+
+/** The displays that have a runner, by name */
+pub fn displays() -> BTreeMap<String, DisplayState> {
+	DISPLAYS.lock().unwrap().clone()
+}
+
+/** The configuration was loaded; it is shown without secrets */
+pub fn config(config: &Config) {
+	*CONFIG.lock().unwrap() = format!("{:#?}", config.redacted());
+}
+
+/** The configuration in effect, as text */
+pub fn config_text() -> String {
+	CONFIG.lock().unwrap().clone()
+}
+
+/** Keep a logged line, forgetting the oldest ones */
+pub fn log(level: log::Level, text: String) {
+	let mut lines = LOG.lock().unwrap();
+	if lines.len() == LOG_LINES {
+		lines.pop_front();
+	}
+	lines.push_back(LogLine {
+		time: Local::now(),
+		level,
+		text,
+	});
+}
+
+/** Recent log lines, oldest first */
+pub fn log_lines() -> Vec<LogLine> {
+	LOG.lock().unwrap().iter().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn keeps_source_states() {
+		display("hall", vec!["hall".to_string()], &["http://a/", "http://b/"]);
+		fetched("hall", 0, Some("timed out".to_string()), 0);
+		fetched("hall", 0, Some("timed out".to_string()), 1);
+		fetched("lobby", 0, None, 1);
+		let hall = &displays()["hall"];
+		assert_eq!(hall.next_source, 1);
+		assert_eq!(hall.sources[0].failures, 2);
+		assert_eq!(hall.sources[0].last_error.as_ref().unwrap().1, "timed out");
+		assert_eq!(hall.sources[1].last_error, None);
+		assert!(!displays().contains_key("lobby"));
+	}
+}
@@ -0,0 +1,53 @@
+use std::fs;
+use std::net::{IpAddr, UdpSocket};
+
+/** Host name of this device as reported by the kernel */
+pub fn hostname() -> Option<String> {
+	fs::read_to_string("/proc/sys/kernel/hostname")
+		.ok()
+		.map(|h| h.trim().to_string())
+		.filter(|h| !h.is_empty())
+}
+
+/** IP address of the interface that would be used to reach the internet. No packets are actually sent. */
+pub fn local_ip() -> Option<IpAddr> {
+	let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+	socket.connect("8.8.8.8:80").ok()?;
+	socket.local_addr().ok().map(|a| a.ip())
+}
+
+/** Signal level (in dBm) of a wireless interface, read from /proc/net/wireless */
+pub fn wifi_signal(interface: &str) -> Option<i32> {
+	let wireless = fs::read_to_string("/proc/net/wireless").ok()?;
+	parse_wifi_signal(&wireless, interface)
+}
+
+// Lines look like: " wlan0: 0000   70.  -40.  -256        0      0      0      0      0        0"
+fn parse_wifi_signal(wireless: &str, interface: &str) -> Option<i32> {
+	for line in wireless.lines().skip(2) {
+		let mut parts = line.split_whitespace();
+		if parts.next()?.trim_end_matches(':') == interface {
+			let level = parts.nth(2)?;
+			return level.trim_end_matches('.').parse::<i32>().ok();
+		}
+	}
+	None
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parses_wifi_signal() {
+		let wireless = "Inter-| sta-|   Quality        |   Discarded packets               | Missed | WE
+ face | tus | link level noise |  nwid  crypt   frag  retry   misc | beacon | 22
+ wlan0: 0000   70.  -40.  -256        0      0      0      0      0        0
+  wlp2s0: 0000   44.  -66.  -256        0      0      0      0      0        0
+";
+		assert_eq!(parse_wifi_signal(wireless, "wlan0"), Some(-40));
+		assert_eq!(parse_wifi_signal(wireless, "wlp2s0"), Some(-66));
+		assert_eq!(parse_wifi_signal(wireless, "wlan1"), None);
+		assert_eq!(parse_wifi_signal("", "wlan0"), None);
+	}
+}
@@ -0,0 +1,161 @@
+use rustls::{
+	Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError, WebPKIVerifier,
+};
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/** TLS settings for fetching from a source. The default verifies server certificates against the built-in roots. */
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+	/** PEM file with additional CA certificates to trust (e.g. a private CA) */
+	pub ca_file: Option<PathBuf>,
+	/** SHA-256 fingerprint of the DER-encoded server certificate that must be presented */
+	pub pin_sha256: Option<Vec<u8>>,
+	/** PEM file with the client certificate chain to present (mTLS) */
+	pub client_cert: Option<PathBuf>,
+	/** PEM file with the private key (PKCS#8 or RSA) for the client certificate */
+	pub client_key: Option<PathBuf>,
+	/** Do not verify the server certificate chain at all (a pinned fingerprint is still checked) */
+	pub insecure: bool,
+}
+
+/** Parse a fingerprint written as hex, optionally with colons between the bytes (e.g. as printed by openssl) */
+pub fn parse_fingerprint(text: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+	let hex: String = text.chars().filter(|c| *c != ':').collect();
+	if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+		return Err(format!("SHA-256 fingerprint should only contain hex digits, got {:?}", text).into());
+	}
+	if hex.len() != 64 {
+		return Err(format!("SHA-256 fingerprint should be 32 bytes, got {:?}", text).into());
+	}
+	(0..hex.len())
+		.step_by(2)
+		.map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|e| e.into()))
+		.collect()
+}
+
+impl TlsOptions {
+	pub fn client_config(&self) -> Result<ClientConfig, Box<dyn Error>> {
+		let mut config = ClientConfig::new();
+		config
+			.root_store
+			.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+
+		if let Some(ca_file) = &self.ca_file {
+			let mut reader = BufReader::new(File::open(ca_file)?);
+			let (valid, invalid) = config
+				.root_store
+				.add_pem_file(&mut reader)
+				.map_err(|_| format!("could not read CA certificates from {:?}", ca_file))?;
+			log::info!(
+				"added {} CA certificate(s) from {:?} ({} invalid)",
+				valid,
+				ca_file,
+				invalid
+			);
+			if valid == 0 {
+				return Err(format!("no valid CA certificates in {:?}", ca_file).into());
+			}
+		}
+
+		match (&self.client_cert, &self.client_key) {
+			(Some(cert_file), Some(key_file)) => {
+				let certs = rustls::internal::pemfile::certs(&mut BufReader::new(File::open(cert_file)?))
+					.map_err(|_| format!("could not read client certificate from {:?}", cert_file))?;
+				let key = read_private_key(key_file)?;
+				config.set_single_client_cert(certs, key)?;
+			}
+			(None, None) => {}
+			_ => return Err("a client certificate requires both a certificate and a key file".into()),
+		}
+
+		if self.insecure {
+			log::warn!("TLS certificate verification is disabled");
+		}
+
+		if self.insecure || self.pin_sha256.is_some() {
+			config.dangerous().set_certificate_verifier(Arc::new(Verifier {
+				verify_chain: !self.insecure,
+				pin_sha256: self.pin_sha256.clone(),
+			}));
+		}
+
+		Ok(config)
+	}
+}
+
+fn read_private_key(path: &PathBuf) -> Result<rustls::PrivateKey, Box<dyn Error>> {
+	let read_error = || format!("could not read private key from {:?}", path);
+	let mut keys = rustls::internal::pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(path)?))
+		.map_err(|_| read_error())?;
+	if keys.is_empty() {
+		keys = rustls::internal::pemfile::rsa_private_keys(&mut BufReader::new(File::open(path)?))
+			.map_err(|_| read_error())?;
+	}
+	keys.pop().ok_or_else(|| read_error().into())
+}
+
+/** Certificate verifier that optionally skips chain validation and optionally checks a pinned fingerprint */
+struct Verifier {
+	verify_chain: bool,
+	pin_sha256: Option<Vec<u8>>,
+}
+
+impl ServerCertVerifier for Verifier {
+	fn verify_server_cert(
+		&self,
+		roots: &RootCertStore,
+		presented_certs: &[Certificate],
+		dns_name: webpki::DNSNameRef,
+		ocsp_response: &[u8],
+	) -> Result<ServerCertVerified, TLSError> {
+		if self.verify_chain {
+			WebPKIVerifier::new().verify_server_cert(roots, presented_certs, dns_name, ocsp_response)?;
+		}
+
+		if let Some(pin) = &self.pin_sha256 {
+			let certificate = presented_certs.first().ok_or(TLSError::NoCertificatesPresented)?;
+			let fingerprint = ring::digest::digest(&ring::digest::SHA256, &certificate.0);
+			if fingerprint.as_ref() != pin.as_slice() {
+				return Err(TLSError::General(format!(
+					"server certificate fingerprint {:02x?} does not match pinned fingerprint",
+					fingerprint.as_ref()
+				)));
+			}
+		}
+
+		Ok(ServerCertVerified::assertion())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const FINGERPRINT: &str = "00112233445566778899aabbccddeeff00112233445566778899AABBCCDDEEFF";
+
+	#[test]
+	fn parses_fingerprints() {
+		let bytes = parse_fingerprint(FINGERPRINT).unwrap();
+		assert_eq!(bytes.len(), 32);
+		assert_eq!(&bytes[..4], &[0x00, 0x11, 0x22, 0x33]);
+		assert_eq!(bytes[31], 0xff);
+
+		let with_colons: Vec<String> = (0..32).map(|i| FINGERPRINT[i * 2..i * 2 + 2].to_string()).collect();
+		assert_eq!(parse_fingerprint(&with_colons.join(":")).unwrap(), bytes);
+	}
+
+	#[test]
+	fn rejects_malformed_fingerprints() {
+		assert!(parse_fingerprint("").is_err());
+		assert!(parse_fingerprint(&FINGERPRINT[..62]).is_err());
+		assert!(parse_fingerprint(&format!("{}00", FINGERPRINT)).is_err());
+		assert!(parse_fingerprint(&FINGERPRINT.replace('a', "g")).is_err());
+		assert!(parse_fingerprint(&format!("é{}", &FINGERPRINT[2..])).is_err());
+		assert!(parse_fingerprint(&format!("{}é", &FINGERPRINT[..62])).is_err());
+		assert!(parse_fingerprint(&format!("+{}", &FINGERPRINT[1..])).is_err());
+	}
+}
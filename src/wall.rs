@@ -0,0 +1,159 @@
+/*! Spanning one image across several panels (a video wall).
+
+The wall image covers all panels including the gaps between them, so that lines continue naturally across the bezels:
+the pixels that would fall on a bezel are simply not shown. Each tile says where the top left corner of its panel is
+in the wall image, and how the panel is mounted. */
+use crate::config::TileConfig;
+use crate::graphics::Bitmap;
+use serde::Deserialize;
+use std::convert::TryFrom;
+
+/** Clockwise rotation of a panel as mounted, relative to the wall */
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(try_from = "u32")]
+pub enum Rotation {
+	#[default]
+	Deg0,
+	Deg90,
+	Deg180,
+	Deg270,
+}
+
+impl TryFrom<u32> for Rotation {
+	type Error = String;
+
+	fn try_from(degrees: u32) -> Result<Rotation, String> {
+		match degrees {
+			0 => Ok(Rotation::Deg0),
+			90 => Ok(Rotation::Deg90),
+			180 => Ok(Rotation::Deg180),
+			270 => Ok(Rotation::Deg270),
+			_ => Err(format!("rotation should be 0, 90, 180 or 270, got {}", degrees)),
+		}
+	}
+}
+
+/** Width and height of the area a tile covers in the wall, for a panel of the given size */
+pub fn extent(tile: &TileConfig, width: u32, height: u32) -> (u32, u32) {
+	match tile.rotate {
+		Rotation::Deg0 | Rotation::Deg180 => (width, height),
+		Rotation::Deg90 | Rotation::Deg270 => (height, width),
+	}
+}
+
+/** Size of the wall image needed to cover all tiles, given with the sizes of their panels. The width is rounded up to
+whole bytes, as images are packed. */
+pub fn size(tiles: &[(&TileConfig, u32, u32)]) -> (u32, u32) {
+	let (width, height) = tiles.iter().fold((0, 0), |(w, h), (tile, width, height)| {
+		let (tw, th) = extent(tile, *width, *height);
+		(w.max(tile.x + tw), h.max(tile.y + th))
+	});
+	(width.div_ceil(8) * 8, height)
+}
+
+/** Cut the part of the wall image shown by the panel of a tile, turned so that it appears upright once mounted */
+pub fn cut(wall: &Bitmap, tile: &TileConfig, width: u32, height: u32) -> Bitmap {
+	let map = |x: u32, y: u32| -> (u32, u32) {
+		let (wx, wy) = match tile.rotate {
+			Rotation::Deg0 => (x, y),
+			Rotation::Deg90 => (height - 1 - y, x),
+			Rotation::Deg180 => (width - 1 - x, height - 1 - y),
+			Rotation::Deg270 => (y, width - 1 - x),
+		};
+		(tile.x + wx, tile.y + wy)
+	};
+
+	let cut_plane = |plane: &[u8]| -> Vec<u8> {
+		let wall_row = (wall.width / 8) as usize;
+		let mut out = vec![0u8; (width * height / 8) as usize];
+		for y in 0..height {
+			for x in 0..width {
+				let (wx, wy) = map(x, y);
+				if wx >= wall.width || wy >= wall.height {
+					continue;
+				}
+				if plane[wy as usize * wall_row + wx as usize / 8] & (0x80 >> (wx % 8)) != 0 {
+					out[(y * (width / 8) + x / 8) as usize] |= 0x80 >> (x % 8);
+				}
+			}
+		}
+		out
+	};
+
+	Bitmap {
+		width,
+		height,
+		black: cut_plane(&wall.black),
+		color: wall.color.as_ref().map(|c| cut_plane(c)),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn tile(x: u32, y: u32, rotate: Rotation) -> TileConfig {
+		TileConfig {
+			display: String::new(),
+			x,
+			y,
+			rotate,
+		}
+	}
+
+	/** 16x8 wall with only the pixel at (x, y) set */
+	fn wall_with_pixel(x: u32, y: u32) -> Bitmap {
+		let mut black = vec![0u8; 16];
+		black[(y * 2 + x / 8) as usize] = 0x80 >> (x % 8);
+		Bitmap {
+			width: 16,
+			height: 8,
+			black,
+			color: None,
+		}
+	}
+
+	fn set_pixels(bitmap: &Bitmap) -> Vec<(u32, u32)> {
+		let mut pixels = vec![];
+		for y in 0..bitmap.height {
+			for x in 0..bitmap.width {
+				if bitmap.black[(y * bitmap.width / 8 + x / 8) as usize] & (0x80 >> (x % 8)) != 0 {
+					pixels.push((x, y));
+				}
+			}
+		}
+		pixels
+	}
+
+	#[test]
+	fn cuts_tiles_at_their_offset() {
+		let wall = wall_with_pixel(9, 2);
+		assert_eq!(set_pixels(&cut(&wall, &tile(8, 0, Rotation::Deg0), 8, 8)), vec![(1, 2)]);
+		assert_eq!(set_pixels(&cut(&wall, &tile(0, 0, Rotation::Deg0), 8, 8)), vec![]);
+	}
+
+	#[test]
+	fn turns_rotated_tiles() {
+		// The wall's top left pixel ends up in the panel corner that is mounted at the top left
+		let wall = wall_with_pixel(0, 0);
+		assert_eq!(
+			set_pixels(&cut(&wall, &tile(0, 0, Rotation::Deg90), 8, 16)),
+			vec![(0, 15)]
+		);
+		assert_eq!(
+			set_pixels(&cut(&wall, &tile(0, 0, Rotation::Deg180), 8, 8)),
+			vec![(7, 7)]
+		);
+		assert_eq!(
+			set_pixels(&cut(&wall, &tile(0, 0, Rotation::Deg270), 8, 16)),
+			vec![(7, 0)]
+		);
+	}
+
+	#[test]
+	fn wall_covers_all_tiles() {
+		let left = tile(0, 0, Rotation::Deg0);
+		let right = tile(830, 0, Rotation::Deg90);
+		assert_eq!(size(&[(&left, 800, 480), (&right, 800, 480)]), (1312, 800));
+	}
+}